@@ -0,0 +1,51 @@
+#![no_main]
+use libfuzzer_sys::{arbitrary::Unstructured, fuzz_target};
+
+use waffle::{ConstVal, ExportKind, FrontendOptions, InterpContext, InterpResult, Module};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = env_logger::try_init();
+    let mut u = Unstructured::new(data);
+    let module = match waffle::fuzzing::arbitrary_cfg_module(&mut u) {
+        Ok(module) => module,
+        Err(_) => return,
+    };
+    let arg = match u.arbitrary::<u32>() {
+        Ok(arg) => ConstVal::I32(arg),
+        Err(_) => return,
+    };
+    let func = match module.exports[0].kind {
+        ExportKind::Func(func) => func,
+        _ => unreachable!(),
+    };
+    log::debug!(
+        "original body:\n{}",
+        module.funcs[func].body().unwrap().display("| ", None)
+    );
+
+    let mut orig_ctx = InterpContext::new(&module).unwrap();
+    orig_ctx.fuel = 10000;
+    let orig_result = match orig_ctx.call(&module, func, &[arg]) {
+        InterpResult::OutOfFuel => {
+            log::trace!("Rejecting due to timeout in orig");
+            return;
+        }
+        InterpResult::Ok(vals) => vals,
+        ret => panic!("Bad result: {:?}", ret),
+    };
+
+    // The backend must accept any CFG, reducible or not.
+    let bytes = module.to_wasm_bytes().unwrap();
+    let mut compiled_module =
+        Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+    compiled_module.expand_all_funcs().unwrap();
+
+    let mut compiled_ctx = InterpContext::new(&compiled_module).unwrap();
+    // Dispatch blocks add steps; allow some leeway.
+    compiled_ctx.fuel = 40000;
+    let compiled_result = compiled_ctx
+        .call(&compiled_module, func, &[arg])
+        .ok()
+        .unwrap();
+    assert_eq!(orig_result, compiled_result);
+});
@@ -0,0 +1,266 @@
+//! Call graph with direct and indirect edges.
+
+use crate::declare_entity;
+use crate::entity::{EntityRef, EntityVec, PerEntity};
+use crate::ir::{EntityUse, Func, FuncDecl, Module};
+use anyhow::Result;
+use rayon::prelude::*;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+declare_entity!(SCC, "scc");
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CallKind {
+    /// A `call` to a known function.
+    Direct,
+    /// A possible target of a `call_indirect`.
+    Indirect,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CallEdge {
+    pub callee: Func,
+    pub kind: CallKind,
+}
+
+/// A call graph over all functions in a module.
+///
+/// Indirect calls are resolved to every function in the called
+/// table's known elements whose signature matches the call's
+/// signature. Targets installed at runtime (by `table.set` or by the
+/// host through an imported or exported table) and callbacks from
+/// imported functions into exports are not represented.
+#[derive(Clone, Debug, Default)]
+pub struct CallGraph {
+    /// Outgoing edges of each function, sorted and deduplicated.
+    pub callees: PerEntity<Func, Vec<CallEdge>>,
+    /// Callers of each function, sorted and deduplicated.
+    pub callers: PerEntity<Func, Vec<Func>>,
+    /// Strongly-connected components, in bottom-up order: every SCC
+    /// comes after all SCCs that it calls.
+    pub sccs: EntityVec<SCC, Vec<Func>>,
+    /// SCC of each function.
+    pub func_scc: PerEntity<Func, SCC>,
+}
+
+impl CallGraph {
+    /// Build the call graph for `module`. Lazy bodies are scanned
+    /// without being expanded.
+    pub fn new(module: &Module) -> Result<CallGraph> {
+        let edges = module
+            .funcs
+            .entries()
+            .collect::<Vec<_>>()
+            .par_iter()
+            .map(|&(_, decl)| -> Result<Vec<CallEdge>> {
+                let mut edges = vec![];
+                decl.visit_uses(|u| match u {
+                    EntityUse::Call(callee) => edges.push(CallEdge {
+                        callee,
+                        kind: CallKind::Direct,
+                    }),
+                    EntityUse::CallIndirect(sig, table) => {
+                        let sig_data = &module.signatures[sig];
+                        if let Some(elts) = &module.tables[table].func_elements {
+                            for &callee in elts {
+                                if callee.is_valid()
+                                    && !matches!(module.funcs[callee], FuncDecl::None)
+                                    && &module.signatures[module.funcs[callee].sig()] == sig_data
+                                {
+                                    edges.push(CallEdge {
+                                        callee,
+                                        kind: CallKind::Indirect,
+                                    });
+                                }
+                            }
+                        }
+                    }
+                    _ => {}
+                })?;
+                edges.sort_unstable();
+                edges.dedup();
+                Ok(edges)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut graph = CallGraph::default();
+        for (caller, edges) in edges.into_iter().enumerate() {
+            let caller = Func::new(caller);
+            for edge in &edges {
+                let callers = &mut graph.callers[edge.callee];
+                if callers.last() != Some(&caller) {
+                    callers.push(caller);
+                }
+            }
+            graph.callees[caller] = edges;
+        }
+        graph.compute_sccs(module.funcs.len());
+        Ok(graph)
+    }
+
+    /// Tarjan's algorithm, with an explicit stack. SCCs are completed
+    /// in reverse topological order, which is exactly bottom-up.
+    fn compute_sccs(&mut self, n_funcs: usize) {
+        let mut index: PerEntity<Func, Option<usize>> = PerEntity::default();
+        let mut lowlink: PerEntity<Func, usize> = PerEntity::default();
+        let mut on_stack: PerEntity<Func, bool> = PerEntity::default();
+        let mut scc_stack: Vec<Func> = vec![];
+        let mut next_index = 0;
+
+        struct State {
+            func: Func,
+            next_edge: usize,
+        }
+        let mut dfs: Vec<State> = vec![];
+
+        for root in 0..n_funcs {
+            let root = Func::new(root);
+            if index[root].is_some() {
+                continue;
+            }
+            index[root] = Some(next_index);
+            lowlink[root] = next_index;
+            next_index += 1;
+            scc_stack.push(root);
+            on_stack[root] = true;
+            dfs.push(State {
+                func: root,
+                next_edge: 0,
+            });
+
+            while let Some(state) = dfs.last_mut() {
+                let func = state.func;
+                if let Some(edge) = self.callees[func].get(state.next_edge) {
+                    state.next_edge += 1;
+                    let callee = edge.callee;
+                    match index[callee] {
+                        None => {
+                            index[callee] = Some(next_index);
+                            lowlink[callee] = next_index;
+                            next_index += 1;
+                            scc_stack.push(callee);
+                            on_stack[callee] = true;
+                            dfs.push(State {
+                                func: callee,
+                                next_edge: 0,
+                            });
+                        }
+                        Some(callee_index) if on_stack[callee] => {
+                            lowlink[func] = std::cmp::min(lowlink[func], callee_index);
+                        }
+                        Some(_) => {}
+                    }
+                } else {
+                    dfs.pop();
+                    if let Some(parent) = dfs.last() {
+                        lowlink[parent.func] = std::cmp::min(lowlink[parent.func], lowlink[func]);
+                    }
+                    if Some(lowlink[func]) == index[func] {
+                        let scc = self.sccs.push(vec![]);
+                        loop {
+                            let member = scc_stack.pop().unwrap();
+                            on_stack[member] = false;
+                            self.func_scc[member] = scc;
+                            self.sccs[scc].push(member);
+                            if member == func {
+                                break;
+                            }
+                        }
+                        self.sccs[scc].sort_unstable();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Outgoing edges of `func`.
+    pub fn callees(&self, func: Func) -> &[CallEdge] {
+        &self.callees[func][..]
+    }
+
+    /// Functions that may call `func`.
+    pub fn callers(&self, func: Func) -> &[Func] {
+        &self.callers[func][..]
+    }
+
+    /// All functions, with callees before their callers (except
+    /// within a recursive SCC).
+    pub fn bottom_up(&self) -> impl Iterator<Item = Func> + '_ {
+        self.sccs.values().flat_map(|funcs| funcs.iter().copied())
+    }
+
+    /// All functions, with callers before their callees (except
+    /// within a recursive SCC).
+    pub fn top_down(&self) -> impl Iterator<Item = Func> + '_ {
+        self.sccs
+            .values()
+            .rev()
+            .flat_map(|funcs| funcs.iter().copied())
+    }
+
+    /// Can `func` (transitively) call itself?
+    pub fn is_recursive(&self, func: Func) -> bool {
+        self.sccs[self.func_scc[func]].len() > 1
+            || self.callees[func].iter().any(|edge| edge.callee == func)
+    }
+
+    /// Display the call graph in Graphviz DOT format. Indirect edges
+    /// are dashed and recursive functions are outlined in red.
+    pub fn display_dot<'a>(&'a self, module: &'a Module<'a>) -> CallGraphDot<'a> {
+        CallGraphDot {
+            graph: self,
+            module,
+        }
+    }
+}
+
+pub struct CallGraphDot<'a> {
+    graph: &'a CallGraph,
+    module: &'a Module<'a>,
+}
+
+impl<'a> Display for CallGraphDot<'a> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        writeln!(f, "digraph callgraph {{")?;
+        for (func, decl) in self.module.funcs.entries() {
+            let name = match decl {
+                FuncDecl::None => "",
+                decl => decl.name(),
+            };
+            let label = if name.is_empty() {
+                format!("{}", func)
+            } else {
+                format!(
+                    "{} {}",
+                    func,
+                    name.replace('\\', "\\\\").replace('"', "\\\"")
+                )
+            };
+            let shape = if matches!(decl, FuncDecl::Import(..)) {
+                "box"
+            } else {
+                "ellipse"
+            };
+            let color = if self.graph.is_recursive(func) {
+                "red"
+            } else {
+                "black"
+            };
+            writeln!(
+                f,
+                "  {} [label=\"{}\", shape={}, color={}];",
+                func, label, shape, color
+            )?;
+        }
+        for func in self.module.funcs.iter() {
+            for edge in self.graph.callees(func) {
+                let style = match edge.kind {
+                    CallKind::Direct => "solid",
+                    CallKind::Indirect => "dashed",
+                };
+                writeln!(f, "  {} -> {} [style={}];", func, edge.callee, style)?;
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
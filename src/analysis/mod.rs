@@ -0,0 +1,5 @@
+//! Module-level analyses.
+
+pub mod callgraph;
+
+pub use callgraph::{CallEdge, CallGraph, CallKind};
@@ -0,0 +1,193 @@
+//! Debug-info output: DWARF line tables and source maps for the
+//! compiled code section.
+
+use crate::entity::EntityRef;
+use crate::ir::{Debug, SourceLoc};
+use anyhow::{anyhow, Result};
+use fxhash::FxHashMap as HashMap;
+use gimli::write::{
+    Address, AttributeValue, DwarfUnit, EndianVec, LineProgram, LineString, Sections,
+};
+use gimli::{Encoding, Format, LineEncoding, LittleEndian};
+
+/// One row of the location table: a code-section-relative offset
+/// and the source location of the code starting there, up to the
+/// next row.
+pub(crate) type LocRow = (u32, SourceLoc);
+
+/// Build a DWARF compilation unit whose line program covers the
+/// code section, returning the `.debug_*` custom sections to emit.
+pub(crate) fn dwarf_line_sections(
+    debug: &Debug,
+    rows: &[LocRow],
+    code_len: u32,
+) -> Result<Vec<(&'static str, Vec<u8>)>> {
+    let encoding = Encoding {
+        format: Format::Dwarf32,
+        version: 4,
+        address_size: 4,
+    };
+    let mut dwarf = DwarfUnit::new(encoding);
+    let mut program = LineProgram::new(
+        encoding,
+        LineEncoding::default(),
+        LineString::String(b".".to_vec()),
+        LineString::String(b"<waffle>".to_vec()),
+        None,
+    );
+
+    let mut dirs = HashMap::default();
+    let mut files = HashMap::default();
+    program.begin_sequence(Some(Address::Constant(0)));
+    for &(offset, loc) in rows {
+        let row_file = if loc.is_valid() {
+            let data = debug.source_locs[loc];
+            let file = *files.entry(data.file).or_insert_with(|| {
+                let path = &debug.source_files[data.file];
+                let (dir, name) = match path.rsplit_once('/') {
+                    Some((dir, name)) => {
+                        let dir = *dirs.entry(dir.to_owned()).or_insert_with(|| {
+                            program.add_directory(LineString::String(dir.as_bytes().to_vec()))
+                        });
+                        (dir, name)
+                    }
+                    None => (program.default_directory(), &path[..]),
+                };
+                program.add_file(LineString::String(name.as_bytes().to_vec()), dir, None)
+            });
+            Some((file, data.line, data.col))
+        } else {
+            None
+        };
+        let row = program.row();
+        row.address_offset = offset as u64;
+        match row_file {
+            Some((file, line, col)) => {
+                row.file = file;
+                row.line = line as u64;
+                row.column = col as u64;
+            }
+            None => {
+                row.line = 0;
+                row.column = 0;
+            }
+        }
+        program.generate_row();
+    }
+    program.end_sequence(code_len as u64);
+    dwarf.unit.line_program = program;
+
+    let root = dwarf.unit.root();
+    let root = dwarf.unit.get_mut(root);
+    root.set(
+        gimli::DW_AT_name,
+        AttributeValue::String(b"<waffle>".to_vec()),
+    );
+    root.set(
+        gimli::DW_AT_low_pc,
+        AttributeValue::Address(Address::Constant(0)),
+    );
+    root.set(gimli::DW_AT_high_pc, AttributeValue::Udata(code_len as u64));
+
+    let mut sections = Sections::new(EndianVec::new(LittleEndian));
+    dwarf
+        .write(&mut sections)
+        .map_err(|e| anyhow!("Error writing DWARF: {}", e))?;
+    let mut out = vec![];
+    sections.for_each(|id, data| -> Result<()> {
+        if !data.slice().is_empty() {
+            out.push((id.name(), data.slice().to_vec()));
+        }
+        Ok(())
+    })?;
+    Ok(out)
+}
+
+/// Build a version-3 source map for the module. Wasm source maps
+/// have a single generated line, whose columns are byte offsets into
+/// the module, so each row is shifted by the code section's offset
+/// in the file.
+pub(crate) fn source_map(debug: &Debug, rows: &[LocRow], code_offset_in_file: u32) -> String {
+    let mut mappings = String::new();
+    let mut last_col = 0;
+    let mut last_source = 0;
+    let mut last_line = 0;
+    let mut last_src_col = 0;
+    for &(offset, loc) in rows {
+        if !mappings.is_empty() {
+            mappings.push(',');
+        }
+        let col = (code_offset_in_file + offset) as i64;
+        vlq(&mut mappings, col - last_col);
+        last_col = col;
+        let data = if loc.is_valid() {
+            Some(debug.source_locs[loc])
+        } else {
+            None
+        };
+        // Line zero is DWARF's "no source line".
+        if let Some(data) = data.filter(|data| data.line > 0) {
+            let source = data.file.index() as i64;
+            // Source maps count lines and columns from zero; DWARF
+            // counts from one, with zero meaning "unknown".
+            let line = (data.line - 1) as i64;
+            let src_col = data.col.saturating_sub(1) as i64;
+            vlq(&mut mappings, source - last_source);
+            vlq(&mut mappings, line - last_line);
+            vlq(&mut mappings, src_col - last_src_col);
+            last_source = source;
+            last_line = line;
+            last_src_col = src_col;
+        }
+    }
+
+    let sources = debug
+        .source_files
+        .values()
+        .map(|path| json_string(path))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{{\"version\":3,\"sources\":[{}],\"names\":[],\"mappings\":{}}}",
+        sources,
+        json_string(&mappings)
+    )
+}
+
+/// Append one base64 VLQ-encoded value.
+fn vlq(out: &mut String, value: i64) {
+    const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut bits = if value < 0 {
+        ((-value as u64) << 1) | 1
+    } else {
+        (value as u64) << 1
+    };
+    loop {
+        let mut digit = (bits & 0x1f) as usize;
+        bits >>= 5;
+        if bits != 0 {
+            digit |= 0x20;
+        }
+        out.push(BASE64[digit] as char);
+        if bits == 0 {
+            break;
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -3,10 +3,11 @@
 
 use crate::backend::treeify::Trees;
 use crate::cfg::CFGInfo;
-use crate::entity::{EntityVec, PerEntity};
+use crate::entity::{EntityRef, EntityVec, PerEntity};
 use crate::ir::{Block, FunctionBody, Local, Type, Value, ValueDef};
+use fxhash::FxHashMap as HashMap;
 use smallvec::{smallvec, SmallVec};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet};
 use std::ops::Range;
 
 #[derive(Clone, Debug, Default)]
@@ -21,19 +22,28 @@ impl Localifier {
     }
 }
 
+/// Blockparam/arg slot pairs that would like to share a local.
+///
+/// This and the other hashed tables here are only looked up, never
+/// iterated; everything whose order can reach the output is kept in
+/// ordered collections.
+type Hints = HashMap<(Value, usize), SmallVec<[(Value, usize); 2]>>;
+
 struct Context<'a> {
     body: &'a FunctionBody,
     cfg: &'a CFGInfo,
     trees: &'a Trees,
     results: Localifier,
 
-    /// Precise liveness for each block: live Values at the end.
-    block_end_live: PerEntity<Block, HashSet<Value>>,
+    /// Precise liveness for each block: live Values at the end. Kept
+    /// ordered, as it is iterated when computing ranges.
+    block_end_live: PerEntity<Block, BTreeSet<Value>>,
 
     /// Liveranges for each Value, in an arbitrary index space
     /// (concretely, the span of first to last instruction visit step
-    /// index in an RPO walk over the function body).
-    ranges: HashMap<Value, Range<usize>>,
+    /// index in an RPO walk over the function body). Iterated when
+    /// allocating, so ordered like the liveness sets.
+    ranges: BTreeMap<Value, Range<usize>>,
     /// Number of points.
     points: usize,
 }
@@ -74,7 +84,7 @@ impl<'a, V: Visitor> BlockVisitor<'a, V> {
         self.visitor.pre_term();
 
         for &inst in self.body.blocks[block].insts.iter().rev() {
-            if self.trees.owner.contains_key(&inst) || self.trees.remat.contains(&inst) {
+            if self.trees.is_owned(&inst) || self.trees.remat.contains(&inst) {
                 continue;
             }
             self.visitor.post_inst(inst);
@@ -107,7 +117,7 @@ impl<'a, V: Visitor> BlockVisitor<'a, V> {
             self.visit_use(value);
             return;
         }
-        if self.trees.owner.contains_key(&value) {
+        if self.trees.is_owned(&value) {
             // If this is a treeified value, then don't process the use,
             // but process the instruction directly here.
             self.visit_inst(value, /* root = */ false);
@@ -134,14 +144,14 @@ impl<'a> Context<'a> {
             trees,
             results,
             block_end_live: PerEntity::default(),
-            ranges: HashMap::default(),
+            ranges: BTreeMap::default(),
             points: 0,
         }
     }
 
     fn compute_liveness(&mut self) {
         struct LivenessVisitor {
-            live: HashSet<Value>,
+            live: BTreeSet<Value>,
         }
         impl Visitor for LivenessVisitor {
             fn visit_use(&mut self, value: Value) {
@@ -153,7 +163,7 @@ impl<'a> Context<'a> {
         }
 
         let mut workqueue: Vec<Block> = self.cfg.rpo.values().cloned().collect();
-        let mut workqueue_set: HashSet<Block> = workqueue.iter().cloned().collect();
+        let mut workqueue_set: BTreeSet<Block> = workqueue.iter().cloned().collect();
         while let Some(block) = workqueue.pop() {
             workqueue_set.remove(&block);
             let live = self.block_end_live[block].clone();
@@ -181,8 +191,8 @@ impl<'a> Context<'a> {
 
         struct LiveRangeVisitor<'b> {
             point: &'b mut usize,
-            live: HashMap<Value, usize>,
-            ranges: &'b mut HashMap<Value, Range<usize>>,
+            live: BTreeMap<Value, usize>,
+            ranges: &'b mut BTreeMap<Value, Range<usize>>,
         }
         impl<'b> Visitor for LiveRangeVisitor<'b> {
             fn pre_params(&mut self) {
@@ -211,7 +221,7 @@ impl<'a> Context<'a> {
 
         for &block in self.cfg.rpo.values().rev() {
             let visitor = LiveRangeVisitor {
-                live: HashMap::default(),
+                live: BTreeMap::default(),
                 point: &mut point,
                 ranges: &mut self.ranges,
             };
@@ -235,20 +245,66 @@ impl<'a> Context<'a> {
         self.points = point + 1;
     }
 
+    /// Coalescing hints: for each (value, result index) slot, the
+    /// slots it is copied to or from by blockparam transfers. Giving
+    /// both sides of a transfer the same local makes the copy a no-op.
+    fn compute_hints(&self) -> Hints {
+        let mut hints: Hints = HashMap::default();
+        for &block in self.cfg.rpo.values() {
+            self.body.blocks[block].terminator.visit_targets(|target| {
+                let params = &self.body.blocks[target.block].params;
+                for (&arg, &(_, param)) in target.args.iter().zip(params.iter()) {
+                    let arg = self.body.resolve_alias(arg);
+                    if self.trees.is_owned(&arg) || self.trees.remat.contains(&arg) {
+                        continue;
+                    }
+                    let arg_slot = match &self.body.values[arg] {
+                        &ValueDef::PickOutput(value, index, _) => {
+                            (self.body.resolve_alias(value), index as usize)
+                        }
+                        _ => (arg, 0),
+                    };
+                    let param_slot = (param, 0);
+                    if arg_slot != param_slot {
+                        hints.entry(arg_slot).or_default().push(param_slot);
+                        hints.entry(param_slot).or_default().push(arg_slot);
+                    }
+                }
+            });
+        }
+        hints
+    }
+
     fn allocate(&mut self) {
         // Sort values by ranges' starting points, then value to break ties.
         let mut ranges: Vec<(Value, std::ops::Range<usize>)> =
             self.ranges.iter().map(|(k, v)| (*k, v.clone())).collect();
         ranges.sort_unstable_by_key(|(val, range)| (range.start, *val));
 
+        let hints = self.compute_hints();
+
         // Keep a list of expiring Locals by expiry point.
-        let mut expiring: HashMap<usize, SmallVec<[(Type, Local); 8]>> = HashMap::new();
+        let mut expiring: HashMap<usize, SmallVec<[(Type, Local); 8]>> = HashMap::default();
+
+        // Free locals by type, each with the point until which it may
+        // be used. Function params have fixed locals, which other
+        // values may borrow only outside of the param's range.
+        let mut freelist: HashMap<Type, Vec<(Local, usize)>> = HashMap::default();
+        let mut free_until: HashMap<Local, usize> = HashMap::default();
+        for &(ty, param) in &self.body.blocks[self.body.entry].params {
+            let local = self.results.values[param][0];
+            let until = self
+                .ranges
+                .get(&param)
+                .map_or(usize::MAX, |range| range.start);
+            freelist.entry(ty).or_default().push((local, until));
+            free_until.insert(local, until);
+        }
 
         // Iterate over allocation space, processing range starts (at
         // which point we allocate) and ends (at which point we add to
         // the freelist).
         let mut range_idx = 0;
-        let mut freelist: HashMap<Type, Vec<Local>> = HashMap::new();
 
         for i in 0..self.points {
             // Process ends. (Ends are exclusive, so we do them
@@ -257,7 +313,8 @@ impl<'a> Context<'a> {
             if let Some(expiring) = expiring.remove(&i) {
                 for (ty, local) in expiring {
                     log::trace!(" -> expiring {} of type {} back to freelist", local, ty);
-                    freelist.entry(ty).or_insert_with(|| vec![]).push(local);
+                    let until = free_until.get(&local).copied().unwrap_or(usize::MAX);
+                    freelist.entry(ty).or_default().push((local, until));
                 }
             }
 
@@ -272,39 +329,111 @@ impl<'a> Context<'a> {
                     range.end
                 );
 
-                // If the value is an arg on block0, ignore; these
-                // already have fixed locations.
-                if let &ValueDef::BlockParam(b, _, _) = &self.body.values[value] {
+                // If the value is an arg on block0, it already has a
+                // fixed location: take it off the freelist for the
+                // duration of its range.
+                if let &ValueDef::BlockParam(b, _, ty) = &self.body.values[value] {
                     if b == self.body.entry {
+                        let local = self.results.values[value][0];
+                        let free = freelist.get_mut(&ty).unwrap();
+                        let pos = free.iter().position(|&(l, _)| l == local).unwrap();
+                        free.remove(pos);
+                        free_until.remove(&local);
+                        expiring
+                            .entry(range.end)
+                            .or_insert_with(|| smallvec![])
+                            .push((ty, local));
                         continue;
                     }
                 }
 
-                // Try getting a local from the freelist; if not,
-                // allocate a new one.
+                // Try getting a local from the freelist, preferring
+                // one already given to a value that this one is
+                // copied to or from; if none is free, allocate a new
+                // one.
                 let mut allocs = smallvec![];
-                let expiring = expiring.entry(range.end).or_insert_with(|| smallvec![]);
-                for &ty in self.body.values[value].tys(&self.body.type_pool) {
-                    let local = freelist
-                        .get_mut(&ty)
-                        .and_then(|v| v.pop())
-                        .unwrap_or_else(|| {
+                for (index, &ty) in self.body.values[value]
+                    .tys(&self.body.type_pool)
+                    .iter()
+                    .enumerate()
+                {
+                    let free = freelist.entry(ty).or_default();
+                    let hinted = hints.get(&(value, index)).and_then(|partners| {
+                        partners.iter().find_map(|&(partner, partner_index)| {
+                            let local = *self.results.values[partner].get(partner_index)?;
+                            free.iter()
+                                .position(|&(l, until)| l == local && until >= range.end)
+                        })
+                    });
+                    // Otherwise, leave function params' locals for values
+                    // that may coalesce with them.
+                    let pos = hinted
+                        .or_else(|| free.iter().rposition(|&(_, until)| until == usize::MAX))
+                        .or_else(|| free.iter().rposition(|&(_, until)| until >= range.end));
+                    let local = match pos {
+                        Some(pos) => free.remove(pos).0,
+                        None => {
                             log::trace!(" -> allocating new local of type {}", ty);
                             self.results.locals.push(ty)
-                        });
+                        }
+                    };
                     log::trace!(" -> got local {} of type {}", local, ty);
                     allocs.push(local);
-                    expiring.push((ty, local));
+                    expiring
+                        .entry(range.end)
+                        .or_insert_with(|| smallvec![])
+                        .push((ty, local));
                 }
                 self.results.values[value] = allocs;
             }
         }
     }
 
+    /// Renumber non-param locals so that locals of the same type are
+    /// adjacent, letting the local declarations compress into a few
+    /// `(count, type)` runs.
+    fn sort_locals(&mut self) {
+        let n_params = self.body.blocks[self.body.entry].params.len();
+        let mut order = (n_params..self.results.locals.len()).collect::<Vec<_>>();
+        let mut type_order: Vec<Type> = vec![];
+        for &local in &order {
+            let ty = self.results.locals[Local::new(local)];
+            if !type_order.contains(&ty) {
+                type_order.push(ty);
+            }
+        }
+        order.sort_by_key(|&local| {
+            let ty = self.results.locals[Local::new(local)];
+            type_order.iter().position(|&t| t == ty).unwrap()
+        });
+
+        let mut remap = (0..self.results.locals.len())
+            .map(Local::new)
+            .collect::<Vec<_>>();
+        let mut locals = self
+            .results
+            .locals
+            .values()
+            .take(n_params)
+            .copied()
+            .collect::<Vec<_>>();
+        for (i, &local) in order.iter().enumerate() {
+            remap[local] = Local::new(n_params + i);
+            locals.push(self.results.locals[Local::new(local)]);
+        }
+        self.results.locals = EntityVec::from(locals);
+        for value in self.body.values.iter() {
+            for local in self.results.values[value].iter_mut() {
+                *local = remap[local.index()];
+            }
+        }
+    }
+
     fn compute(mut self) -> Localifier {
         self.compute_liveness();
         self.find_ranges();
         self.allocate();
+        self.sort_locals();
         self.results
     }
 }
@@ -2,24 +2,50 @@
 
 use crate::cfg::CFGInfo;
 use crate::entity::EntityRef;
-use crate::ir::{ExportKind, FuncDecl, FunctionBody, ImportKind, Module, Type, Value, ValueDef};
+use crate::errors::BackendError;
+use crate::ir::{
+    Block, ExportKind, Func, FuncDecl, FunctionBody, Global, ImportKind, Module, SourceLoc, Type,
+    Value, ValueDef,
+};
 use crate::Operator;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use rayon::prelude::*;
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 
+mod debuginfo;
+use debuginfo::LocRow;
+mod offsets;
+pub use offsets::{FuncOffsetMap, OffsetMap};
+pub mod reducify;
 pub mod stackify;
-use stackify::{Context as StackifyContext, WasmBlock};
+use stackify::{Context as StackifyContext, WasmBlock, WasmLabel};
 pub mod treeify;
 use treeify::Trees;
 pub mod localify;
 use localify::Localifier;
+mod peephole;
+use peephole::{InstBuffer, InstLoc};
+
+/// A stackify control frame, as seen while lowering.
+#[derive(Clone, Copy, Debug, Default)]
+struct Frame {
+    /// The blockparam whose value is carried on the stack by branches
+    /// to this frame's label, if any.
+    result: Option<Value>,
+    /// This frame has no Wasm scope (an `if` lowered as `br_if`).
+    elided: bool,
+    /// Branches to this frame's label go to this label instead,
+    /// counted from just outside the frame.
+    forward: Option<WasmLabel>,
+}
 
 pub struct WasmFuncBackend<'a> {
     body: &'a FunctionBody,
     trees: Trees,
     ctrl: Vec<WasmBlock<'a>>,
     locals: Localifier,
+    optimize_for: OptimizeFor,
 }
 
 macro_rules! op {
@@ -29,12 +55,12 @@ macro_rules! op {
 }
 
 impl<'a> WasmFuncBackend<'a> {
-    pub fn new(body: &'a FunctionBody) -> Result<WasmFuncBackend<'a>> {
+    pub fn new(body: &'a FunctionBody, optimize_for: OptimizeFor) -> Result<WasmFuncBackend<'a>> {
         body.validate()?;
         log::debug!("Backend compiling:\n{}\n", body.display_verbose("| ", None));
         let cfg = CFGInfo::new(body);
         log::debug!("CFG:\n{:?}\n", cfg);
-        let trees = Trees::compute(body);
+        let trees = Trees::compute(body, optimize_for);
         log::debug!("Trees:\n{:?}\n", trees);
         let ctrl = StackifyContext::new(body, &cfg)?.compute();
         log::debug!("Ctrl:\n{:?}\n", ctrl);
@@ -45,22 +71,20 @@ impl<'a> WasmFuncBackend<'a> {
             trees,
             ctrl,
             locals,
+            optimize_for,
         })
     }
 
     pub fn compile(&self) -> Result<wasm_encoder::Function> {
-        let mut func = wasm_encoder::Function::new(
-            self.locals
-                .locals
-                .values()
-                .skip(self.body.blocks[self.body.entry].params.len())
-                .map(|&ty| (1, wasm_encoder::ValType::from(ty)))
-                .collect::<Vec<_>>(),
-        );
+        Ok(self.compile_with_locs()?.0)
+    }
 
-        for block in &self.ctrl {
-            self.lower_block(block, &mut func);
-        }
+    /// Compile the body, also returning where its instructions came
+    /// from.
+    pub fn compile_with_locs(&self) -> Result<(wasm_encoder::Function, BodyLocs)> {
+        let mut insts = InstBuffer::default();
+        let mut frames = vec![];
+        self.lower_blocks(&self.ctrl[..], &mut frames, &mut insts)?;
 
         // If the last block was a Block, Loop or If, then the type
         // may not match, so end with an Unreachable.
@@ -68,58 +92,385 @@ impl<'a> WasmFuncBackend<'a> {
             Some(&WasmBlock::Block { .. })
             | Some(&WasmBlock::Loop { .. })
             | Some(&WasmBlock::If { .. }) => {
-                func.instruction(&wasm_encoder::Instruction::Unreachable);
+                insts.instruction(&wasm_encoder::Instruction::Unreachable);
             }
             _ => {}
         }
-        func.instruction(&wasm_encoder::Instruction::End);
+        insts.instruction(&wasm_encoder::Instruction::End);
+
+        let n_params = self.body.blocks[self.body.entry].params.len();
+        let local_types = self.locals.locals.values().copied().collect::<Vec<_>>();
+        let local_remap = if self.optimize_for == OptimizeFor::Size {
+            Self::renumber_locals_by_use(&mut insts, &local_types[..], n_params)
+        } else {
+            (0..local_types.len() as u32).collect()
+        };
+
+        // Declare locals as runs of the same type.
+        let mut local_decls: Vec<(u32, wasm_encoder::ValType)> = vec![];
+        for &ty in local_types.iter().skip(n_params) {
+            match local_decls.last_mut() {
+                Some((count, last_ty)) if *last_ty == wasm_encoder::ValType::from(ty) => {
+                    *count += 1
+                }
+                _ => local_decls.push((1, wasm_encoder::ValType::from(ty))),
+            }
+        }
+        let mut func = wasm_encoder::Function::new(local_decls);
+        let mut locs = BodyLocs {
+            local_names: self.local_names(&local_remap[..]),
+            label_names: self.label_names(&insts),
+            ..BodyLocs::default()
+        };
+        for (inst, loc) in insts.insts.iter().zip(insts.locs.iter()) {
+            let offset = func.byte_len() as u32;
+            if locs.source_locs.last().map(|&(_, last)| last) != Some(loc.source) {
+                locs.source_locs.push((offset, loc.source));
+            }
+            if let Some(value) = loc.value {
+                if locs.values.last().map(|&(_, last)| last) != Some(value) {
+                    locs.values.push((offset, value));
+                }
+            }
+            if let Some(orig_offset) = loc.orig_offset {
+                if locs.orig_offsets.last().map(|&(_, last)| last) != Some(orig_offset) {
+                    locs.orig_offsets.push((offset, orig_offset));
+                }
+            }
+            func.instruction(inst);
+        }
 
         log::debug!("Compiled to:\n{:?}\n", func);
 
-        Ok(func)
+        Ok((func, locs))
     }
 
-    fn lower_block(&self, block: &WasmBlock<'_>, func: &mut wasm_encoder::Function) {
-        match block {
-            WasmBlock::Block { body, .. } => {
-                func.instruction(&wasm_encoder::Instruction::Block(
-                    wasm_encoder::BlockType::Empty,
-                ));
-                for sub_block in &body[..] {
-                    self.lower_block(sub_block, func);
+    /// Renumber non-param locals so that, within each run of locals
+    /// of the same type, the most-used come first and get the
+    /// shortest LEB128 indices. Types are unchanged, since locals
+    /// only move within their run. Returns the new index of each
+    /// local.
+    fn renumber_locals_by_use(
+        insts: &mut InstBuffer,
+        local_types: &[Type],
+        n_params: usize,
+    ) -> Vec<u32> {
+        let mut uses = vec![0usize; local_types.len()];
+        for inst in &insts.insts {
+            match *inst {
+                wasm_encoder::Instruction::LocalGet(local)
+                | wasm_encoder::Instruction::LocalSet(local)
+                | wasm_encoder::Instruction::LocalTee(local) => uses[local as usize] += 1,
+                _ => {}
+            }
+        }
+
+        let mut order = (n_params..local_types.len()).collect::<Vec<_>>();
+        // A stable sort by (type run, uses) keeps runs in place.
+        order.sort_by_key(|&local| {
+            let run_start = local_types[n_params..local]
+                .iter()
+                .rposition(|&ty| ty != local_types[local])
+                .map(|pos| pos + 1)
+                .unwrap_or(0);
+            (run_start, std::cmp::Reverse(uses[local]))
+        });
+        let mut remap = (0..local_types.len() as u32).collect::<Vec<_>>();
+        for (i, &local) in order.iter().enumerate() {
+            remap[local] = (n_params + i) as u32;
+        }
+        for inst in &mut insts.insts {
+            match inst {
+                wasm_encoder::Instruction::LocalGet(local)
+                | wasm_encoder::Instruction::LocalSet(local)
+                | wasm_encoder::Instruction::LocalTee(local) => *local = remap[*local as usize],
+                _ => {}
+            }
+        }
+        remap
+    }
+
+    /// Name each allocated local after the Wasm locals of the values
+    /// assigned to it, taking the most common name.
+    fn local_names(&self, local_remap: &[u32]) -> Vec<(u32, String)> {
+        let mut candidates: BTreeMap<u32, Vec<(&str, usize)>> = BTreeMap::new();
+        for value in self.body.values.iter() {
+            let local = match &self.locals.values[value][..] {
+                &[local] => local_remap[local.index()],
+                _ => continue,
+            };
+            let name = match self.body.value_locals[value]
+                .and_then(|orig| self.body.local_names[orig].as_deref())
+            {
+                Some(name) => name,
+                None => continue,
+            };
+            let names = candidates.entry(local).or_default();
+            match names.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, count)) => *count += 1,
+                None => names.push((name, 1)),
+            }
+        }
+        candidates
+            .into_iter()
+            .filter_map(|(local, names)| {
+                // The first of the most common names wins ties.
+                let (name, _) = names.iter().rev().max_by_key(|(_, count)| *count)?;
+                Some((local, name.to_string()))
+            })
+            .collect()
+    }
+
+    /// Name each `block`, `loop`, and `if` label after the IR block
+    /// that branches to it go to.
+    fn label_names(&self, insts: &InstBuffer) -> Vec<(u32, String)> {
+        let mut names = vec![];
+        let mut index = 0;
+        for (inst, loc) in insts.insts.iter().zip(insts.locs.iter()) {
+            match inst {
+                wasm_encoder::Instruction::Block(_)
+                | wasm_encoder::Instruction::Loop(_)
+                | wasm_encoder::Instruction::If(_) => {
+                    if let Some(name) = loc
+                        .label
+                        .filter(|b| b.is_valid())
+                        .and_then(|b| self.body.label_names[b].as_ref())
+                    {
+                        names.push((index, name.clone()));
+                    }
+                    index += 1;
                 }
-                func.instruction(&wasm_encoder::Instruction::End);
+                _ => {}
             }
-            WasmBlock::Loop { body, .. } => {
-                func.instruction(&wasm_encoder::Instruction::Loop(
-                    wasm_encoder::BlockType::Empty,
-                ));
-                for sub_block in &body[..] {
-                    self.lower_block(sub_block, func);
+        }
+        names
+    }
+
+    /// The blockparam of `out` whose value can flow out of a Wasm
+    /// block labeled by `out` as its result, if `out` has exactly one
+    /// live blockparam.
+    ///
+    /// Only MVP block types are emitted: when `out` has several live
+    /// blockparams, they all go through locals rather than a
+    /// multi-result block, whether or not the target has multivalue.
+    fn block_result(&self, out: Block) -> Option<Value> {
+        if out.is_invalid() {
+            return None;
+        }
+        let mut live = self.body.blocks[out]
+            .params
+            .iter()
+            .filter(|&&(_, param)| !self.locals.values[param].is_empty());
+        match (live.next(), live.next()) {
+            (Some(&(_, param)), None) => Some(param),
+            _ => None,
+        }
+    }
+
+    /// Resolve a stackify label to a Wasm branch depth, skipping
+    /// frames whose Wasm scope was elided, and return the result the
+    /// target carries, if any.
+    fn resolve_label(frames: &[Frame], target: WasmLabel) -> (u32, Option<Value>) {
+        let index = target.index() as usize;
+        let (outer, inner) = frames.split_at(frames.len() - 1 - index);
+        debug_assert!(!inner[0].elided);
+        let elided = inner.iter().filter(|frame| frame.elided).count();
+        match inner[0].forward {
+            Some(forward) => {
+                let (depth, result) = Self::resolve_label(outer, forward);
+                (depth + (index + 1 - elided) as u32, result)
+            }
+            None => ((index - elided) as u32, inner[0].result),
+        }
+    }
+
+    /// If `arm` is only a branch that transfers no values, its target.
+    fn trivial_branch(&self, arm: &[WasmBlock<'_>], frames: &[Frame]) -> Option<WasmLabel> {
+        let target = match arm {
+            [WasmBlock::Br { target }] => *target,
+            [WasmBlock::BlockParams { to, .. }, WasmBlock::Br { target }]
+                if to
+                    .iter()
+                    .all(|&(_, param)| self.locals.values[param].is_empty()) =>
+            {
+                *target
+            }
+            _ => return None,
+        };
+        // The IfThenElse frame itself is never a branch target.
+        match Self::resolve_label(frames, target) {
+            (_, None) => Some(target),
+            (_, Some(_)) => None,
+        }
+    }
+
+    /// Lower a sequence of blocks. `frames` has one entry per
+    /// enclosing stackify control frame.
+    fn lower_blocks(
+        &self,
+        blocks: &[WasmBlock<'_>],
+        frames: &mut Vec<Frame>,
+        func: &mut InstBuffer,
+    ) -> Result<()> {
+        let mut i = 0;
+        while i < blocks.len() {
+            // A blockparam transfer immediately followed by a branch
+            // to a block with a typed result: leave the value on the
+            // stack instead of setting the blockparam's local.
+            if let (Some(WasmBlock::BlockParams { from, to }), Some(WasmBlock::Br { target })) =
+                (blocks.get(i), blocks.get(i + 1))
+            {
+                if let (depth, Some(param)) = Self::resolve_label(&frames[..], *target) {
+                    let pos = match to.iter().position(|&(_, to)| to == param) {
+                        Some(pos) => pos,
+                        None => bail!(BackendError::MalformedBody {
+                            func: None,
+                            message: format!("branch does not pass result {}", param),
+                        }),
+                    };
+                    self.lower_value(from[pos], func)?;
+                    func.instruction(&wasm_encoder::Instruction::Br(depth));
+                    i += 2;
+                    continue;
                 }
+            }
+            // In size mode, a block followed only by a branch
+            // elsewhere is a trampoline: send branches to its end
+            // straight to the branch's target, so the block can be
+            // elided, along with the branch if nothing falls into it.
+            if let (OptimizeFor::Size, Some(WasmBlock::Block { body, out })) =
+                (self.optimize_for, blocks.get(i))
+            {
+                let trampoline = [1, 2].iter().find_map(|&len| {
+                    let arm = blocks.get(i + 1..i + 1 + len)?;
+                    Some((len, self.trivial_branch(arm, &frames[..])?))
+                });
+                if let (None, Some((len, target))) = (self.block_result(*out), trampoline) {
+                    let start = func.insts.len();
+                    func.scope(
+                        &wasm_encoder::Instruction::Block(wasm_encoder::BlockType::Empty),
+                        *out,
+                    );
+                    frames.push(Frame {
+                        forward: Some(target),
+                        ..Frame::default()
+                    });
+                    self.lower_blocks(&body[..], frames, func)?;
+                    frames.pop();
+                    func.end_block(start);
+                    i += 1;
+                    if func.ends_unconditionally() {
+                        i += len;
+                    }
+                    continue;
+                }
+            }
+            self.lower_block(&blocks[i], frames, func)?;
+            i += 1;
+        }
+        Ok(())
+    }
+
+    fn lower_block(
+        &self,
+        block: &WasmBlock<'_>,
+        frames: &mut Vec<Frame>,
+        func: &mut InstBuffer,
+    ) -> Result<()> {
+        match block {
+            WasmBlock::Block { body, out } => {
+                let result = self.block_result(*out);
+                let block_type = match result {
+                    Some(param) => match self.body.values[param].ty(&self.body.type_pool) {
+                        Some(ty) => wasm_encoder::BlockType::Result(ty.into()),
+                        None => bail!(BackendError::MalformedBody {
+                            func: None,
+                            message: format!("blockparam {} has no single type", param),
+                        }),
+                    },
+                    None => wasm_encoder::BlockType::Empty,
+                };
+                let start = func.insts.len();
+                func.scope(&wasm_encoder::Instruction::Block(block_type), *out);
+                frames.push(Frame {
+                    result,
+                    ..Frame::default()
+                });
+                self.lower_blocks(&body[..], frames, func)?;
+                frames.pop();
+                if result.is_none() && self.optimize_for == OptimizeFor::Size {
+                    func.end_block(start);
+                } else if let Some(param) = result {
+                    // Every path to `out` is an explicit branch, but
+                    // the end of the block must still type-check.
+                    if !func.ends_unconditionally() {
+                        func.instruction(&wasm_encoder::Instruction::Unreachable);
+                    }
+                    func.instruction(&wasm_encoder::Instruction::End);
+                    self.lower_set_value(param, func);
+                } else {
+                    func.instruction(&wasm_encoder::Instruction::End);
+                }
+            }
+            WasmBlock::Loop { body, header } => {
+                func.scope(
+                    &wasm_encoder::Instruction::Loop(wasm_encoder::BlockType::Empty),
+                    *header,
+                );
+                frames.push(Frame::default());
+                self.lower_blocks(&body[..], frames, func)?;
+                frames.pop();
                 func.instruction(&wasm_encoder::Instruction::End);
             }
             WasmBlock::Br { target } => {
-                func.instruction(&wasm_encoder::Instruction::Br(target.index()));
+                let (depth, result) = Self::resolve_label(&frames[..], *target);
+                debug_assert!(result.is_none());
+                func.instruction(&wasm_encoder::Instruction::Br(depth));
             }
             WasmBlock::If {
                 cond,
                 if_true,
                 if_false,
             } => {
-                self.lower_value(*cond, func);
+                // If one arm is just a branch, emit a `br_if` and the
+                // other arm inline, without an `if` scope.
+                frames.push(Frame {
+                    elided: true,
+                    ..Frame::default()
+                });
+                let br_if = match self.trivial_branch(&if_true[..], &frames[..]) {
+                    Some(target) => Some((target, false, if_false)),
+                    None => self
+                        .trivial_branch(&if_false[..], &frames[..])
+                        .map(|target| (target, true, if_true)),
+                };
+                if let Some((target, negate, other)) = br_if {
+                    let (depth, _) = Self::resolve_label(&frames[..], target);
+                    self.lower_value(*cond, func)?;
+                    if negate {
+                        func.instruction(&wasm_encoder::Instruction::I32Eqz);
+                    }
+                    func.instruction(&wasm_encoder::Instruction::BrIf(depth));
+                    self.lower_blocks(&other[..], frames, func)?;
+                    frames.pop();
+                    return Ok(());
+                }
+                frames.pop();
+                frames.push(Frame::default());
+
+                // The `if` is always untyped: stackify's arms end in
+                // branches, never falling through with a value, and
+                // values flowing out of them are set in locals.
+                self.lower_value(*cond, func)?;
                 func.instruction(&wasm_encoder::Instruction::If(
                     wasm_encoder::BlockType::Empty,
                 ));
-                for sub_block in &if_true[..] {
-                    self.lower_block(sub_block, func);
-                }
+                self.lower_blocks(&if_true[..], frames, func)?;
                 if if_false.len() > 0 {
                     func.instruction(&wasm_encoder::Instruction::Else);
-                    for sub_block in &if_false[..] {
-                        self.lower_block(sub_block, func);
-                    }
+                    self.lower_blocks(&if_false[..], frames, func)?;
                 }
+                frames.pop();
                 func.instruction(&wasm_encoder::Instruction::End);
             }
             WasmBlock::Select {
@@ -127,26 +478,60 @@ impl<'a> WasmFuncBackend<'a> {
                 targets,
                 default,
             } => {
-                self.lower_value(*selector, func);
-                func.instruction(&wasm_encoder::Instruction::BrTable(
-                    Cow::Owned(
-                        targets
-                            .iter()
-                            .map(|label| label.index())
-                            .collect::<Vec<_>>(),
-                    ),
-                    default.index(),
+                let targets = targets
+                    .iter()
+                    .map(|&label| Self::resolve_label(&frames[..], label).0)
+                    .collect::<Vec<_>>();
+                let default = Self::resolve_label(&frames[..], *default).0;
+                let mut table = InstBuffer {
+                    loc: func.loc,
+                    ..InstBuffer::default()
+                };
+                self.lower_value(*selector, &mut table)?;
+                table.instruction(&wasm_encoder::Instruction::BrTable(
+                    Cow::Owned(targets.clone()),
+                    default,
                 ));
+
+                // A selector in a local can be read once per target,
+                // so a chain of `br_if`s, skipping targets that go to
+                // the default, may be smaller.
+                let selector_in_local =
+                    !self.trees.is_owned(selector) && !self.trees.remat.contains(selector);
+                if self.optimize_for == OptimizeFor::Size && selector_in_local {
+                    let mut chain = InstBuffer {
+                        loc: func.loc,
+                        ..InstBuffer::default()
+                    };
+                    for (i, &target) in targets.iter().enumerate() {
+                        if target == default {
+                            continue;
+                        }
+                        self.lower_value(*selector, &mut chain)?;
+                        if i == 0 {
+                            chain.instruction(&wasm_encoder::Instruction::I32Eqz);
+                        } else {
+                            chain.instruction(&wasm_encoder::Instruction::I32Const(i as i32));
+                            chain.instruction(&wasm_encoder::Instruction::I32Eq);
+                        }
+                        chain.instruction(&wasm_encoder::Instruction::BrIf(target));
+                    }
+                    chain.instruction(&wasm_encoder::Instruction::Br(default));
+                    if chain.byte_len() < table.byte_len() {
+                        table = chain;
+                    }
+                }
+                func.append(table);
             }
             WasmBlock::Leaf { block } => {
                 for &inst in &self.body.blocks[*block].insts {
                     // If this value is "owned", do nothing: it will be lowered in
                     // the one place it's used.
-                    if self.trees.owner.contains_key(&inst) || self.trees.remat.contains(&inst) {
+                    if self.trees.is_owned(&inst) || self.trees.remat.contains(&inst) {
                         continue;
                     }
                     if let &ValueDef::Operator(..) = &self.body.values[inst] {
-                        self.lower_inst(inst, /* root = */ true, func);
+                        self.lower_inst(inst, /* root = */ true, func)?;
                     }
                 }
             }
@@ -156,7 +541,7 @@ impl<'a> WasmFuncBackend<'a> {
                     if self.locals.values[to].is_empty() {
                         continue;
                     }
-                    self.lower_value(from, func);
+                    self.lower_value(from, func)?;
                 }
                 for &(_, to) in to.iter().rev() {
                     if self.locals.values[to].is_empty() {
@@ -167,7 +552,7 @@ impl<'a> WasmFuncBackend<'a> {
             }
             WasmBlock::Return { values } => {
                 for &value in &values[..] {
-                    self.lower_value(value, func);
+                    self.lower_value(value, func)?;
                 }
                 func.instruction(&wasm_encoder::Instruction::Return);
             }
@@ -175,26 +560,32 @@ impl<'a> WasmFuncBackend<'a> {
                 func.instruction(&wasm_encoder::Instruction::Unreachable);
             }
         }
+        Ok(())
     }
 
-    fn lower_value(&self, value: Value, func: &mut wasm_encoder::Function) {
+    fn lower_value(&self, value: Value, func: &mut InstBuffer) -> Result<()> {
         log::trace!("lower_value: value {}", value);
         let value = self.body.resolve_alias(value);
-        if self.trees.remat.contains(&value) {
-            self.lower_inst(value, /* root = */ false, func);
+        if self.trees.remat.contains(&value) || self.trees.is_owned(&value) {
+            self.lower_inst(value, /* root = */ false, func)?;
         } else {
             let local = match &self.body.values[value] {
                 &ValueDef::BlockParam(..) | &ValueDef::Operator(..) => self.locals.values[value][0],
                 &ValueDef::PickOutput(orig_value, idx, _) => {
                     self.locals.values[orig_value][idx as usize]
                 }
-                _ => unreachable!(),
+                def => bail!(BackendError::UnsupportedValue {
+                    func: None,
+                    value,
+                    def: format!("{:?}", def),
+                }),
             };
             func.instruction(&wasm_encoder::Instruction::LocalGet(local.index() as u32));
         }
+        Ok(())
     }
 
-    fn lower_set_value(&self, value: Value, func: &mut wasm_encoder::Function) {
+    fn lower_set_value(&self, value: Value, func: &mut InstBuffer) {
         debug_assert_eq!(
             self.locals.values[value].len(),
             1,
@@ -205,17 +596,23 @@ impl<'a> WasmFuncBackend<'a> {
         func.instruction(&wasm_encoder::Instruction::LocalSet(local.index() as u32));
     }
 
-    fn lower_inst(&self, value: Value, root: bool, func: &mut wasm_encoder::Function) {
+    fn lower_inst(&self, value: Value, root: bool, func: &mut InstBuffer) -> Result<()> {
         log::trace!("lower_inst: value {} root {}", value, root);
         match &self.body.values[value] {
             &ValueDef::Operator(ref op, args, tys) => {
+                let outer_loc = func.set_loc(InstLoc {
+                    source: self.body.source_locs[value],
+                    orig_offset: self.body.orig_offsets[value],
+                    value: Some(value),
+                    label: None,
+                });
                 for &arg in &self.body.arg_pool[args] {
                     let arg = self.body.resolve_alias(arg);
-                    if self.trees.owner.contains_key(&arg) || self.trees.remat.contains(&arg) {
+                    if self.trees.is_owned(&arg) || self.trees.remat.contains(&arg) {
                         log::trace!(" -> arg {} is owned", arg);
-                        self.lower_inst(arg, /* root = */ false, func);
+                        self.lower_inst(arg, /* root = */ false, func)?;
                     } else {
-                        self.lower_value(arg, func);
+                        self.lower_value(arg, func)?;
                     }
                 }
                 self.lower_op(op, func);
@@ -230,15 +627,21 @@ impl<'a> WasmFuncBackend<'a> {
                         func.instruction(&wasm_encoder::Instruction::Drop);
                     }
                 }
+                func.set_loc(outer_loc);
             }
             &ValueDef::PickOutput(..) => {
-                self.lower_value(value, func);
+                self.lower_value(value, func)?;
             }
-            def => unreachable!("Unexpected inst: {:?}", def),
+            def => bail!(BackendError::UnsupportedValue {
+                func: None,
+                value,
+                def: format!("{:?}", def),
+            }),
         }
+        Ok(())
     }
 
-    fn lower_op(&self, op: &Operator, func: &mut wasm_encoder::Function) {
+    fn lower_op(&self, op: &Operator, func: &mut InstBuffer) {
         let inst = match op {
             Operator::Unreachable => Some(wasm_encoder::Instruction::Unreachable),
             Operator::Nop => None,
@@ -522,7 +925,189 @@ impl<'a> WasmFuncBackend<'a> {
     }
 }
 
+/// How source locations are carried over into the compiled module.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum DebugInfoMode {
+    /// Emit no debug info.
+    #[default]
+    None,
+    /// Emit a DWARF `.debug_line` program for the new code section,
+    /// in a minimal compilation unit.
+    DwarfLine,
+    /// Produce a source map, referenced by a `sourceMappingURL`
+    /// section with the given URL.
+    SourceMap { url: String },
+}
+
+/// What the backend trades off when it has a choice of encodings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OptimizeFor {
+    /// Favor fewer instructions executed: rematerialize all
+    /// constants and use `br_table` for every switch.
+    #[default]
+    Speed,
+    /// Favor fewer bytes: treeify more aggressively, pick the smaller
+    /// of equivalent encodings, and elide unneeded blocks.
+    Size,
+}
+
+impl std::str::FromStr for OptimizeFor {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<OptimizeFor> {
+        match s {
+            "speed" => Ok(OptimizeFor::Speed),
+            "size" => Ok(OptimizeFor::Size),
+            _ => bail!("Unknown optimization goal: {}", s),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CompileOptions {
+    pub debug_info: DebugInfoMode,
+    pub optimize_for: OptimizeFor,
+    /// Build a map between instruction offsets in the original and
+    /// compiled modules.
+    pub offset_map: bool,
+    /// Validate the compiled module, failing with a
+    /// `BackendError::Validation` if it is invalid.
+    pub validate: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompileOutput {
+    pub wasm: Vec<u8>,
+    /// The source map's JSON text, in `DebugInfoMode::SourceMap`
+    /// mode.
+    pub source_map: Option<String>,
+    /// The offset map, if `CompileOptions::offset_map` is set.
+    pub offset_map: Option<OffsetMap>,
+    /// The size of every function body with code, in code-section
+    /// order.
+    pub func_sizes: Vec<FuncSize>,
+}
+
+/// The size of one compiled function body, not counting its size
+/// prefix.
+#[derive(Clone, Copy, Debug)]
+pub struct FuncSize {
+    pub func: Func,
+    /// The size of the body in the original module, if it was parsed
+    /// from there.
+    pub orig_size: Option<usize>,
+    pub new_size: usize,
+}
+
+impl FuncSize {
+    /// How many bytes the body grew by, if it has an original size.
+    pub fn delta(&self) -> Option<isize> {
+        self.orig_size
+            .map(|orig_size| self.new_size as isize - orig_size as isize)
+    }
+}
+
+/// Where the instructions of a compiled body came from. Offsets are
+/// from the start of the encoded body, after its size prefix.
+#[derive(Clone, Debug, Default)]
+pub struct BodyLocs {
+    /// The source location of each run of instructions, by starting
+    /// offset.
+    pub source_locs: Vec<(u32, SourceLoc)>,
+    /// `(offset, original offset)` for each instruction compiled from
+    /// an operator in the original module.
+    pub orig_offsets: Vec<(u32, u32)>,
+    /// The IR value lowered into each run of instructions, by
+    /// starting offset.
+    pub values: Vec<(u32, Value)>,
+    /// Names of the body's locals and labels, by index, for the name
+    /// section.
+    pub local_names: Vec<(u32, String)>,
+    pub label_names: Vec<(u32, String)>,
+}
+
+/// Compile `module` with the default options. Prefer
+/// `Module::to_wasm_bytes`, or `Module::compile` to pass options.
 pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
+    module.to_wasm_bytes()
+}
+
+/// Source locations for an uncompiled body, from the input's debug
+/// map, as offsets from the start of the body.
+fn raw_body_locs(
+    module: &Module<'_>,
+    reader: &wasmparser::FunctionBody<'_>,
+    options: &CompileOptions,
+) -> Result<BodyLocs> {
+    let range = reader.range();
+    let mut locs = BodyLocs::default();
+    if let Some(names) = module.orig_body_names.get(&range.start) {
+        locs.local_names = names.locals.clone();
+        locs.label_names = names.labels.clone();
+    }
+
+    let code_offset = module.debug_map.code_offset as usize;
+    if options.debug_info != DebugInfoMode::None && range.start >= code_offset {
+        let start = (range.start - code_offset) as u32;
+        let end = (range.end - code_offset) as u32;
+        for &(tuple_start, len, loc) in &module.debug_map.tuples {
+            let tuple_end = tuple_start + len;
+            if tuple_end <= start || tuple_start >= end {
+                continue;
+            }
+            locs.source_locs.push((tuple_start.max(start) - start, loc));
+            if tuple_end < end {
+                locs.source_locs
+                    .push((tuple_end - start, SourceLoc::invalid()));
+            }
+        }
+    }
+
+    if options.offset_map {
+        // The body is copied verbatim, so every operator maps to
+        // itself.
+        for item in reader.get_operators_reader()?.into_iter_with_offsets() {
+            let (_, offset) = item?;
+            locs.orig_offsets
+                .push(((offset - range.start) as u32, offset as u32));
+        }
+    }
+
+    Ok(locs)
+}
+
+/// A name-section map from `(index, name)` pairs in index order.
+fn name_map<S: AsRef<str>>(names: impl IntoIterator<Item = (u32, S)>) -> wasm_encoder::NameMap {
+    let mut map = wasm_encoder::NameMap::new();
+    for (index, name) in names {
+        map.append(index, name.as_ref());
+    }
+    map
+}
+
+/// A name-section map of per-function name maps, in function order.
+fn indirect_name_map(maps: Vec<(u32, wasm_encoder::NameMap)>) -> wasm_encoder::IndirectNameMap {
+    let mut indirect = wasm_encoder::IndirectNameMap::new();
+    for (func, map) in maps {
+        indirect.append(func, &map);
+    }
+    indirect
+}
+
+/// The encoded size of a LEB128 `u32`.
+fn leb_len(value: usize) -> usize {
+    let mut len = 1;
+    let mut value = value >> 7;
+    while value != 0 {
+        len += 1;
+        value >>= 7;
+    }
+    len
+}
+
+pub fn compile_with_options(
+    module: &Module<'_>,
+    options: &CompileOptions,
+) -> anyhow::Result<CompileOutput> {
     let mut into_mod = wasm_encoder::Module::new();
 
     let mut types = wasm_encoder::TypeSection::new();
@@ -591,13 +1176,13 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
     let mut funcs = wasm_encoder::FunctionSection::new();
     for (func, func_decl) in module.funcs.entries().skip(num_func_imports) {
         match func_decl {
-            FuncDecl::Import(_, _) => anyhow::bail!("Import comes after func with body: {}", func),
+            FuncDecl::Import(_, _) => bail!(BackendError::ImportAfterBody(func)),
             FuncDecl::Lazy(sig, _, _)
             | FuncDecl::Body(sig, _, _)
             | FuncDecl::Compiled(sig, _, _) => {
                 funcs.function(sig.index() as u32);
             }
-            FuncDecl::None => panic!("FuncDecl::None at compilation time"),
+            FuncDecl::None => bail!(BackendError::MissingFunc(func)),
         }
     }
     into_mod.section(&funcs);
@@ -628,13 +1213,13 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
     into_mod.section(&memories);
 
     let mut globals = wasm_encoder::GlobalSection::new();
-    for global_data in module.globals.values().skip(num_global_imports) {
+    for (global, global_data) in module.globals.entries().skip(num_global_imports) {
         globals.global(
             wasm_encoder::GlobalType {
                 val_type: wasm_encoder::ValType::from(global_data.ty),
                 mutable: global_data.mutable,
             },
-            &const_init(global_data.ty, global_data.value),
+            &const_init(global, global_data.ty, global_data.value)?,
         );
     }
     into_mod.section(&globals);
@@ -715,24 +1300,103 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
             match func_decl {
                 FuncDecl::Lazy(_, _name, reader) => {
                     let data = &module.orig_bytes[reader.range()];
-                    Ok(FuncOrRawBytes::Raw(data))
-                }
-                FuncDecl::Compiled(_, _name, encoder) => {
-                    Ok(FuncOrRawBytes::Func(Cow::Borrowed(encoder)))
+                    let locs = raw_body_locs(module, reader, options)?;
+                    let orig_range = Some(reader.range());
+                    Ok((*func, FuncOrRawBytes::Raw(data), locs, orig_range))
                 }
-                FuncDecl::Body(_, name, body) => {
-                    log::debug!("Compiling {} \"{}\"", func, name);
-                    WasmFuncBackend::new(body)?
-                        .compile()
-                        .map(|func| FuncOrRawBytes::Func(Cow::Owned(func)))
-                }
-                FuncDecl::Import(_, _) => unreachable!("Should have skipped imports"),
-                FuncDecl::None => panic!("FuncDecl::None at compilation time"),
+                FuncDecl::Compiled(_, _name, encoder) => Ok((
+                    *func,
+                    FuncOrRawBytes::Func(Cow::Borrowed(encoder)),
+                    BodyLocs::default(),
+                    None,
+                )),
+                FuncDecl::Body(_, name, body) => match body.clean_orig_range() {
+                    Some(range) => {
+                        // Unchanged since it was parsed: copy the
+                        // original bytes, as for a lazy body.
+                        log::debug!("Keeping original bytes for {} \"{}\"", func, name);
+                        let data = &module.orig_bytes[range.clone()];
+                        let reader = wasmparser::FunctionBody::new(range.start, data);
+                        let locs = raw_body_locs(module, &reader, options)?;
+                        Ok((*func, FuncOrRawBytes::Raw(data), locs, Some(range)))
+                    }
+                    None => {
+                        log::debug!("Compiling {} \"{}\"", func, name);
+                        let (compiled, locs) = body
+                            .compile_with_locs(options.optimize_for)
+                            .map_err(|e| match e.downcast::<BackendError>() {
+                                Ok(e) => e.in_func(*func).into(),
+                                Err(e) => e,
+                            })?;
+                        Ok((
+                            *func,
+                            FuncOrRawBytes::Func(Cow::Owned(compiled)),
+                            locs,
+                            body.orig_range.clone(),
+                        ))
+                    }
+                },
+                FuncDecl::Import(_, _) => bail!(BackendError::ImportAfterBody(*func)),
+                FuncDecl::None => bail!(BackendError::MissingFunc(*func)),
             }
         })
         .collect::<Result<Vec<_>>>()?;
 
-    for body in bodies {
+    // Offsets in the location table are relative to the start of
+    // the code section's contents, which begin with the body count.
+    let count_len = leb_len(bodies.len());
+    let mut rows: Vec<LocRow> = vec![];
+    let mut func_offsets = vec![];
+    let mut func_values = vec![];
+    let mut func_sizes = vec![];
+    let mut local_names = vec![];
+    let mut label_names = vec![];
+    for (func, body, locs, orig_range) in bodies {
+        let body_len = match &body {
+            FuncOrRawBytes::Raw(bytes) => bytes.len(),
+            FuncOrRawBytes::Func(func) => func.byte_len(),
+        };
+        func_sizes.push(FuncSize {
+            func,
+            orig_size: orig_range.as_ref().map(|range| range.len()),
+            new_size: body_len,
+        });
+        let body_start = (count_len + code.byte_len() + leb_len(body_len)) as u32;
+        if options.debug_info != DebugInfoMode::None {
+            let source_locs = std::iter::once((0, SourceLoc::invalid())).chain(locs.source_locs);
+            for (offset, loc) in source_locs {
+                // A later row at the same offset replaces an earlier one.
+                let offset = body_start + offset;
+                if rows.last().map(|&(last_offset, _)| last_offset) == Some(offset) {
+                    rows.pop();
+                }
+                if rows.last().map(|&(_, last)| last) != Some(loc) {
+                    rows.push((offset, loc));
+                }
+            }
+        }
+        for (names, map) in [
+            (&locs.local_names, &mut local_names),
+            (&locs.label_names, &mut label_names),
+        ] {
+            if !names.is_empty() {
+                map.push((
+                    func.index() as u32,
+                    name_map(names.iter().map(|(i, n)| (*i, n))),
+                ));
+            }
+        }
+        if options.validate {
+            func_values.push((func, body_start..body_start + body_len as u32, locs.values));
+        }
+        if options.offset_map {
+            func_offsets.push((
+                func,
+                body_start..body_start + body_len as u32,
+                orig_range,
+                locs.orig_offsets,
+            ));
+        }
         match body {
             FuncOrRawBytes::Raw(bytes) => {
                 code.raw(bytes);
@@ -742,8 +1406,33 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
             }
         }
     }
+    let code_len = count_len + code.byte_len();
+    let code_offset_in_file = into_mod.as_slice().len() + 1 + leb_len(code_len);
     into_mod.section(&code);
 
+    let offset_map = if options.offset_map {
+        let shift = code_offset_in_file as u32;
+        Some(OffsetMap {
+            funcs: func_offsets
+                .into_iter()
+                .map(|(func, new_range, orig_range, orig_offsets)| {
+                    let start = new_range.start + shift;
+                    FuncOffsetMap::new(
+                        func,
+                        start..new_range.end + shift,
+                        orig_range.map(|range| range.start as u32..range.end as u32),
+                        orig_offsets
+                            .into_iter()
+                            .map(|(new, orig)| (start + new, orig))
+                            .collect(),
+                    )
+                })
+                .collect(),
+        })
+    } else {
+        None
+    };
+
     let mut data = wasm_encoder::DataSection::new();
     for (mem, mem_data) in module.memories.entries() {
         for segment in &mem_data.segments {
@@ -762,18 +1451,138 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
         func_names.append(func.index() as u32, decl.name());
     }
     names.functions(&func_names);
+    if !local_names.is_empty() {
+        names.locals(&indirect_name_map(local_names));
+    }
+    if !label_names.is_empty() {
+        names.labels(&indirect_name_map(label_names));
+    }
+    let entity_names = [
+        name_map(module.signatures.iter().filter_map(|sig| {
+            module.signature_names[sig]
+                .as_deref()
+                .map(|name| (sig.index() as u32, name))
+        })),
+        name_map(module.tables.iter().filter_map(|table| {
+            module.table_names[table]
+                .as_deref()
+                .map(|name| (table.index() as u32, name))
+        })),
+        name_map(module.memories.iter().filter_map(|mem| {
+            module.memory_names[mem]
+                .as_deref()
+                .map(|name| (mem.index() as u32, name))
+        })),
+        name_map(module.globals.iter().filter_map(|global| {
+            module.global_names[global]
+                .as_deref()
+                .map(|name| (global.index() as u32, name))
+        })),
+        name_map(
+            module
+                .memories
+                .entries()
+                .flat_map(|(mem, mem_data)| (0..mem_data.segments.len()).map(move |i| (mem, i)))
+                .enumerate()
+                .filter_map(|(i, segment)| {
+                    module
+                        .segment_names
+                        .get(&segment)
+                        .map(|name| (i as u32, name))
+                }),
+        ),
+    ];
+    let [types, tables, memories, globals, data] = &entity_names;
+    if !types.is_empty() {
+        names.types(types);
+    }
+    if !tables.is_empty() {
+        names.tables(tables);
+    }
+    if !memories.is_empty() {
+        names.memories(memories);
+    }
+    if !globals.is_empty() {
+        names.globals(globals);
+    }
+    if !data.is_empty() {
+        names.data(data);
+    }
     into_mod.section(&names);
 
-    Ok(into_mod.finish())
+    let mut source_map = None;
+    match &options.debug_info {
+        DebugInfoMode::None => {}
+        DebugInfoMode::DwarfLine => {
+            for (name, data) in
+                debuginfo::dwarf_line_sections(&module.debug, &rows[..], code_len as u32)?
+            {
+                into_mod.section(&wasm_encoder::CustomSection {
+                    name,
+                    data: &data[..],
+                });
+            }
+        }
+        DebugInfoMode::SourceMap { url } => {
+            source_map = Some(debuginfo::source_map(
+                &module.debug,
+                &rows[..],
+                code_offset_in_file as u32,
+            ));
+            let mut data = vec![];
+            wasm_encoder::Encode::encode(&url[..], &mut data);
+            into_mod.section(&wasm_encoder::CustomSection {
+                name: "sourceMappingURL",
+                data: &data[..],
+            });
+        }
+    }
+
+    let wasm = into_mod.finish();
+    if options.validate {
+        if let Err(e) = wasmparser::Validator::new().validate_all(&wasm[..]) {
+            // Find the function, and the value lowered last at or
+            // before the failing offset.
+            let code_offset = e
+                .offset()
+                .checked_sub(code_offset_in_file)
+                .map(|offset| offset as u32);
+            let (func, value) = match code_offset.and_then(|offset| {
+                func_values
+                    .iter()
+                    .find(|(_, range, _)| range.contains(&offset))
+                    .map(|(func, range, values)| (offset - range.start, func, values))
+            }) {
+                Some((offset, &func, values)) => {
+                    let idx = values.partition_point(|&(start, _)| start <= offset);
+                    (Some(func), idx.checked_sub(1).map(|idx| values[idx].1))
+                }
+                None => (None, None),
+            };
+            bail!(BackendError::Validation {
+                func,
+                value,
+                offset: e.offset(),
+                message: e.message().to_owned(),
+            });
+        }
+    }
+
+    Ok(CompileOutput {
+        wasm,
+        source_map,
+        offset_map,
+        func_sizes,
+    })
 }
 
-fn const_init(ty: Type, value: Option<u64>) -> wasm_encoder::ConstExpr {
+fn const_init(global: Global, ty: Type, value: Option<u64>) -> Result<wasm_encoder::ConstExpr> {
     let bits = value.unwrap_or(0);
-    match ty {
+    Ok(match ty {
         Type::I32 => wasm_encoder::ConstExpr::i32_const(bits as u32 as i32),
         Type::I64 => wasm_encoder::ConstExpr::i64_const(bits as i64),
         Type::F32 => wasm_encoder::ConstExpr::f32_const(f32::from_bits(bits as u32)),
         Type::F64 => wasm_encoder::ConstExpr::f64_const(f64::from_bits(bits as u64)),
-        _ => unimplemented!(),
-    }
+        _ => bail!(BackendError::UnsupportedGlobalType(global, ty)),
+    })
 }
@@ -0,0 +1,139 @@
+//! Mapping between instruction offsets in the original and compiled
+//! modules.
+
+use crate::entity::EntityRef;
+use crate::ir::Func;
+use std::fmt::Write;
+use std::ops::Range;
+
+/// Offsets for one function body. All offsets are from the start of
+/// their module's bytes.
+#[derive(Clone, Debug)]
+pub struct FuncOffsetMap {
+    pub func: Func,
+    /// Byte range of the body in the compiled module, after its size
+    /// prefix.
+    pub new_range: Range<u32>,
+    /// Byte range of the body in the original module, if it was
+    /// parsed from there.
+    pub orig_range: Option<Range<u32>>,
+    /// `(new, orig)` pairs, one per compiled instruction that came
+    /// from an original operator, sorted by new offset.
+    pub new_to_orig: Vec<(u32, u32)>,
+    /// `(orig, new)` pairs, sorted by original offset. An original
+    /// operator maps to the first instruction compiled from it.
+    pub orig_to_new: Vec<(u32, u32)>,
+}
+
+/// Offsets for every function body with code in the compiled
+/// module, in code-section order.
+#[derive(Clone, Debug, Default)]
+pub struct OffsetMap {
+    pub funcs: Vec<FuncOffsetMap>,
+}
+
+impl FuncOffsetMap {
+    pub(crate) fn new(
+        func: Func,
+        new_range: Range<u32>,
+        orig_range: Option<Range<u32>>,
+        new_to_orig: Vec<(u32, u32)>,
+    ) -> FuncOffsetMap {
+        let mut orig_to_new = new_to_orig
+            .iter()
+            .map(|&(new, orig)| (orig, new))
+            .collect::<Vec<_>>();
+        orig_to_new.sort_unstable();
+        orig_to_new.dedup_by_key(|&mut (orig, _)| orig);
+        FuncOffsetMap {
+            func,
+            new_range,
+            orig_range,
+            new_to_orig,
+            orig_to_new,
+        }
+    }
+
+    /// The original offset of the operator that the compiled
+    /// instruction covering `new` came from.
+    pub fn orig_offset(&self, new: u32) -> Option<u32> {
+        lookup(&self.new_to_orig[..], new)
+    }
+
+    /// The compiled offset of the first instruction compiled from the
+    /// original operator covering `orig`.
+    pub fn new_offset(&self, orig: u32) -> Option<u32> {
+        lookup(&self.orig_to_new[..], orig)
+    }
+}
+
+impl OffsetMap {
+    /// Find the function whose compiled body contains `new`.
+    pub fn func_at_new(&self, new: u32) -> Option<&FuncOffsetMap> {
+        let idx = self.funcs.partition_point(|f| f.new_range.end <= new);
+        self.funcs.get(idx).filter(|f| f.new_range.contains(&new))
+    }
+
+    /// Find the function whose original body contains `orig`.
+    pub fn func_at_orig(&self, orig: u32) -> Option<&FuncOffsetMap> {
+        self.funcs.iter().find(|f| {
+            f.orig_range
+                .as_ref()
+                .map(|range| range.contains(&orig))
+                .unwrap_or(false)
+        })
+    }
+
+    pub fn orig_offset(&self, new: u32) -> Option<u32> {
+        self.func_at_new(new)?.orig_offset(new)
+    }
+
+    pub fn new_offset(&self, orig: u32) -> Option<u32> {
+        self.func_at_orig(orig)?.new_offset(orig)
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"funcs\":[");
+        for (i, f) in self.funcs.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(
+                out,
+                "{{\"func\":{},\"new_range\":[{},{}],\"orig_range\":",
+                f.func.index(),
+                f.new_range.start,
+                f.new_range.end
+            )
+            .unwrap();
+            match &f.orig_range {
+                Some(range) => write!(out, "[{},{}]", range.start, range.end).unwrap(),
+                None => out.push_str("null"),
+            }
+            out.push_str(",\"new_to_orig\":");
+            pairs_json(&mut out, &f.new_to_orig[..]);
+            out.push_str(",\"orig_to_new\":");
+            pairs_json(&mut out, &f.orig_to_new[..]);
+            out.push('}');
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+/// The value for the last key at or before `key`.
+fn lookup(pairs: &[(u32, u32)], key: u32) -> Option<u32> {
+    let idx = pairs.partition_point(|&(k, _)| k <= key);
+    idx.checked_sub(1).map(|idx| pairs[idx].1)
+}
+
+fn pairs_json(out: &mut String, pairs: &[(u32, u32)]) {
+    out.push('[');
+    for (i, &(a, b)) in pairs.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write!(out, "[{},{}]", a, b).unwrap();
+    }
+    out.push(']');
+}
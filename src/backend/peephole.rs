@@ -0,0 +1,160 @@
+//! Instruction buffer with peephole rewrites, applied as each
+//! instruction is emitted.
+
+use crate::ir::{Block, SourceLoc, Value};
+use std::borrow::Cow;
+use wasm_encoder::{Encode, Instruction};
+
+/// Where an emitted instruction came from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InstLoc {
+    pub source: SourceLoc,
+    /// Offset of the originating operator in the original module.
+    pub orig_offset: Option<u32>,
+    /// The IR value being lowered.
+    pub value: Option<Value>,
+    /// For a `block` or `loop`, the IR block that branches to its
+    /// label go to.
+    pub label: Option<Block>,
+}
+
+#[derive(Debug, Default)]
+pub struct InstBuffer {
+    pub insts: Vec<Instruction<'static>>,
+    /// The origin of each instruction in `insts`.
+    pub locs: Vec<InstLoc>,
+    /// The origin given to instructions as they are emitted.
+    pub loc: InstLoc,
+}
+
+impl InstBuffer {
+    /// Set the current origin, returning the previous one.
+    pub fn set_loc(&mut self, loc: InstLoc) -> InstLoc {
+        std::mem::replace(&mut self.loc, loc)
+    }
+
+    pub fn instruction(&mut self, inst: &Instruction<'static>) -> &mut Self {
+        match *inst {
+            // `local.set x; local.get x` => `local.tee x`.
+            Instruction::LocalGet(local) => {
+                if let Some(&Instruction::LocalSet(set)) = self.insts.last() {
+                    if set == local {
+                        *self.insts.last_mut().unwrap() = Instruction::LocalTee(local);
+                        return self;
+                    }
+                }
+            }
+            Instruction::LocalSet(local) => match self.insts.last() {
+                // `local.get x; local.set x` is a no-op.
+                Some(&Instruction::LocalGet(get)) if get == local => {
+                    self.insts.pop();
+                    self.locs.pop();
+                    return self;
+                }
+                // `local.tee x; local.set x` => `local.set x`.
+                Some(&Instruction::LocalTee(tee)) if tee == local => {
+                    *self.insts.last_mut().unwrap() = Instruction::LocalSet(local);
+                    return self;
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+        self.insts.push(inst.clone());
+        self.locs.push(self.loc);
+        self
+    }
+
+    /// Emit a `block`, `loop` or `if`, whose label the branches to
+    /// `label` go to.
+    pub fn scope(&mut self, inst: &Instruction<'static>, label: Block) {
+        self.insts.push(inst.clone());
+        self.locs.push(InstLoc {
+            label: Some(label),
+            ..self.loc
+        });
+    }
+
+    /// Does the last instruction transfer control unconditionally, so
+    /// that the code after it is unreachable?
+    pub fn ends_unconditionally(&self) -> bool {
+        matches!(
+            self.insts.last(),
+            Some(Instruction::Br(_))
+                | Some(Instruction::BrTable(..))
+                | Some(Instruction::Return)
+                | Some(Instruction::Unreachable)
+        )
+    }
+
+    /// Emit the instructions of `other` in order, each with its own
+    /// origin, applying the peephole rewrites as they go.
+    pub fn append(&mut self, other: InstBuffer) {
+        let loc = self.loc;
+        for (inst, inst_loc) in other.insts.iter().zip(other.locs) {
+            self.loc = inst_loc;
+            self.instruction(inst);
+        }
+        self.loc = loc;
+    }
+
+    /// The encoded size of the instructions, in bytes.
+    pub fn byte_len(&self) -> usize {
+        let mut bytes = vec![];
+        for inst in &self.insts {
+            inst.encode(&mut bytes);
+        }
+        bytes.len()
+    }
+
+    /// Close the untyped `block` at `insts[start]`. A trailing branch
+    /// to the block's own end is dropped; then, if nothing else
+    /// branches there, the `block` is removed and branches out of it
+    /// are renumbered. Otherwise, an `end` is emitted.
+    pub fn end_block(&mut self, start: usize) {
+        debug_assert!(matches!(self.insts[start], Instruction::Block(_)));
+        if matches!(self.insts.last(), Some(Instruction::Br(0))) {
+            self.insts.pop();
+            self.locs.pop();
+        }
+
+        let mut depth = 0;
+        let mut targeted = false;
+        for inst in &self.insts[start + 1..] {
+            match inst {
+                Instruction::Block(_) | Instruction::Loop(_) | Instruction::If(_) => depth += 1,
+                Instruction::End => depth -= 1,
+                Instruction::Br(label) | Instruction::BrIf(label) => {
+                    targeted |= *label == depth;
+                }
+                Instruction::BrTable(labels, default) => {
+                    targeted |= *default == depth || labels.contains(&depth);
+                }
+                _ => {}
+            }
+        }
+        if targeted {
+            self.instruction(&Instruction::End);
+            return;
+        }
+
+        self.insts.remove(start);
+        self.locs.remove(start);
+        let renumber = |label: u32, depth: u32| if label > depth { label - 1 } else { label };
+        let mut depth = 0;
+        for inst in &mut self.insts[start..] {
+            match inst {
+                Instruction::Block(_) | Instruction::Loop(_) | Instruction::If(_) => depth += 1,
+                Instruction::End => depth -= 1,
+                Instruction::Br(label) | Instruction::BrIf(label) => {
+                    *label = renumber(*label, depth);
+                }
+                Instruction::BrTable(labels, default) => {
+                    *labels = Cow::Owned(labels.iter().map(|&l| renumber(l, depth)).collect());
+                    *default = renumber(*default, depth);
+                }
+                _ => {}
+            }
+        }
+    }
+}
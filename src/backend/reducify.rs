@@ -0,0 +1,446 @@
+//! Reducification: rewriting irreducible control flow into a form
+//! that stackify can handle.
+//!
+//! Stackify requires every backward edge to target a block that
+//! dominates its source, i.e. every cycle in the CFG has a single
+//! entry block (its loop header). Wasm input always has this
+//! property, but transforms on the IR need not preserve it.
+//!
+//! We find cycles (strongly-connected regions) with more than one
+//! entry and fix each in one of two ways:
+//!
+//! - Node splitting: the region is duplicated once per extra entry,
+//!   and edges from outside the region into that entry are redirected
+//!   to the duplicate, so each copy has a single entry. This keeps
+//!   control flow direct but grows code with the size of the region.
+//!
+//! - A dispatcher: a new block takes a label and the union of the
+//!   entries' blockparams, and switches to the right entry. Every
+//!   edge into an entry goes through the dispatcher instead, which
+//!   becomes the single loop header. This grows code only with the
+//!   number of entry edges.
+//!
+//! Splitting is used for small regions, and the dispatcher otherwise.
+//!
+//! Both rewrites work on maximal SSA (see `passes::maxssa`), where no
+//! value is used outside its defining block except through
+//! blockparams, so duplicating or re-routing blocks never breaks
+//! dominance of defs over uses.
+
+use crate::cfg::{postorder, CFGInfo};
+use crate::entity::{EntityRef, PerEntity};
+use crate::ir::{Block, BlockTarget, FunctionBody, Terminator, Type, Value, ValueDef};
+use crate::Operator;
+use fxhash::FxHashMap;
+use std::borrow::Cow;
+
+/// Largest number of instructions that splitting one region may
+/// duplicate.
+const MAX_SPLIT_INSTS: usize = 64;
+/// Largest number of instructions that splitting may duplicate over
+/// the whole function.
+const MAX_TOTAL_SPLIT_INSTS: usize = 256;
+
+/// A cycle in the CFG with more than one entry.
+#[derive(Clone, Debug)]
+struct Region {
+    blocks: Vec<Block>,
+    /// Blocks in the region with a predecessor outside of it, in RPO.
+    entries: Vec<Block>,
+}
+
+/// Does every backward edge (in RPO) target a block that dominates
+/// its source?
+pub fn is_reducible(body: &FunctionBody, cfg: &CFGInfo) -> bool {
+    cfg.rpo.entries().all(|(block_rpo, &block)| {
+        body.blocks[block]
+            .succs
+            .iter()
+            .all(|&succ| cfg.rpo_pos[succ].unwrap() > block_rpo || cfg.dominates(succ, block))
+    })
+}
+
+/// Return a version of `body` with reducible control flow, copying
+/// only if `body` is irreducible.
+pub fn reducify(body: &FunctionBody) -> Cow<'_, FunctionBody> {
+    let cfg = CFGInfo::new(body);
+    if is_reducible(body, &cfg) {
+        return Cow::Borrowed(body);
+    }
+
+    let mut body = body.clone();
+    body.convert_to_max_ssa(None);
+
+    let mut split_budget = MAX_TOTAL_SPLIT_INSTS;
+    while let Some(region) = find_irreducible_region(&body) {
+        let region_insts = region
+            .blocks
+            .iter()
+            .map(|&block| body.blocks[block].insts.len() + 1)
+            .sum::<usize>();
+        let split_cost = region_insts * (region.entries.len() - 1);
+        // The function's entry block cannot be reached through a
+        // dispatcher, so a region containing it is always split.
+        let can_dispatch =
+            !region.entries.contains(&body.entry) && dispatch_slots(&body, &region).is_some();
+        log::debug!(
+            "reducify: region {:?} with entries {:?}: split cost {}, can dispatch {}",
+            region.blocks,
+            region.entries,
+            split_cost,
+            can_dispatch
+        );
+        if !can_dispatch || split_cost <= std::cmp::min(MAX_SPLIT_INSTS, split_budget) {
+            split_budget = split_budget.saturating_sub(split_cost);
+            split_region(&mut body, &region);
+        } else {
+            add_dispatcher(&mut body, &region);
+        }
+        body.recompute_edges();
+    }
+
+    Cow::Owned(body)
+}
+
+/// Compute the strongly-connected components of the subgraph on the
+/// blocks for which `member` is true, with Tarjan's algorithm.
+fn sccs<F: Fn(Block) -> bool>(body: &FunctionBody, blocks: &[Block], member: F) -> Vec<Vec<Block>> {
+    let mut index: PerEntity<Block, Option<usize>> = PerEntity::default();
+    let mut lowlink: PerEntity<Block, usize> = PerEntity::default();
+    let mut on_stack: PerEntity<Block, bool> = PerEntity::default();
+    let mut stack = vec![];
+    let mut next_index = 0;
+    let mut result = vec![];
+
+    for &root in blocks {
+        if index[root].is_some() {
+            continue;
+        }
+        // Explicit DFS stack of (block, next successor index).
+        let mut dfs = vec![(root, 0)];
+        index[root] = Some(next_index);
+        lowlink[root] = next_index;
+        next_index += 1;
+        stack.push(root);
+        on_stack[root] = true;
+
+        while let Some(&(block, next_succ)) = dfs.last() {
+            let succs = &body.blocks[block].succs;
+            if next_succ < succs.len() {
+                let succ = succs[next_succ];
+                dfs.last_mut().unwrap().1 += 1;
+                if !member(succ) {
+                    continue;
+                }
+                match index[succ] {
+                    None => {
+                        index[succ] = Some(next_index);
+                        lowlink[succ] = next_index;
+                        next_index += 1;
+                        stack.push(succ);
+                        on_stack[succ] = true;
+                        dfs.push((succ, 0));
+                    }
+                    Some(succ_index) if on_stack[succ] => {
+                        lowlink[block] = std::cmp::min(lowlink[block], succ_index);
+                    }
+                    _ => {}
+                }
+            } else {
+                dfs.pop();
+                if let Some(&(parent, _)) = dfs.last() {
+                    lowlink[parent] = std::cmp::min(lowlink[parent], lowlink[block]);
+                }
+                if Some(lowlink[block]) == index[block] {
+                    let mut scc = vec![];
+                    loop {
+                        let scc_block = stack.pop().unwrap();
+                        on_stack[scc_block] = false;
+                        scc.push(scc_block);
+                        if scc_block == block {
+                            break;
+                        }
+                    }
+                    result.push(scc);
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Find a cycle with more than one entry, looking inside
+/// single-entry cycles (with their header removed) for nested ones.
+fn find_irreducible_region(body: &FunctionBody) -> Option<Region> {
+    let mut rpo = postorder::calculate(body.entry, |block| &body.blocks[block].succs[..]);
+    rpo.reverse();
+    let mut rpo_pos: PerEntity<Block, Option<usize>> = PerEntity::default();
+    for (i, &block) in rpo.iter().enumerate() {
+        rpo_pos[block] = Some(i);
+    }
+
+    let mut in_region: PerEntity<Block, bool> = PerEntity::default();
+    let mut worklist = vec![rpo];
+    while let Some(blocks) = worklist.pop() {
+        for &block in &blocks {
+            in_region[block] = true;
+        }
+        let components = sccs(body, &blocks[..], |block| in_region[block]);
+        for &block in &blocks {
+            in_region[block] = false;
+        }
+
+        for mut scc in components {
+            let is_cycle = scc.len() > 1 || body.blocks[scc[0]].succs.contains(&scc[0]);
+            if !is_cycle {
+                continue;
+            }
+            scc.sort_by_key(|&block| rpo_pos[block]);
+            for &block in &scc {
+                in_region[block] = true;
+            }
+            let entries = scc
+                .iter()
+                .copied()
+                .filter(|&block| {
+                    block == body.entry
+                        || body.blocks[block]
+                            .preds
+                            .iter()
+                            .any(|&pred| rpo_pos[pred].is_some() && !in_region[pred])
+                })
+                .collect::<Vec<_>>();
+            for &block in &scc {
+                in_region[block] = false;
+            }
+
+            if entries.len() > 1 {
+                return Some(Region {
+                    blocks: scc,
+                    entries,
+                });
+            }
+            let header = entries[0];
+            scc.retain(|&block| block != header);
+            worklist.push(scc);
+        }
+    }
+    None
+}
+
+/// Duplicate the region once for each entry but the first, and send
+/// edges from outside the region into that entry to its copy.
+fn split_region(body: &mut FunctionBody, region: &Region) {
+    let mut in_region: PerEntity<Block, bool> = PerEntity::default();
+    for &block in &region.blocks {
+        in_region[block] = true;
+    }
+
+    for &entry in &region.entries[1..] {
+        let mut block_map: FxHashMap<Block, Block> = FxHashMap::default();
+        for &block in &region.blocks {
+            let new_block = body.add_block();
+            body.blocks[new_block].desc = body.blocks[block].desc.clone();
+            body.label_names[new_block] = body.label_names[block].clone();
+            block_map.insert(block, new_block);
+        }
+
+        let mut value_map: FxHashMap<Value, Value> = FxHashMap::default();
+        for &block in &region.blocks {
+            let new_block = block_map[&block];
+            for (ty, param) in body.blocks[block].params.clone() {
+                let new_param = body.add_blockparam(new_block, ty);
+                body.value_locals[new_param] = body.value_locals[param];
+                value_map.insert(param, new_param);
+            }
+            for inst in body.blocks[block].insts.clone() {
+                let map = |body: &FunctionBody, value: Value| {
+                    let value = body.resolve_alias(value);
+                    value_map.get(&value).copied().unwrap_or(value)
+                };
+                let def = match body.values[inst].clone() {
+                    ValueDef::Operator(op, args, tys) => {
+                        let args = body.arg_pool[args]
+                            .iter()
+                            .map(|&arg| map(body, arg))
+                            .collect::<Vec<_>>();
+                        let args = body.arg_pool.from_iter(args.into_iter());
+                        ValueDef::Operator(op, args, tys)
+                    }
+                    ValueDef::Trace(id, args) => {
+                        let args = body.arg_pool[args]
+                            .iter()
+                            .map(|&arg| map(body, arg))
+                            .collect::<Vec<_>>();
+                        let args = body.arg_pool.from_iter(args.into_iter());
+                        ValueDef::Trace(id, args)
+                    }
+                    ValueDef::PickOutput(from, index, ty) => {
+                        ValueDef::PickOutput(map(body, from), index, ty)
+                    }
+                    def => def,
+                };
+                let new_inst = body.add_value(def);
+                body.append_to_block(new_block, new_inst);
+                body.source_locs[new_inst] = body.source_locs[inst];
+                body.orig_offsets[new_inst] = body.orig_offsets[inst];
+                body.value_locals[new_inst] = body.value_locals[inst];
+                value_map.insert(inst, new_inst);
+            }
+
+            let mut terminator = body.blocks[block].terminator.clone();
+            terminator.update_uses(|value| {
+                let resolved = body.resolve_alias(*value);
+                if let Some(&new_value) = value_map.get(&resolved) {
+                    *value = new_value;
+                }
+            });
+            terminator.update_targets(|target| {
+                if let Some(&new_block) = block_map.get(&target.block) {
+                    target.block = new_block;
+                }
+            });
+            body.blocks[new_block].terminator = terminator;
+        }
+
+        let mut preds = body.blocks[entry].preds.clone();
+        preds.sort_unstable();
+        preds.dedup();
+        for pred in preds {
+            if in_region[pred] {
+                continue;
+            }
+            body.blocks[pred].terminator.update_targets(|target| {
+                if target.block == entry {
+                    target.block = block_map[&entry];
+                }
+            });
+        }
+    }
+}
+
+/// Blockparam slots of a dispatcher for the region: for each entry,
+/// the dispatcher param (after the label) that carries each of its
+/// params. Entries share slots of the same type. `None` if some slot
+/// would need a filler value of a type that has no constant.
+fn dispatch_slots(body: &FunctionBody, region: &Region) -> Option<(Vec<Type>, Vec<Vec<usize>>)> {
+    let mut slot_tys: Vec<Type> = vec![];
+    let mut entry_slots = vec![];
+    for &entry in &region.entries {
+        let mut used = vec![false; slot_tys.len()];
+        let mut slots = vec![];
+        for &(ty, _) in &body.blocks[entry].params {
+            let slot = match (0..slot_tys.len()).find(|&i| !used[i] && slot_tys[i] == ty) {
+                Some(slot) => slot,
+                None => {
+                    slot_tys.push(ty);
+                    used.push(false);
+                    slot_tys.len() - 1
+                }
+            };
+            used[slot] = true;
+            slots.push(slot);
+        }
+        entry_slots.push(slots);
+    }
+
+    let all_used = |slot: usize| entry_slots.iter().all(|slots| slots.contains(&slot));
+    let fillable =
+        (0..slot_tys.len()).all(|slot| zero_const(slot_tys[slot]).is_some() || all_used(slot));
+    if fillable {
+        Some((slot_tys, entry_slots))
+    } else {
+        None
+    }
+}
+
+fn zero_const(ty: Type) -> Option<Operator> {
+    match ty {
+        Type::I32 => Some(Operator::I32Const { value: 0 }),
+        Type::I64 => Some(Operator::I64Const { value: 0 }),
+        Type::F32 => Some(Operator::F32Const { value: 0 }),
+        Type::F64 => Some(Operator::F64Const { value: 0 }),
+        _ => None,
+    }
+}
+
+/// Route every edge into an entry of the region through a new
+/// dispatcher block that switches on a label.
+fn add_dispatcher(body: &mut FunctionBody, region: &Region) {
+    let (slot_tys, entry_slots) = dispatch_slots(body, region).unwrap();
+
+    let dispatch = body.add_block();
+    body.blocks[dispatch].desc = "reducify dispatch".to_owned();
+    let label = body.add_blockparam(dispatch, Type::I32);
+    let slots = slot_tys
+        .iter()
+        .map(|&ty| body.add_blockparam(dispatch, ty))
+        .collect::<Vec<_>>();
+
+    let mut entry_index: FxHashMap<Block, usize> = FxHashMap::default();
+    for (i, &entry) in region.entries.iter().enumerate() {
+        entry_index.insert(entry, i);
+    }
+
+    let mut preds = region
+        .entries
+        .iter()
+        .flat_map(|&entry| body.blocks[entry].preds.iter().copied())
+        .collect::<Vec<_>>();
+    preds.sort_unstable();
+    preds.dedup();
+    for pred in preds {
+        // Take the terminator out so we can add constants to the
+        // block while rewriting its targets.
+        let mut terminator = std::mem::take(&mut body.blocks[pred].terminator);
+        terminator.update_targets(|target| {
+            let i = match entry_index.get(&target.block) {
+                Some(&i) => i,
+                None => return,
+            };
+            let add_const = |body: &mut FunctionBody, op: Operator, ty: Type| {
+                let tys = body.single_type_list(ty);
+                let value = body.add_value(ValueDef::Operator(op, Default::default(), tys));
+                body.append_to_block(pred, value);
+                value
+            };
+            let mut args = vec![add_const(
+                body,
+                Operator::I32Const { value: i as u32 },
+                Type::I32,
+            )];
+            let mut slot_args = vec![Value::invalid(); slot_tys.len()];
+            for (&slot, &arg) in entry_slots[i].iter().zip(target.args.iter()) {
+                slot_args[slot] = arg;
+            }
+            for (slot, arg) in slot_args.iter_mut().enumerate() {
+                if arg.is_invalid() {
+                    let ty = slot_tys[slot];
+                    *arg = add_const(body, zero_const(ty).unwrap(), ty);
+                }
+            }
+            args.extend(slot_args);
+            *target = BlockTarget {
+                block: dispatch,
+                args,
+            };
+        });
+        body.blocks[pred].terminator = terminator;
+    }
+
+    let mut targets = region
+        .entries
+        .iter()
+        .zip(entry_slots.iter())
+        .map(|(&entry, slot_indices)| BlockTarget {
+            block: entry,
+            args: slot_indices.iter().map(|&slot| slots[slot]).collect(),
+        })
+        .collect::<Vec<_>>();
+    let default = targets.pop().unwrap();
+    body.blocks[dispatch].terminator = Terminator::Select {
+        value: label,
+        targets,
+        default,
+    };
+}
@@ -12,7 +12,7 @@
 use crate::cfg::CFGInfo;
 use crate::entity::EntityRef;
 use crate::ir::{Block, BlockTarget, FunctionBody, Terminator, Type, Value};
-use std::collections::HashSet;
+use fxhash::FxHashSet as HashSet;
 use std::convert::TryFrom;
 
 #[derive(Clone, Debug)]
@@ -74,6 +74,7 @@ impl WasmLabel {
 pub struct Context<'a, 'b> {
     body: &'a FunctionBody,
     cfg: &'b CFGInfo,
+    /// Membership sets, never iterated.
     merge_nodes: HashSet<Block>,
     loop_headers: HashSet<Block>,
     ctrl_stack: Vec<CtrlEntry>,
@@ -134,9 +135,9 @@ impl<'a, 'b> Context<'a, 'b> {
         body: &FunctionBody,
         cfg: &CFGInfo,
     ) -> anyhow::Result<(HashSet<Block>, HashSet<Block>)> {
-        let mut loop_headers = HashSet::new();
-        let mut branched_once = HashSet::new();
-        let mut merge_nodes = HashSet::new();
+        let mut loop_headers = HashSet::default();
+        let mut branched_once = HashSet::default();
+        let mut merge_nodes = HashSet::default();
 
         for (block_rpo, &block) in cfg.rpo.entries() {
             for &succ in &body.blocks[block].succs {
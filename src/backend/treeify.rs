@@ -1,8 +1,10 @@
 //! Treeification: placing some values "under" others if only used
 //! once, to generate more AST-like Wasm code.
 
+use super::OptimizeFor;
 use crate::entity::EntityRef;
 use crate::ir::{FunctionBody, Value, ValueDef};
+use crate::op_traits::op_rematerialize;
 use crate::Operator;
 use fxhash::{FxHashMap as HashMap, FxHashSet as HashSet};
 use std::convert::TryFrom;
@@ -11,6 +13,8 @@ use std::convert::TryFrom;
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct ValueArg(Value, u16);
 
+/// The tables are hashed, and only ever looked up: nothing iterates
+/// them, so their order cannot affect the compiled code.
 #[derive(Clone, Debug)]
 pub struct Trees {
     /// Is a value placed "under" the given arg slot of the given
@@ -21,21 +25,30 @@ pub struct Trees {
     pub owned: HashMap<ValueArg, Value>,
     /// Values that are regenerated every time they are used.
     pub remat: HashSet<Value>,
+    /// Values placed under the terminator of their block, their only
+    /// use.
+    pub term_owned: HashSet<Value>,
 }
 
-fn is_remat(op: &Operator) -> bool {
+fn is_remat(op: &Operator, optimize_for: OptimizeFor) -> bool {
     // Only ops with no args can be always-rematerialized.
-    match op {
-        Operator::I32Const { .. }
-        | Operator::I64Const { .. }
-        | Operator::F32Const { .. }
-        | Operator::F64Const { .. } => true,
-        _ => false,
+    if !op_rematerialize(op) {
+        return false;
+    }
+    match optimize_for {
+        OptimizeFor::Speed => true,
+        // A constant wider than a `local.get` is cheaper to keep in
+        // a local if it is used more than once.
+        OptimizeFor::Size => match *op {
+            Operator::I32Const { value } => (-64..64).contains(&(value as i32)),
+            Operator::I64Const { value } => (-64..64).contains(&(value as i64)),
+            _ => false,
+        },
     }
 }
 
 impl Trees {
-    pub fn compute(body: &FunctionBody) -> Trees {
+    pub fn compute(body: &FunctionBody, optimize_for: OptimizeFor) -> Trees {
         let mut owner = HashMap::default();
         let mut owned = HashMap::default();
         let mut remat = HashSet::default();
@@ -51,7 +64,7 @@ impl Trees {
                     }
                     // If this is an always-rematerialized operator,
                     // mark it as such and continue.
-                    if is_remat(&op) {
+                    if is_remat(&op, optimize_for) {
                         remat.insert(value);
                         continue;
                     }
@@ -87,22 +100,53 @@ impl Trees {
                 | &ValueDef::None => {}
             }
         }
+        let mut term_uses: HashMap<Value, usize> = HashMap::default();
         for block in body.blocks.values() {
             block.terminator.visit_uses(|u| {
                 let u = body.resolve_alias(u);
                 if let Some(old_owner) = owner.remove(&u) {
                     owned.remove(&old_owner);
+                    multi_use.insert(u);
                 }
+                *term_uses.entry(u).or_insert(0) += 1;
             });
         }
 
+        // When optimizing for size, also place a value under its
+        // block's terminator if that is its only use: it is then
+        // computed just before the branch rather than set and got
+        // from a local.
+        let mut term_owned = HashSet::default();
+        if optimize_for == OptimizeFor::Size {
+            for (block, block_def) in body.blocks.entries() {
+                block_def.terminator.visit_uses(|u| {
+                    let u = body.resolve_alias(u);
+                    if term_uses[&u] == 1
+                        && !multi_use.contains(&u)
+                        && !remat.contains(&u)
+                        && body.value_blocks[u] == block
+                        && Self::is_movable(body, u)
+                    {
+                        term_owned.insert(u);
+                    }
+                });
+            }
+        }
+
         Trees {
             owner,
             owned,
             remat,
+            term_owned,
         }
     }
 
+    /// Is this value computed at its use rather than at its
+    /// definition?
+    pub fn is_owned(&self, value: &Value) -> bool {
+        self.owner.contains_key(value) || self.term_owned.contains(value)
+    }
+
     fn is_single_output_op(body: &FunctionBody, value: Value) -> Option<Operator> {
         match &body.values[value] {
             &ValueDef::Operator(op, _, ref tys) if tys.len() == 1 => Some(op),
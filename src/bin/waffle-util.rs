@@ -2,10 +2,21 @@
 
 use anyhow::Result;
 use log::debug;
+use regex::Regex;
 use std::path::PathBuf;
 use structopt::StructOpt;
+use waffle::analysis::CallGraph;
+use waffle::passes::asyncify::AsyncifyOptions;
+use waffle::passes::fuel::{FuelGlobal, FuelOptions, OutOfFuel};
+use waffle::passes::lower_features::{Features, LowerOptions};
+use waffle::passes::memtrace::MemTraceOptions;
+use waffle::passes::profile::ProfileOptions;
+use waffle::passes::stack_limit::{StackLimit, StackLimitOptions};
+use waffle::passes::PassManager;
 use waffle::InterpContext;
-use waffle::{entity::EntityRef, FrontendOptions, Func, Module};
+use waffle::{
+    entity::EntityRef, CompileOptions, DebugInfoMode, FrontendOptions, Func, Module, OptimizeFor,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "waffle-util", about = "WAFFLE utility.")]
@@ -26,9 +37,107 @@ struct Options {
     )]
     debug_info: bool,
 
+    #[structopt(
+        help = "Remove unreachable functions, globals, imports, and signatures",
+        long = "gc"
+    )]
+    gc: bool,
+
+    #[structopt(
+        help = "Recompile every function body, even if unchanged",
+        long = "recompile"
+    )]
+    recompile: bool,
+
     #[structopt(help = "Transform to maximal SSA", long = "max-ssa")]
     max_ssa: bool,
 
+    #[structopt(
+        help = "Run a comma-separated pipeline of named passes (e.g. \"gvn,remove-phis,empty-blocks\")",
+        short = "p",
+        long = "passes"
+    )]
+    passes: Option<String>,
+
+    #[structopt(
+        help = "Validate every function body after each pass",
+        long = "validate-passes"
+    )]
+    validate_passes: bool,
+
+    #[structopt(
+        help = "Print per-pass timing and IR-size statistics",
+        long = "pass-stats"
+    )]
+    pass_stats: bool,
+
+    #[structopt(
+        help = "Add profiling hooks on function entry and exit, and write the id-to-name map to this file",
+        long = "profile"
+    )]
+    profile: Option<PathBuf>,
+
+    #[structopt(
+        help = "With --profile, also add a hook at every call site",
+        long = "profile-calls"
+    )]
+    profile_calls: bool,
+
+    #[structopt(
+        help = "Call imported hooks on every memory access and memory.grow",
+        long = "trace-memory"
+    )]
+    trace_memory: bool,
+
+    #[structopt(
+        help = "With --trace-memory, only instrument functions whose names match this regex",
+        long = "trace-memory-funcs"
+    )]
+    trace_memory_funcs: Option<Regex>,
+
+    #[structopt(
+        help = "Meter execution with an exported `fuel` global holding this much initial fuel",
+        long = "fuel"
+    )]
+    fuel: Option<i64>,
+
+    #[structopt(
+        help = "With --fuel, call an imported handler instead of trapping when fuel runs out",
+        long = "fuel-handler"
+    )]
+    fuel_handler: bool,
+
+    #[structopt(
+        help = "Trap when the estimated stack depth, in bytes, would exceed this limit",
+        long = "stack-limit"
+    )]
+    stack_limit: Option<u32>,
+
+    #[structopt(
+        help = "Allow execution to unwind and rewind across async imports, with Binaryen's Asyncify API",
+        long = "asyncify"
+    )]
+    asyncify: bool,
+
+    #[structopt(
+        help = "With --asyncify, an import that can unwind, as `module.name` (default: every import)",
+        long = "asyncify-import",
+        number_of_values = 1
+    )]
+    asyncify_imports: Vec<String>,
+
+    #[structopt(
+        help = "Lower features the target lacks, given a comma-separated list of those it supports (e.g. \"mvp,sign-ext\"), and check that no others remain",
+        long = "target-features"
+    )]
+    target_features: Option<Features>,
+
+    #[structopt(
+        help = "With --target-features lacking multivalue, the address of an otherwise unused area of memory 0 through which to return all results after the first",
+        long = "return-area"
+    )]
+    return_area: Option<u32>,
+
     #[structopt(subcommand)]
     command: Command,
 }
@@ -53,6 +162,42 @@ enum Command {
         input: PathBuf,
         #[structopt(help = "Wasm file to produce", short = "o")]
         output: PathBuf,
+        #[structopt(
+            help = "Emit a DWARF line table for the output code",
+            long = "debug-line"
+        )]
+        debug_line: bool,
+        #[structopt(
+            help = "Write a source map for the output code to this file",
+            long = "source-map"
+        )]
+        source_map: Option<PathBuf>,
+        #[structopt(
+            help = "Write a map between input and output instruction offsets to this JSON file",
+            long = "offset-map"
+        )]
+        offset_map: Option<PathBuf>,
+        #[structopt(help = "Validate the output", long = "validate")]
+        validate: bool,
+        #[structopt(
+            help = "Optimize the output for \"speed\" or \"size\"",
+            long = "optimize-for",
+            default_value = "speed"
+        )]
+        optimize_for: OptimizeFor,
+        #[structopt(
+            help = "Print each function's size change to stderr",
+            long = "size-report"
+        )]
+        size_report: bool,
+    },
+    #[structopt(
+        name = "callgraph",
+        about = "Print the call graph in Graphviz DOT format"
+    )]
+    CallGraph {
+        #[structopt(help = "Wasm file to parse")]
+        wasm: PathBuf,
     },
     #[structopt(name = "interp", about = "Interpret Waffle IR from Wasm")]
     Interp {
@@ -62,13 +207,93 @@ enum Command {
 }
 
 fn apply_options(opts: &Options, module: &mut Module) -> Result<()> {
+    // Run before expansion so that unreachable bodies are never parsed.
+    if opts.gc {
+        waffle::passes::gc::run(module)?;
+    }
     module.expand_all_funcs()?;
+    if opts.recompile {
+        module.per_func_body(|body| body.mark_dirty());
+    }
     if opts.basic_opts {
         module.per_func_body(|body| body.optimize());
     }
     if opts.max_ssa {
         module.per_func_body(|body| body.convert_to_max_ssa(None));
     }
+    if let Some(pipeline) = &opts.passes {
+        let mut pm = PassManager::with_builtin_passes();
+        pm.validate = opts.validate_passes;
+        pm.add_pipeline(pipeline)?;
+        pm.run(module)?;
+        if opts.pass_stats {
+            for stats in pm.stats() {
+                eprintln!("{}", stats);
+            }
+        }
+    }
+    if opts.trace_memory {
+        let options = MemTraceOptions {
+            funcs: opts.trace_memory_funcs.clone(),
+            ..MemTraceOptions::default()
+        };
+        waffle::passes::memtrace::run(module, &options)?;
+    }
+    if let Some(initial) = opts.fuel {
+        let options = FuelOptions {
+            global: FuelGlobal::New { initial },
+            out_of_fuel: if opts.fuel_handler {
+                OutOfFuel::Handler
+            } else {
+                OutOfFuel::Trap
+            },
+            ..FuelOptions::default()
+        };
+        waffle::passes::fuel::run(module, &options)?;
+    }
+    if let Some(limit) = opts.stack_limit {
+        let options = StackLimitOptions {
+            limit: StackLimit::Fixed(limit),
+            ..StackLimitOptions::default()
+        };
+        waffle::passes::stack_limit::run(module, &options)?;
+    }
+    if opts.asyncify {
+        let imports = if opts.asyncify_imports.is_empty() {
+            None
+        } else {
+            let mut imports = vec![];
+            for import in &opts.asyncify_imports {
+                match import.split_once('.') {
+                    Some((module, name)) => imports.push((module.to_owned(), name.to_owned())),
+                    None => anyhow::bail!("Expected `module.name`, got '{}'", import),
+                }
+            }
+            Some(imports)
+        };
+        let options = AsyncifyOptions {
+            imports,
+            ..AsyncifyOptions::default()
+        };
+        waffle::passes::asyncify::run(module, &options)?;
+    }
+    if let Some(path) = &opts.profile {
+        let options = ProfileOptions {
+            call_sites: opts.profile_calls,
+            ..ProfileOptions::default()
+        };
+        let map = waffle::passes::profile::run(module, &options)?;
+        std::fs::write(path, map.to_string())?;
+    }
+    // Run last, so that nothing can add post-MVP features afterward.
+    if let Some(features) = opts.target_features {
+        let options = LowerOptions {
+            features,
+            return_area: opts.return_area,
+            ..LowerOptions::default()
+        };
+        waffle::passes::lower_features::run(module, &options)?;
+    }
     Ok(())
 }
 
@@ -105,13 +330,61 @@ fn main() -> Result<()> {
                     .display_verbose("", Some(&module))
             );
         }
-        Command::RoundTrip { input, output } => {
+        Command::RoundTrip {
+            input,
+            output,
+            debug_line,
+            source_map,
+            offset_map,
+            validate,
+            optimize_for,
+            size_report,
+        } => {
             let bytes = std::fs::read(input)?;
             debug!("Loaded {} bytes of Wasm data", bytes.len());
             let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
             apply_options(&opts, &mut module)?;
-            let produced = module.to_wasm_bytes()?;
-            std::fs::write(output, &produced[..])?;
+            let mut compile_options = CompileOptions::default();
+            if let Some(path) = source_map {
+                // Refer to the map relative to the module.
+                let url = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                compile_options.debug_info = DebugInfoMode::SourceMap { url };
+            } else if *debug_line {
+                compile_options.debug_info = DebugInfoMode::DwarfLine;
+            }
+            compile_options.offset_map = offset_map.is_some();
+            compile_options.validate = *validate;
+            compile_options.optimize_for = *optimize_for;
+            let produced = module.compile(&compile_options)?;
+            std::fs::write(output, &produced.wasm[..])?;
+            if let (Some(path), Some(map)) = (source_map, produced.source_map) {
+                std::fs::write(path, map)?;
+            }
+            if let (Some(path), Some(map)) = (offset_map, produced.offset_map) {
+                std::fs::write(path, map.to_json())?;
+            }
+            if *size_report {
+                for size in &produced.func_sizes {
+                    match (size.orig_size, size.delta()) {
+                        (Some(orig_size), Some(delta)) => eprintln!(
+                            "{}: {} -> {} bytes ({:+})",
+                            size.func, orig_size, size.new_size, delta
+                        ),
+                        _ => eprintln!("{}: {} bytes (new)", size.func, size.new_size),
+                    }
+                }
+            }
+        }
+        Command::CallGraph { wasm } => {
+            let bytes = std::fs::read(wasm)?;
+            debug!("Loaded {} bytes of Wasm data", bytes.len());
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            apply_options(&opts, &mut module)?;
+            let graph = CallGraph::new(&module)?;
+            println!("{}", graph.display_dot(&module));
         }
         Command::Interp { input } => {
             let bytes = std::fs::read(input)?;
@@ -1,5 +1,7 @@
 //! Error types.
 
+use crate::ir::{Func, Global, Type, Value};
+
 #[derive(Clone, Debug)]
 pub enum FrontendError {
     UnsupportedFeature(String),
@@ -14,3 +16,58 @@ impl std::fmt::Display for FrontendError {
 }
 
 impl std::error::Error for FrontendError {}
+
+#[derive(Clone, Debug)]
+pub enum BackendError {
+    /// A function slot has no declaration (`FuncDecl::None`).
+    MissingFunc(Func),
+    /// An imported function appears after a function with a body.
+    ImportAfterBody(Func),
+    /// A global's type has no constant initializer.
+    UnsupportedGlobalType(Global, Type),
+    /// A value with no Wasm lowering was reached while compiling. The
+    /// function is given when compiling a whole module.
+    UnsupportedValue {
+        func: Option<Func>,
+        value: Value,
+        def: String,
+    },
+    /// A body's IR is inconsistent in a way the backend cannot lower.
+    /// The function is given when compiling a whole module.
+    MalformedBody { func: Option<Func>, message: String },
+    /// The compiled module failed validation. The failing function
+    /// and the IR value whose code contains the error are given when
+    /// known.
+    Validation {
+        func: Option<Func>,
+        value: Option<Value>,
+        offset: usize,
+        message: String,
+    },
+}
+
+impl BackendError {
+    /// Attribute an error from compiling a single body to `func`.
+    pub(crate) fn in_func(self, func: Func) -> Self {
+        match self {
+            BackendError::UnsupportedValue { value, def, .. } => BackendError::UnsupportedValue {
+                func: Some(func),
+                value,
+                def,
+            },
+            BackendError::MalformedBody { message, .. } => BackendError::MalformedBody {
+                func: Some(func),
+                message,
+            },
+            other => other,
+        }
+    }
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl std::error::Error for BackendError {}
@@ -14,7 +14,8 @@ use fxhash::{FxHashMap, FxHashSet};
 use log::trace;
 use std::convert::TryFrom;
 use wasmparser::{
-    BlockType, DataKind, ExternalKind, Name, NameSectionReader, Parser, Payload, TypeRef,
+    BlockType, DataKind, ExternalKind, IndirectNameMap, Name, NameSectionReader, Parser, Payload,
+    TypeRef,
 };
 
 #[derive(Clone, Copy, Debug, Default)]
@@ -84,6 +85,10 @@ struct ExtraSections<'a> {
     debug_ranges: gimli::DebugRanges<gimli::EndianSlice<'a, gimli::LittleEndian>>,
     debug_rnglists: gimli::DebugRngLists<gimli::EndianSlice<'a, gimli::LittleEndian>>,
     code_offset: u32,
+    /// Where each data segment, by data index, was placed: its memory
+    /// and position in that memory's segments. Passive segments are
+    /// not kept.
+    data_segments: Vec<Option<(Memory, usize)>>,
 }
 
 fn handle_payload<'a>(
@@ -173,11 +178,9 @@ fn handle_payload<'a>(
         Payload::FunctionSection(reader) => {
             for sig_idx in reader {
                 let sig_idx = Signature::from(sig_idx?);
-                module.funcs.push(FuncDecl::Body(
-                    sig_idx,
-                    "".to_owned(),
-                    FunctionBody::default(),
-                ));
+                module
+                    .funcs
+                    .push(FuncDecl::Body(sig_idx, "".to_owned(), Box::default()));
             }
         }
         Payload::CodeSectionStart { range, .. } => {
@@ -221,7 +224,7 @@ fn handle_payload<'a>(
             for segment in reader {
                 let segment = segment?;
                 match &segment.kind {
-                    DataKind::Passive => {}
+                    DataKind::Passive => extra_sections.data_segments.push(None),
                     DataKind::Active {
                         memory_index,
                         offset_expr,
@@ -229,9 +232,11 @@ fn handle_payload<'a>(
                         let data = segment.data.to_vec();
                         let memory = Memory::from(*memory_index);
                         let offset = parse_init_expr(offset_expr)?.unwrap_or(0) as usize;
-                        module.memories[memory]
-                            .segments
-                            .push(MemorySegment { offset, data });
+                        let segments = &mut module.memories[memory].segments;
+                        extra_sections
+                            .data_segments
+                            .push(Some((memory, segments.len())));
+                        segments.push(MemorySegment { offset, data });
                     }
                 }
             }
@@ -247,6 +252,52 @@ fn handle_payload<'a>(
                             module.funcs[Func::new(name.index as usize)].set_name(name.name);
                         }
                     }
+                    Name::Local(names) => add_body_names(module, names, false)?,
+                    Name::Label(names) => add_body_names(module, names, true)?,
+                    Name::Type(names) => {
+                        for name in names {
+                            let name = name?;
+                            module.signature_names[Signature::new(name.index as usize)] =
+                                Some(name.name.to_owned());
+                        }
+                    }
+                    Name::Table(names) => {
+                        for name in names {
+                            let name = name?;
+                            let table = Table::new(name.index as usize);
+                            if table.index() < module.tables.len() {
+                                module.table_names[table] = Some(name.name.to_owned());
+                            }
+                        }
+                    }
+                    Name::Memory(names) => {
+                        for name in names {
+                            let name = name?;
+                            let memory = Memory::new(name.index as usize);
+                            if memory.index() < module.memories.len() {
+                                module.memory_names[memory] = Some(name.name.to_owned());
+                            }
+                        }
+                    }
+                    Name::Global(names) => {
+                        for name in names {
+                            let name = name?;
+                            let global = Global::new(name.index as usize);
+                            if global.index() < module.globals.len() {
+                                module.global_names[global] = Some(name.name.to_owned());
+                            }
+                        }
+                    }
+                    Name::Data(names) => {
+                        for name in names {
+                            let name = name?;
+                            if let Some(&Some(segment)) =
+                                extra_sections.data_segments.get(name.index as usize)
+                            {
+                                module.segment_names.insert(segment, name.name.to_owned());
+                            }
+                        }
+                    }
                     _ => {}
                 }
             }
@@ -416,12 +467,39 @@ impl<'a> DebugLocReader<'a> {
     }
 }
 
+/// Record local or label names for the original function bodies.
+/// Bodies are still lazy here, as the name section follows the code
+/// section, so the names are keyed by body offset.
+fn add_body_names(module: &mut Module, names: IndirectNameMap, labels: bool) -> Result<()> {
+    for func_names in names {
+        let func_names = func_names?;
+        let body_start = match module.funcs.get(Func::new(func_names.index as usize)) {
+            Some(FuncDecl::Lazy(_, _, body)) => body.range().start,
+            _ => continue,
+        };
+        let body_names = module.orig_body_names.entry(body_start).or_default();
+        let list = if labels {
+            &mut body_names.labels
+        } else {
+            &mut body_names.locals
+        };
+        for name in func_names.names {
+            let name = name?;
+            list.push((name.index, name.name.to_owned()));
+        }
+    }
+    Ok(())
+}
+
 pub(crate) fn parse_body<'a>(
     module: &'a Module,
     my_sig: Signature,
     body: &mut wasmparser::FunctionBody,
 ) -> Result<FunctionBody> {
-    let mut ret: FunctionBody = FunctionBody::default();
+    let mut ret = FunctionBody {
+        orig_range: Some(body.range()),
+        ..FunctionBody::default()
+    };
 
     let mut debug_locs = DebugLocReader::new(module, body.range().start as u32);
 
@@ -448,7 +526,16 @@ pub(crate) fn parse_body<'a>(
         module.signatures[my_sig]
     );
 
+    let body_names = module.orig_body_names.get(&body.range().start);
+    for (index, name) in body_names.map(|names| &names.locals[..]).unwrap_or(&[]) {
+        let local = Local::new(*index as usize);
+        if local.index() < ret.locals.len() {
+            ret.local_names[local] = Some(name.clone());
+        }
+    }
+
     let mut builder = FunctionBodyBuilder::new(module, my_sig, &mut ret);
+    builder.label_names = body_names.map(|names| &names.labels[..]).unwrap_or(&[]);
     let entry = Block::new(0);
     builder.body.entry = entry;
     builder.locals.seal_block_preds(entry, &mut builder.body);
@@ -459,6 +546,7 @@ pub(crate) fn parse_body<'a>(
         builder.body.add_blockparam(entry, arg_ty);
         let value = builder.body.blocks[entry].params.last().unwrap().1;
         trace!("defining local {} to value {}", local_idx, value);
+        builder.body.mark_value_as_local(value, local_idx);
         builder.locals.declare(local_idx, arg_ty);
         builder.locals.set(local_idx, value);
     }
@@ -473,6 +561,7 @@ pub(crate) fn parse_body<'a>(
     for item in ops.into_iter_with_offsets() {
         let (op, offset) = item?;
         let loc = debug_locs.get_loc(offset);
+        builder.cur_offset = Some(offset as u32);
         if builder.reachable {
             builder.handle_op(op, loc)?;
         } else {
@@ -480,6 +569,7 @@ pub(crate) fn parse_body<'a>(
         }
     }
 
+    builder.cur_offset = None;
     if builder.reachable {
         builder.handle_op(wasmparser::Operator::Return, SourceLoc::invalid())?;
     }
@@ -779,6 +869,12 @@ struct FunctionBodyBuilder<'a, 'b> {
     reachable: bool,
     ctrl_stack: Vec<Frame>,
     op_stack: Vec<(Type, Value)>,
+    /// Offset in the module of the operator being translated.
+    cur_offset: Option<u32>,
+    /// Label names for this body, by label index, and the index of
+    /// the next label.
+    label_names: &'b [(u32, String)],
+    next_label: u32,
 }
 
 #[derive(Clone, Debug)]
@@ -885,6 +981,25 @@ impl Frame {
 }
 
 impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
+    /// Assign `value` to a Wasm local, linking the value back to the
+    /// first local it is assigned to.
+    fn set_local(&mut self, local: Local, value: Value) {
+        if self.body.value_locals[value].is_none() {
+            self.body.mark_value_as_local(value, local);
+        }
+        self.locals.set(local, value);
+    }
+
+    /// Give the next label's name, if it has one, to `target`, the
+    /// block reached by branches to the label.
+    fn name_label(&mut self, target: Block) {
+        let label = self.next_label;
+        self.next_label += 1;
+        if let Some((_, name)) = self.label_names.iter().find(|(index, _)| *index == label) {
+            self.body.label_names[target] = Some(name.clone());
+        }
+    }
+
     fn new(module: &'b Module<'a>, my_sig: Signature, body: &'b mut FunctionBody) -> Self {
         body.blocks.push(BlockDef::default());
         let mut ret = Self {
@@ -896,6 +1011,9 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             cur_block: Block::new(0),
             reachable: true,
             locals: LocalTracker::default(),
+            cur_offset: None,
+            label_names: &[],
+            next_label: 0,
         };
 
         // Push initial implicit Block.
@@ -968,13 +1086,13 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             wasmparser::Operator::LocalSet { local_index } => {
                 let local_index = Local::from(*local_index);
                 let (_, value) = self.op_stack.pop().unwrap();
-                self.locals.set(local_index, value);
+                self.set_local(local_index, value);
             }
 
             wasmparser::Operator::LocalTee { local_index } => {
                 let local_index = Local::from(*local_index);
                 let (_ty, value) = *self.op_stack.last().unwrap();
-                self.locals.set(local_index, value);
+                self.set_local(local_index, value);
             }
 
             wasmparser::Operator::Call { .. }
@@ -1388,6 +1506,7 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
                 let (params, results) = self.block_params_and_results(*blockty);
                 let out = self.body.add_block();
                 self.add_block_params(out, &results[..]);
+                self.name_label(out);
                 let start_depth = if self.reachable {
                     self.op_stack.len() - params.len()
                 } else {
@@ -1406,6 +1525,7 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
                 let (params, results) = self.block_params_and_results(*blockty);
                 let header = self.body.add_block();
                 self.add_block_params(header, &params[..]);
+                self.name_label(header);
                 let initial_args = if self.reachable {
                     self.pop_n(params.len())
                 } else {
@@ -1434,6 +1554,7 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
                 let if_false = self.body.add_block();
                 let join = self.body.add_block();
                 self.add_block_params(join, &results[..]);
+                self.name_label(join);
                 let (cond, param_values) = if self.reachable {
                     let cond = self.pop_1();
                     let param_values = self.op_stack[self.op_stack.len() - params.len()..].to_vec();
@@ -1711,6 +1832,7 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             self.body.append_to_block(self.cur_block, value);
         }
         self.body.source_locs[value] = loc;
+        self.body.orig_offsets[value] = self.cur_offset;
 
         if n_outputs == 1 {
             let output_ty = outputs[0];
@@ -1,5 +1,10 @@
 //! Fuzzing-specific utilities.
 
+use crate::ir::{
+    Block, BlockTarget, Export, ExportKind, FuncDecl, FunctionBody, Module, SignatureData,
+    Terminator, Type, Value, ValueDef,
+};
+use crate::Operator;
 use libfuzzer_sys::arbitrary;
 
 pub fn reject(bytes: &[u8]) -> bool {
@@ -109,3 +114,137 @@ impl wasm_smith::Config for Config {
         1
     }
 }
+
+fn add_op(body: &mut FunctionBody, block: Block, op: Operator, args: &[Value], ty: Type) -> Value {
+    let args = body.arg_pool.from_iter(args.iter().copied());
+    let tys = body.single_type_list(ty);
+    let value = body.add_value(ValueDef::Operator(op, args, tys));
+    body.append_to_block(block, value);
+    value
+}
+
+/// Build a module with one exported function `f: i32 -> i32` whose
+/// body is a random CFG, which is often irreducible. Each block takes
+/// an `i32` (and sometimes an `i64`), mixes it with constants, and
+/// then returns, branches, or switches on the result. Used to fuzz
+/// the backend on control flow that Wasm input cannot produce.
+pub fn arbitrary_cfg_module(
+    u: &mut arbitrary::Unstructured<'_>,
+) -> arbitrary::Result<Module<'static>> {
+    let mut module = Module::with_orig_bytes(&[]);
+    let sig = module.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32],
+    });
+    let mut body = FunctionBody::new(&module, sig);
+    let entry = body.entry;
+    let entry_param = body.blocks[entry].params[0].1;
+
+    let n_blocks = u.int_in_range(2..=8)?;
+    let mut blocks = vec![];
+    let mut wide = vec![];
+    for _ in 0..n_blocks {
+        let block = body.add_block();
+        body.add_blockparam(block, Type::I32);
+        let is_wide: bool = u.arbitrary()?;
+        if is_wide {
+            body.add_blockparam(block, Type::I64);
+        }
+        blocks.push(block);
+        wide.push(is_wide);
+    }
+
+    let target = |body: &mut FunctionBody,
+                  u: &mut arbitrary::Unstructured<'_>,
+                  from: Block,
+                  x: Value|
+     -> arbitrary::Result<BlockTarget> {
+        let to = u.choose_index(n_blocks)?;
+        let mut args = vec![x];
+        if wide[to] {
+            args.push(add_op(body, from, Operator::I64ExtendI32U, &[x], Type::I64));
+        }
+        Ok(BlockTarget {
+            block: blocks[to],
+            args,
+        })
+    };
+
+    let start = target(&mut body, u, entry, entry_param)?;
+    body.set_terminator(entry, Terminator::Br { target: start });
+
+    for &block in &blocks {
+        let params = body.blocks[block].params.clone();
+        let mut x = params[0].1;
+        if let Some(&(_, y)) = params.get(1) {
+            let y = add_op(&mut body, block, Operator::I32WrapI64, &[y], Type::I32);
+            x = add_op(&mut body, block, Operator::I32Add, &[x, y], Type::I32);
+        }
+        let c1 = add_op(
+            &mut body,
+            block,
+            Operator::I32Const {
+                value: u.arbitrary()?,
+            },
+            &[],
+            Type::I32,
+        );
+        let c2 = add_op(
+            &mut body,
+            block,
+            Operator::I32Const {
+                value: u.arbitrary()?,
+            },
+            &[],
+            Type::I32,
+        );
+        let x = add_op(&mut body, block, Operator::I32Mul, &[x, c1], Type::I32);
+        let x = add_op(&mut body, block, Operator::I32Add, &[x, c2], Type::I32);
+
+        let terminator = match u.int_in_range(0..=3)? {
+            0 => Terminator::Return { values: vec![x] },
+            1 => Terminator::Br {
+                target: target(&mut body, u, block, x)?,
+            },
+            2 => {
+                let shift = add_op(
+                    &mut body,
+                    block,
+                    Operator::I32Const {
+                        value: u.int_in_range(0..=31)?,
+                    },
+                    &[],
+                    Type::I32,
+                );
+                let cond = add_op(&mut body, block, Operator::I32ShrU, &[x, shift], Type::I32);
+                Terminator::CondBr {
+                    cond,
+                    if_true: target(&mut body, u, block, x)?,
+                    if_false: target(&mut body, u, block, x)?,
+                }
+            }
+            _ => {
+                let n_targets = u.int_in_range(1..=3)?;
+                let mut targets = vec![];
+                for _ in 0..n_targets {
+                    targets.push(target(&mut body, u, block, x)?);
+                }
+                Terminator::Select {
+                    value: x,
+                    targets,
+                    default: target(&mut body, u, block, x)?,
+                }
+            }
+        };
+        body.set_terminator(block, terminator);
+    }
+
+    let func = module
+        .funcs
+        .push(FuncDecl::Body(sig, "f".to_owned(), Box::new(body)));
+    module.exports.push(Export {
+        name: "f".to_owned(),
+        kind: ExportKind::Func(func),
+    });
+    Ok(module)
+}
@@ -2,6 +2,7 @@
 
 use crate::entity::{EntityRef, PerEntity};
 use crate::ir::*;
+use crate::op_traits::op_produces_arithmetic_nan;
 use crate::ops::Operator;
 use smallvec::{smallvec, SmallVec};
 
@@ -18,8 +19,16 @@ pub struct InterpContext {
     pub globals: PerEntity<Global, ConstVal>,
     pub fuel: u64,
     pub trace_handler: Option<Box<dyn Fn(usize, Vec<ConstVal>) -> bool + Send>>,
+    /// Host implementation of imported functions, given the import's
+    /// module and name. It may call back into the module, but calls
+    /// to imports from there go to WASI only. Returning `None` falls
+    /// back to WASI.
+    pub import_handler: Option<Box<ImportHandler>>,
 }
 
+pub type ImportHandler = dyn FnMut(&mut InterpContext, &Module<'_>, &str, &str, &[ConstVal]) -> Option<InterpResult>
+    + Send;
+
 type MultiVal = SmallVec<[ConstVal; 2]>;
 
 #[derive(Clone, Debug)]
@@ -86,6 +95,7 @@ impl InterpContext {
             globals,
             fuel: u64::MAX,
             trace_handler: None,
+            import_handler: None,
         })
     }
 
@@ -96,7 +106,7 @@ impl InterpContext {
             FuncDecl::Import(..) => {
                 let import = &module.imports[func.index()];
                 assert_eq!(import.kind, ImportKind::Func(func));
-                return self.call_import(&import.name[..], args);
+                return self.call_import(module, &import.module[..], &import.name[..], args);
             }
             FuncDecl::Body(_, _, body) => body,
             FuncDecl::None => panic!("FuncDecl::None in call()"),
@@ -162,7 +172,16 @@ impl InterpContext {
                             })
                             .collect::<Vec<_>>();
                         let idx = args.last().unwrap().as_u32().unwrap() as usize;
-                        let func = self.tables[table_index].elements[idx];
+                        let func = match self.tables[table_index].elements.get(idx) {
+                            Some(&func) => func,
+                            None => {
+                                return InterpResult::Trap(
+                                    frame.func,
+                                    frame.cur_block,
+                                    inst_idx as u32,
+                                )
+                            }
+                        };
                         let result = self.call(module, func, &args[..args.len() - 1]);
                         match result {
                             InterpResult::Ok(vals) => vals,
@@ -279,7 +298,20 @@ impl InterpContext {
         }
     }
 
-    fn call_import(&mut self, name: &str, args: &[ConstVal]) -> InterpResult {
+    fn call_import(
+        &mut self,
+        module: &Module<'_>,
+        from: &str,
+        name: &str,
+        args: &[ConstVal],
+    ) -> InterpResult {
+        if let Some(mut handler) = self.import_handler.take() {
+            let result = handler(self, module, from, name, args);
+            self.import_handler = Some(handler);
+            if let Some(result) = result {
+                return result;
+            }
+        }
         if let Some(ret) = wasi::call_wasi(&mut self.memories[Memory::from(0)], name, args) {
             return ret;
         }
@@ -349,10 +381,34 @@ impl ConstVal {
     }
 }
 
+/// The canonical NaNs, with a positive sign.
+pub const CANONICAL_NAN_F32: u32 = 0x7fc0_0000;
+pub const CANONICAL_NAN_F64: u64 = 0x7ff8_0000_0000_0000;
+
+/// Evaluate `op` on constant arguments. NaN results of arithmetic
+/// operators are always the canonical NaN: the spec allows it, and it
+/// agrees with NaN-canonicalized code, so folding never exposes the
+/// host's NaN bits.
 pub fn const_eval(
     op: &Operator,
     vals: &[ConstVal],
     ctx: Option<&mut InterpContext>,
+) -> Option<ConstVal> {
+    let result = const_eval_op(op, vals, ctx)?;
+    if !op_produces_arithmetic_nan(op) {
+        return Some(result);
+    }
+    Some(match result {
+        ConstVal::F32(bits) if f32::from_bits(bits).is_nan() => ConstVal::F32(CANONICAL_NAN_F32),
+        ConstVal::F64(bits) if f64::from_bits(bits).is_nan() => ConstVal::F64(CANONICAL_NAN_F64),
+        result => result,
+    })
+}
+
+fn const_eval_op(
+    op: &Operator,
+    vals: &[ConstVal],
+    ctx: Option<&mut InterpContext>,
 ) -> Option<ConstVal> {
     match (op, vals) {
         (Operator::I32Const { value }, []) => Some(ConstVal::I32(*value)),
@@ -71,3 +71,7 @@ mod display;
 pub use display::*;
 mod debug;
 pub use debug::*;
+mod refs;
+pub use refs::*;
+mod structural;
+pub(crate) use structural::{KeyOptions, StructuralKey, Token};
@@ -1,5 +1,6 @@
 use super::{Block, FunctionBodyDisplay, Local, Module, Signature, Type, Value, ValueDef};
-use crate::backend::WasmFuncBackend;
+use crate::backend::reducify::reducify;
+use crate::backend::{BodyLocs, OptimizeFor, WasmFuncBackend};
 use crate::cfg::CFGInfo;
 use crate::entity::{EntityRef, EntityVec, PerEntity};
 use crate::frontend::parse_body;
@@ -8,6 +9,7 @@ use crate::pool::{ListPool, ListRef};
 use anyhow::Result;
 use fxhash::FxHashMap;
 use std::collections::HashSet;
+use std::ops::Range;
 
 /// A declaration of a function: there is one `FuncDecl` per `Func`
 /// index.
@@ -18,7 +20,7 @@ pub enum FuncDecl<'a> {
     /// An un-expanded body that can be lazily expanded if needed.
     Lazy(Signature, String, wasmparser::FunctionBody<'a>),
     /// A modified or new function body that requires compilation.
-    Body(Signature, String, FunctionBody),
+    Body(Signature, String, Box<FunctionBody>),
     /// A compiled function body (was IR, has been collapsed back to bytecode).
     Compiled(Signature, String, wasm_encoder::Function),
     /// A placeholder.
@@ -41,7 +43,7 @@ impl<'a> FuncDecl<'a> {
         match self {
             FuncDecl::Lazy(sig, name, body) => {
                 let body = parse_body(module, *sig, body)?;
-                *self = FuncDecl::Body(*sig, name.clone(), body);
+                *self = FuncDecl::Body(*sig, name.clone(), Box::new(body));
                 Ok(())
             }
             _ => Ok(()),
@@ -49,20 +51,14 @@ impl<'a> FuncDecl<'a> {
     }
 
     pub fn optimize(&mut self) {
-        match self {
-            FuncDecl::Body(_, _, body) => {
-                body.optimize();
-            }
-            _ => {}
+        if let Some(body) = self.body_mut() {
+            body.optimize();
         }
     }
 
     pub fn convert_to_max_ssa(&mut self, cut_blocks: Option<HashSet<Block>>) {
-        match self {
-            FuncDecl::Body(_, _, body) => {
-                body.convert_to_max_ssa(cut_blocks);
-            }
-            _ => {}
+        if let Some(body) = self.body_mut() {
+            body.convert_to_max_ssa(cut_blocks);
         }
     }
 
@@ -73,9 +69,15 @@ impl<'a> FuncDecl<'a> {
         }
     }
 
+    /// Get the body for modification. This marks the body dirty, so
+    /// that it will be recompiled rather than copied from the
+    /// original module.
     pub fn body_mut(&mut self) -> Option<&mut FunctionBody> {
         match self {
-            FuncDecl::Body(_, _, body) => Some(body),
+            FuncDecl::Body(_, _, body) => {
+                body.mark_dirty();
+                Some(body)
+            }
             _ => None,
         }
     }
@@ -102,7 +104,11 @@ impl<'a> FuncDecl<'a> {
 
     pub fn without_orig_bytes(self) -> FuncDecl<'static> {
         match self {
-            FuncDecl::Body(sig, name, body) => FuncDecl::Body(sig, name, body),
+            FuncDecl::Body(sig, name, mut body) => {
+                // The original bytes are no longer available to copy.
+                body.mark_dirty();
+                FuncDecl::Body(sig, name, body)
+            }
             FuncDecl::Import(sig, name) => FuncDecl::Import(sig, name),
             FuncDecl::Compiled(sig, name, func) => FuncDecl::Compiled(sig, name, func),
             FuncDecl::None => FuncDecl::None,
@@ -136,8 +142,22 @@ pub struct FunctionBody {
     pub value_blocks: PerEntity<Value, Block>,
     /// Wasm locals that values correspond to, if any.
     pub value_locals: PerEntity<Value, Option<Local>>,
+    /// Names of Wasm locals, from the name section.
+    pub local_names: PerEntity<Local, Option<String>>,
+    /// Names of Wasm labels, given to the blocks reached by branches
+    /// to each label.
+    pub label_names: PerEntity<Block, Option<String>>,
     /// Debug source locations of each value.
     pub source_locs: PerEntity<Value, SourceLoc>,
+    /// Offset in the original module's bytes of the operator that
+    /// produced each value, if any.
+    pub orig_offsets: PerEntity<Value, Option<u32>>,
+    /// Byte range of this body in the original module, if it was
+    /// parsed from there.
+    pub orig_range: Option<Range<usize>>,
+    /// Whether the body may have changed since it was parsed. A clean
+    /// body with an `orig_range` is emitted as its original bytes.
+    pub(crate) dirty: bool,
 }
 
 impl FunctionBody {
@@ -166,11 +186,48 @@ impl FunctionBody {
             single_type_dedup: FxHashMap::default(),
             value_blocks,
             value_locals: PerEntity::default(),
+            local_names: PerEntity::default(),
+            label_names: PerEntity::default(),
             source_locs: PerEntity::default(),
+            orig_offsets: PerEntity::default(),
+            orig_range: None,
+            dirty: true,
+        }
+    }
+
+    /// Has the body been modified, or been made available for
+    /// modification, since it was parsed?
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Mark the body as changed, so that the backend recompiles it.
+    /// `FuncDecl::body_mut()` and the transforms on `FunctionBody`
+    /// do this already; code that changes a body some other way must
+    /// call this.
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Mark the body as unchanged from its original bytes, e.g. after
+    /// an analysis that used `FuncDecl::body_mut()` but made no
+    /// changes.
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+
+    /// The range of original bytes that can be emitted in place of
+    /// compiling this body, if it is clean.
+    pub fn clean_orig_range(&self) -> Option<Range<usize>> {
+        if self.dirty {
+            None
+        } else {
+            self.orig_range.clone()
         }
     }
 
     pub fn optimize(&mut self) {
+        self.mark_dirty();
         let cfg = crate::cfg::CFGInfo::new(self);
         crate::passes::remove_phis::run(self, &cfg);
         crate::passes::basic_opt::gvn(self, &cfg);
@@ -179,6 +236,7 @@ impl FunctionBody {
     }
 
     pub fn convert_to_max_ssa(&mut self, cut_blocks: Option<HashSet<Block>>) {
+        self.mark_dirty();
         let cfg = crate::cfg::CFGInfo::new(self);
         crate::passes::maxssa::run(self, cut_blocks, &cfg);
     }
@@ -250,6 +308,42 @@ impl FunctionBody {
         edge_block
     }
 
+    /// Split `block` before the instruction at `index`: the
+    /// instructions from `index` onward and the terminator move to a
+    /// new block, and `block` ends with an unconditional branch to
+    /// it. Returns the new block.
+    pub fn split_block(&mut self, block: Block, index: usize) -> Block {
+        let new_block = self.add_block();
+
+        let insts = self.blocks[block].insts.split_off(index);
+        for &inst in &insts {
+            self.value_blocks[inst] = new_block;
+        }
+        self.blocks[new_block].insts = insts;
+        self.blocks[new_block].terminator = std::mem::replace(
+            &mut self.blocks[block].terminator,
+            Terminator::Br {
+                target: BlockTarget {
+                    block: new_block,
+                    args: vec![],
+                },
+            },
+        );
+
+        // Move the succ edges to the new block; our position in each
+        // succ's pred list stays the same.
+        let succs = std::mem::take(&mut self.blocks[block].succs);
+        let pos_in_succ_pred = std::mem::take(&mut self.blocks[block].pos_in_succ_pred);
+        for (&succ, &pos) in succs.iter().zip(pos_in_succ_pred.iter()) {
+            self.blocks[succ].preds[pos] = new_block;
+        }
+        self.blocks[new_block].succs = succs;
+        self.blocks[new_block].pos_in_succ_pred = pos_in_succ_pred;
+        self.add_edge(block, new_block);
+
+        new_block
+    }
+
     pub fn recompute_edges(&mut self) {
         for block in self.blocks.values_mut() {
             block.preds.clear();
@@ -309,6 +403,25 @@ impl FunctionBody {
         value
     }
 
+    /// Append a parameter to the function, returning its value (a
+    /// blockparam on the entry block). Non-parameter locals are
+    /// renumbered to follow it.
+    pub fn add_param(&mut self, ty: Type) -> Value {
+        debug_assert!(self.blocks[self.entry].preds.is_empty());
+        let mut locals = std::mem::take(&mut self.locals).into_vec();
+        locals.insert(self.n_params, ty);
+        self.locals = EntityVec::from(locals);
+        for value in self.values.iter() {
+            if let Some(local) = self.value_locals[value] {
+                if local.index() >= self.n_params {
+                    self.value_locals[value] = Some(Local::new(local.index() + 1));
+                }
+            }
+        }
+        self.n_params += 1;
+        self.add_blockparam(self.entry, ty)
+    }
+
     pub fn add_placeholder(&mut self, ty: Type) -> Value {
         self.add_value(ValueDef::Placeholder(ty))
     }
@@ -476,8 +589,20 @@ impl FunctionBody {
     }
 
     pub fn compile(&self) -> Result<wasm_encoder::Function> {
-        let backend = WasmFuncBackend::new(self)?;
-        backend.compile()
+        Ok(self.compile_with_locs(OptimizeFor::default())?.0)
+    }
+
+    /// Compile the body, also returning where its instructions came
+    /// from.
+    pub fn compile_with_locs(
+        &self,
+        optimize_for: OptimizeFor,
+    ) -> Result<(wasm_encoder::Function, BodyLocs)> {
+        // Stackify needs reducible control flow; rewrite a copy of
+        // the body if necessary.
+        let body = reducify(self);
+        let backend = WasmFuncBackend::new(&body, optimize_for)?;
+        backend.compile_with_locs()
     }
 }
 
@@ -1,9 +1,14 @@
 use super::{Func, FuncDecl, Global, Memory, ModuleDisplay, Signature, Table, Type};
-use crate::entity::{EntityRef, EntityVec};
+use crate::entity::{EntityRef, EntityVec, PerEntity};
 use crate::ir::{Debug, DebugMap, FunctionBody};
 use crate::{backend, frontend};
 use anyhow::Result;
+use std::collections::BTreeMap;
 
+pub use crate::backend::{
+    BodyLocs, CompileOptions, CompileOutput, DebugInfoMode, FuncOffsetMap, FuncSize, OffsetMap,
+    OptimizeFor,
+};
 pub use crate::frontend::FrontendOptions;
 
 #[derive(Clone, Debug)]
@@ -19,6 +24,28 @@ pub struct Module<'a> {
     pub start_func: Option<Func>,
     pub debug: Debug,
     pub debug_map: DebugMap,
+    /// Names of signatures, from the name section. These are kept
+    /// apart from `SignatureData`, which is compared structurally.
+    pub signature_names: PerEntity<Signature, Option<String>>,
+    /// Names of globals, tables, and memories, kept apart from their
+    /// data for the same reason.
+    pub global_names: PerEntity<Global, Option<String>>,
+    pub table_names: PerEntity<Table, Option<String>>,
+    pub memory_names: PerEntity<Memory, Option<String>>,
+    /// Names of data segments, keyed by memory and index in its
+    /// `segments`.
+    pub segment_names: BTreeMap<(Memory, usize), String>,
+    /// Local and label names of the original module's function
+    /// bodies, keyed by each body's offset in `orig_bytes`.
+    pub orig_body_names: BTreeMap<usize, BodyNames>,
+}
+
+/// Names within one function body, as in the name section: Wasm
+/// local and label indices with their names.
+#[derive(Clone, Debug, Default)]
+pub struct BodyNames {
+    pub locals: Vec<(u32, String)>,
+    pub labels: Vec<(u32, String)>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -143,6 +170,12 @@ impl<'a> Module<'a> {
             start_func: None,
             debug: Debug::default(),
             debug_map: DebugMap::default(),
+            signature_names: PerEntity::default(),
+            global_names: PerEntity::default(),
+            table_names: PerEntity::default(),
+            memory_names: PerEntity::default(),
+            segment_names: BTreeMap::new(),
+            orig_body_names: BTreeMap::default(),
         }
     }
 
@@ -165,6 +198,12 @@ impl<'a> Module<'a> {
             start_func: self.start_func,
             debug: self.debug,
             debug_map: self.debug_map,
+            signature_names: self.signature_names,
+            global_names: self.global_names,
+            table_names: self.table_names,
+            memory_names: self.memory_names,
+            segment_names: self.segment_names,
+            orig_body_names: self.orig_body_names,
         }
     }
 }
@@ -183,12 +222,35 @@ impl<'a> Module<'a> {
         })
     }
 
+    /// The signature with exactly these params and returns, added if
+    /// the module has none yet.
+    pub fn find_or_add_signature(&mut self, data: SignatureData) -> Signature {
+        let existing = self
+            .signatures
+            .entries()
+            .find(|(_, sig)| **sig == data)
+            .map(|(sig, _)| sig);
+        existing.unwrap_or_else(|| self.signatures.push(data))
+    }
+
     pub fn from_wasm_bytes(bytes: &'a [u8], options: &FrontendOptions) -> Result<Self> {
         frontend::wasm_to_ir(bytes, options)
     }
 
+    /// Compile the module to Wasm bytes with the default
+    /// `CompileOptions`. This is `compile(&CompileOptions::default())`
+    /// without the other outputs; use `compile` to optimize for size,
+    /// emit debug info, or validate.
     pub fn to_wasm_bytes(&self) -> Result<Vec<u8>> {
-        backend::compile(self)
+        Ok(self.compile(&CompileOptions::default())?.wasm)
+    }
+
+    /// Compile the module to Wasm bytes, along with the source map,
+    /// offset map, and function sizes that `options` asks for. The
+    /// output depends only on the module and the options, not on the
+    /// run or the size of the thread pool.
+    pub fn compile(&self, options: &CompileOptions) -> Result<CompileOutput> {
+        backend::compile_with_options(self, options)
     }
 
     pub fn per_func_body<F: Fn(&mut FunctionBody)>(&mut self, f: F) {
@@ -213,15 +275,17 @@ impl<'a> Module<'a> {
         let mut body = self.funcs[id].clone();
         body.parse(self)?;
         Ok(match body {
-            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Body(_, _, body) => *body,
             _ => unreachable!(),
         })
     }
 
-    pub fn replace_body(&mut self, id: Func, body: FunctionBody) {
+    /// Replace a function's body. The new body is marked dirty.
+    pub fn replace_body(&mut self, id: Func, mut body: FunctionBody) {
+        body.mark_dirty();
         let sig = self.funcs[id].sig();
         let name = self.funcs[id].name().to_owned();
-        self.funcs[id] = FuncDecl::Body(sig, name, body);
+        self.funcs[id] = FuncDecl::Body(sig, name, Box::new(body));
     }
 
     pub fn expand_all_funcs(&mut self) -> Result<()> {
@@ -0,0 +1,504 @@
+//! References from function bodies to module-level entities, and
+//! renumbering of module-level entities.
+
+use super::{
+    ExportKind, Func, FuncDecl, FunctionBody, Global, GlobalData, Import, ImportKind, Module,
+    Signature, Table, ValueDef,
+};
+use crate::entity::{EntityRef, EntityVec, PerEntity};
+use crate::ops::Operator;
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+
+/// A use of a module-level entity by a function body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EntityUse {
+    /// A direct call.
+    Call(Func),
+    /// A reference to a function other than a direct call
+    /// (`ref.func`). Only seen in not-yet-expanded bodies.
+    FuncRef(Func),
+    /// An indirect call through a table, with the expected signature.
+    CallIndirect(Signature, Table),
+    GlobalGet(Global),
+    GlobalSet(Global),
+    /// A read of a table other than by an indirect call
+    /// (`table.get`, `table.size`, etc.).
+    Table(Table),
+    /// A write to a table (`table.set`, `table.grow`, etc.).
+    TableWrite(Table),
+    /// A use of a signature other than by an indirect call, e.g. a
+    /// block type in a not-yet-expanded body.
+    Signature(Signature),
+}
+
+fn visit_op_uses<F: FnMut(EntityUse)>(op: &Operator, f: &mut F) {
+    match op {
+        &Operator::Call { function_index } => f(EntityUse::Call(function_index)),
+        &Operator::CallIndirect {
+            sig_index,
+            table_index,
+        } => f(EntityUse::CallIndirect(sig_index, table_index)),
+        &Operator::GlobalGet { global_index } => f(EntityUse::GlobalGet(global_index)),
+        &Operator::GlobalSet { global_index } => f(EntityUse::GlobalSet(global_index)),
+        &Operator::TableGet { table_index } | &Operator::TableSize { table_index } => {
+            f(EntityUse::Table(table_index))
+        }
+        &Operator::TableSet { table_index } | &Operator::TableGrow { table_index } => {
+            f(EntityUse::TableWrite(table_index))
+        }
+        _ => {}
+    }
+}
+
+fn visit_wasm_op_uses<F: FnMut(EntityUse)>(op: &wasmparser::Operator, f: &mut F) {
+    match op {
+        &wasmparser::Operator::Call { function_index }
+        | &wasmparser::Operator::ReturnCall { function_index } => {
+            f(EntityUse::Call(Func::from(function_index)))
+        }
+        &wasmparser::Operator::RefFunc { function_index } => {
+            f(EntityUse::FuncRef(Func::from(function_index)))
+        }
+        &wasmparser::Operator::CallIndirect {
+            type_index,
+            table_index,
+            ..
+        }
+        | &wasmparser::Operator::ReturnCallIndirect {
+            type_index,
+            table_index,
+        } => f(EntityUse::CallIndirect(
+            Signature::from(type_index),
+            Table::from(table_index),
+        )),
+        &wasmparser::Operator::GlobalGet { global_index } => {
+            f(EntityUse::GlobalGet(Global::from(global_index)))
+        }
+        &wasmparser::Operator::GlobalSet { global_index } => {
+            f(EntityUse::GlobalSet(Global::from(global_index)))
+        }
+        &wasmparser::Operator::TableGet { table } | &wasmparser::Operator::TableSize { table } => {
+            f(EntityUse::Table(Table::from(table)))
+        }
+        &wasmparser::Operator::TableSet { table }
+        | &wasmparser::Operator::TableGrow { table }
+        | &wasmparser::Operator::TableFill { table }
+        | &wasmparser::Operator::TableInit { table, .. } => {
+            f(EntityUse::TableWrite(Table::from(table)))
+        }
+        &wasmparser::Operator::TableCopy {
+            dst_table,
+            src_table,
+        } => {
+            f(EntityUse::TableWrite(Table::from(dst_table)));
+            f(EntityUse::Table(Table::from(src_table)));
+        }
+        &wasmparser::Operator::Block { blockty }
+        | &wasmparser::Operator::Loop { blockty }
+        | &wasmparser::Operator::If { blockty }
+        | &wasmparser::Operator::Try { blockty } => {
+            if let wasmparser::BlockType::FuncType(sig_index) = blockty {
+                f(EntityUse::Signature(Signature::from(sig_index)));
+            }
+        }
+        _ => {}
+    }
+}
+
+impl<'a> FuncDecl<'a> {
+    /// Visit every use of a module-level entity in this function's
+    /// body. Lazy bodies are scanned without expanding them. The
+    /// function's own signature is not visited.
+    pub fn visit_uses<F: FnMut(EntityUse)>(&self, mut f: F) -> Result<()> {
+        match self {
+            FuncDecl::Body(_, _, body) => {
+                for block in body.blocks.values() {
+                    for &inst in &block.insts {
+                        if let ValueDef::Operator(op, ..) = &body.values[inst] {
+                            visit_op_uses(op, &mut f);
+                        }
+                    }
+                }
+            }
+            FuncDecl::Lazy(_, _, body) => {
+                for op in body.get_operators_reader()? {
+                    visit_wasm_op_uses(&op?, &mut f);
+                }
+            }
+            FuncDecl::Compiled(_, name, _) => {
+                bail!(
+                    "Cannot scan already-compiled function body \"{}\" for entity uses",
+                    name
+                );
+            }
+            FuncDecl::Import(..) | FuncDecl::None => {}
+        }
+        Ok(())
+    }
+}
+
+impl FunctionBody {
+    /// Visit every use of a module-level entity in the original bytes
+    /// that this body is emitted as while it is clean, given the
+    /// module's `orig_bytes`. These can refer to entities the IR does
+    /// not, such as block-type signatures and anything in unreachable
+    /// code.
+    pub(crate) fn visit_orig_uses<F: FnMut(EntityUse)>(
+        &self,
+        orig_bytes: &[u8],
+        mut f: F,
+    ) -> Result<()> {
+        if let Some(range) = self.clean_orig_range() {
+            let reader = wasmparser::FunctionBody::new(range.start, &orig_bytes[range]);
+            for op in reader.get_operators_reader()? {
+                visit_wasm_op_uses(&op?, &mut f);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A renumbering of module-level entities. Each map takes an old
+/// index to a new index, or to an invalid index if the entity is
+/// removed. The new indices of retained entities must be exactly
+/// `0..n` for some `n`.
+#[derive(Clone, Debug)]
+pub struct EntityRemap {
+    pub funcs: EntityVec<Func, Func>,
+    pub signatures: EntityVec<Signature, Signature>,
+    pub globals: EntityVec<Global, Global>,
+    pub tables: EntityVec<Table, Table>,
+}
+
+fn identity<Idx: EntityRef + std::fmt::Debug>(len: usize) -> EntityVec<Idx, Idx> {
+    EntityVec::from((0..len).map(Idx::new).collect::<Vec<_>>())
+}
+
+fn check_map<Idx: EntityRef + std::fmt::Display + std::fmt::Debug>(
+    map: &EntityVec<Idx, Idx>,
+    len: usize,
+    kind: &str,
+) -> Result<usize> {
+    if map.len() != len {
+        bail!(
+            "Remap for {} has {} entries but module has {}",
+            kind,
+            map.len(),
+            len
+        );
+    }
+    let mut seen = vec![false; len];
+    for (old, &new) in map.entries() {
+        if new.is_invalid() {
+            continue;
+        }
+        if new.index() >= len || seen[new.index()] {
+            bail!(
+                "Remap for {} maps {} to invalid or duplicate {}",
+                kind,
+                old,
+                new
+            );
+        }
+        seen[new.index()] = true;
+    }
+    let kept = seen.iter().filter(|&&s| s).count();
+    if seen[..kept].iter().any(|&s| !s) {
+        bail!("Remap for {} does not produce dense indices", kind);
+    }
+    Ok(kept)
+}
+
+fn permute<Idx: EntityRef + std::fmt::Debug, T: Clone + std::fmt::Debug>(
+    items: EntityVec<Idx, T>,
+    map: &EntityVec<Idx, Idx>,
+    kept: usize,
+) -> EntityVec<Idx, T> {
+    let mut slots: Vec<Option<T>> = vec![None; kept];
+    for (old, item) in items.into_vec().into_iter().enumerate() {
+        let new = map[Idx::new(old)];
+        if new.is_valid() {
+            slots[new.index()] = Some(item);
+        }
+    }
+    EntityVec::from(
+        slots
+            .into_iter()
+            .map(|item| item.unwrap())
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Move each name to its entity's new index, dropping the names of
+/// removed entities.
+fn permute_names<Idx: EntityRef + std::fmt::Debug + Default>(
+    names: &mut PerEntity<Idx, Option<String>>,
+    map: &EntityVec<Idx, Idx>,
+) {
+    let mut permuted = PerEntity::default();
+    for (old, &new) in map.entries() {
+        if new.is_valid() {
+            permuted[new] = names[old].take();
+        }
+    }
+    *names = permuted;
+}
+
+impl EntityRemap {
+    /// A remap that leaves every entity of `module` in place.
+    pub fn identity(module: &Module) -> Self {
+        EntityRemap {
+            funcs: identity(module.funcs.len()),
+            signatures: identity(module.signatures.len()),
+            globals: identity(module.globals.len()),
+            tables: identity(module.tables.len()),
+        }
+    }
+
+    /// Does this remap change the entity referred to by `entity_use`?
+    pub fn changes(&self, entity_use: EntityUse) -> bool {
+        match entity_use {
+            EntityUse::Call(func) | EntityUse::FuncRef(func) => self.funcs[func] != func,
+            EntityUse::CallIndirect(sig, table) => {
+                self.signatures[sig] != sig || self.tables[table] != table
+            }
+            EntityUse::GlobalGet(global) | EntityUse::GlobalSet(global) => {
+                self.globals[global] != global
+            }
+            EntityUse::Table(table) | EntityUse::TableWrite(table) => self.tables[table] != table,
+            EntityUse::Signature(sig) => self.signatures[sig] != sig,
+        }
+    }
+
+    fn remap_op(&self, op: &mut Operator) {
+        match op {
+            Operator::Call { function_index } => *function_index = self.funcs[*function_index],
+            Operator::CallIndirect {
+                sig_index,
+                table_index,
+            } => {
+                *sig_index = self.signatures[*sig_index];
+                *table_index = self.tables[*table_index];
+            }
+            Operator::GlobalGet { global_index } | Operator::GlobalSet { global_index } => {
+                *global_index = self.globals[*global_index]
+            }
+            Operator::TableGet { table_index }
+            | Operator::TableSet { table_index }
+            | Operator::TableGrow { table_index }
+            | Operator::TableSize { table_index } => *table_index = self.tables[*table_index],
+            _ => {}
+        }
+    }
+}
+
+impl<'a> Module<'a> {
+    /// Renumber functions, signatures, globals, and tables according
+    /// to `remap`, dropping removed entities along with any imports
+    /// and exports of them. Lazy bodies whose entity references would
+    /// change are expanded first, and clean bodies whose original
+    /// bytes would change are marked dirty; all others are left as raw
+    /// bytes.
+    pub fn remap_entities(&mut self, remap: &EntityRemap) -> Result<()> {
+        let n_funcs = check_map(&remap.funcs, self.funcs.len(), "functions")?;
+        let n_sigs = check_map(&remap.signatures, self.signatures.len(), "signatures")?;
+        let n_globals = check_map(&remap.globals, self.globals.len(), "globals")?;
+        let n_tables = check_map(&remap.tables, self.tables.len(), "tables")?;
+
+        for (func, decl) in self.funcs.entries() {
+            if remap.funcs[func].is_valid()
+                && !matches!(decl, FuncDecl::None)
+                && remap.signatures[decl.sig()].is_invalid()
+            {
+                bail!("Cannot remove signature {} used by {}", decl.sig(), func);
+            }
+        }
+
+        // Expand lazy bodies that refer to renumbered entities. This
+        // must happen before anything is renumbered, since parsing
+        // resolves indices against the current module.
+        let expanded = self
+            .funcs
+            .entries()
+            .collect::<Vec<_>>()
+            .par_iter()
+            .filter(|(func, decl)| {
+                remap.funcs[*func].is_valid() && matches!(decl, FuncDecl::Lazy(..))
+            })
+            .map(|&(func, decl)| -> Result<Option<(Func, FuncDecl<'a>)>> {
+                let mut changed = false;
+                decl.visit_uses(|u| changed |= remap.changes(u))?;
+                if !changed {
+                    return Ok(None);
+                }
+                let mut decl = decl.clone();
+                decl.parse(self)?;
+                Ok(Some((func, decl)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for (func, decl) in expanded.into_iter().flatten() {
+            self.funcs[func] = decl;
+        }
+
+        let orig_bytes = self.orig_bytes;
+        for decl in self.funcs.values_mut() {
+            match decl {
+                FuncDecl::Import(sig, _)
+                | FuncDecl::Lazy(sig, _, _)
+                | FuncDecl::Body(sig, _, _)
+                | FuncDecl::Compiled(sig, _, _) => *sig = remap.signatures[*sig],
+                FuncDecl::None => {}
+            }
+            if let FuncDecl::Body(_, _, body) = decl {
+                let mut changed = false;
+                for value_def in body.values.values_mut() {
+                    if let ValueDef::Operator(op, ..) = value_def {
+                        let old = *op;
+                        remap.remap_op(op);
+                        changed |= *op != old;
+                    }
+                }
+                if !changed {
+                    body.visit_orig_uses(orig_bytes, |u| changed |= remap.changes(u))?;
+                }
+                // The original bytes refer to the old indices.
+                if changed {
+                    body.mark_dirty();
+                }
+            }
+        }
+
+        let funcs = std::mem::take(&mut self.funcs);
+        self.funcs = permute(funcs, &remap.funcs, n_funcs);
+        let signatures = std::mem::take(&mut self.signatures);
+        self.signatures = permute(signatures, &remap.signatures, n_sigs);
+        permute_names(&mut self.signature_names, &remap.signatures);
+        let globals = std::mem::take(&mut self.globals);
+        self.globals = permute(globals, &remap.globals, n_globals);
+        permute_names(&mut self.global_names, &remap.globals);
+        let tables = std::mem::take(&mut self.tables);
+        self.tables = permute(tables, &remap.tables, n_tables);
+        permute_names(&mut self.table_names, &remap.tables);
+
+        for table_data in self.tables.values_mut() {
+            if let Some(elts) = &mut table_data.func_elements {
+                for elt in elts.iter_mut() {
+                    if elt.is_valid() {
+                        *elt = remap.funcs[*elt];
+                    }
+                }
+            }
+        }
+
+        self.imports.retain_mut(|import| match &mut import.kind {
+            ImportKind::Func(func) => {
+                *func = remap.funcs[*func];
+                func.is_valid()
+            }
+            ImportKind::Global(global) => {
+                *global = remap.globals[*global];
+                global.is_valid()
+            }
+            ImportKind::Table(table) => {
+                *table = remap.tables[*table];
+                table.is_valid()
+            }
+            ImportKind::Memory(_) => true,
+        });
+        self.exports.retain_mut(|export| match &mut export.kind {
+            ExportKind::Func(func) => {
+                *func = remap.funcs[*func];
+                func.is_valid()
+            }
+            ExportKind::Global(global) => {
+                *global = remap.globals[*global];
+                global.is_valid()
+            }
+            ExportKind::Table(table) => {
+                *table = remap.tables[*table];
+                table.is_valid()
+            }
+            ExportKind::Memory(_) => true,
+        });
+        self.start_func = self
+            .start_func
+            .map(|func| remap.funcs[func])
+            .filter(|func| func.is_valid());
+
+        Ok(())
+    }
+
+    /// Add imported functions, given as `(module, name, signature)`,
+    /// after the existing function imports. Imports must precede all
+    /// bodies, so the module's own functions are renumbered to make
+    /// room. Returns the new functions, in order.
+    pub fn add_func_imports(&mut self, imports: &[(&str, &str, Signature)]) -> Result<Vec<Func>> {
+        let n_imports = self
+            .funcs
+            .values()
+            .take_while(|decl| matches!(decl, FuncDecl::Import(..)))
+            .count();
+        let n_funcs = self.funcs.len();
+        for &(module, name, sig) in imports {
+            let func = self.funcs.push(FuncDecl::Import(sig, name.to_owned()));
+            self.imports.push(Import {
+                module: module.to_owned(),
+                name: name.to_owned(),
+                kind: ImportKind::Func(func),
+            });
+        }
+
+        let mut remap = EntityRemap::identity(self);
+        for (old, new) in remap.funcs.entries_mut() {
+            let index = old.index();
+            *new = Func::new(if index < n_imports {
+                index
+            } else if index < n_funcs {
+                index + imports.len()
+            } else {
+                index - n_funcs + n_imports
+            });
+        }
+        self.remap_entities(&remap)?;
+        Ok((n_imports..n_imports + imports.len())
+            .map(Func::new)
+            .collect())
+    }
+
+    /// Add an imported global after the existing global imports,
+    /// renumbering the module's own globals to make room.
+    pub fn add_global_import(
+        &mut self,
+        module: &str,
+        name: &str,
+        data: GlobalData,
+    ) -> Result<Global> {
+        let n_imports = self
+            .imports
+            .iter()
+            .filter(|import| matches!(import.kind, ImportKind::Global(_)))
+            .count();
+        let n_globals = self.globals.len();
+        let global = self.globals.push(data);
+        self.imports.push(Import {
+            module: module.to_owned(),
+            name: name.to_owned(),
+            kind: ImportKind::Global(global),
+        });
+
+        let mut remap = EntityRemap::identity(self);
+        for (old, new) in remap.globals.entries_mut() {
+            let index = old.index();
+            *new = Global::new(if index < n_imports {
+                index
+            } else if index < n_globals {
+                index + 1
+            } else {
+                n_imports
+            });
+        }
+        self.remap_entities(&remap)?;
+        Ok(Global::new(n_imports))
+    }
+}
@@ -0,0 +1,210 @@
+//! Structural hashing and comparison of function bodies, modulo
+//! value and block numbering.
+
+use super::{Block, Func, FunctionBody, Terminator, Type, Value, ValueDef};
+use crate::cfg::postorder;
+use crate::entity::PerEntity;
+use crate::Operator;
+use std::hash::{Hash, Hasher};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Token {
+    Params(usize),
+    Rets(usize),
+    Type(Type),
+    Block(usize),
+    Op(Operator),
+    /// A call from a function to itself.
+    SelfCall,
+    /// A constant whose value is abstracted away.
+    ConstHole(Type),
+    /// A direct call whose callee is abstracted away.
+    CallHole,
+    Args(usize),
+    Use(usize),
+    Pick(u32),
+    Trace(usize),
+    Br,
+    CondBr,
+    Select(usize),
+    Return(usize),
+    Unreachable,
+    NoTerminator,
+    Target(usize),
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct KeyOptions {
+    /// The function that the body belongs to, if calls to itself
+    /// should match calls to itself in the other body.
+    pub self_func: Option<Func>,
+    /// Abstract away constants and callees, recording each as a
+    /// hole.
+    pub holes: bool,
+}
+
+/// A canonical linearization of a function body: blocks are visited
+/// in reverse postorder from the entry and values are numbered in
+/// definition order, so two bodies with the same key compute the
+/// same thing. Unreachable blocks, aliases, and source locations are
+/// ignored.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct StructuralKey {
+    pub tokens: Vec<Token>,
+    /// Instructions abstracted away as holes, in key order.
+    pub holes: Vec<Value>,
+}
+
+impl StructuralKey {
+    pub fn new(body: &FunctionBody, options: KeyOptions) -> StructuralKey {
+        let postorder = postorder::calculate(body.entry, |block| &body.blocks[block].succs[..]);
+        let rpo = postorder.into_iter().rev().collect::<Vec<Block>>();
+
+        let mut block_num: PerEntity<Block, Option<usize>> = PerEntity::default();
+        let mut value_num: PerEntity<Value, Option<usize>> = PerEntity::default();
+        let mut next_value = 0;
+        for (i, &block) in rpo.iter().enumerate() {
+            block_num[block] = Some(i);
+            for &(_, param) in &body.blocks[block].params {
+                value_num[param] = Some(next_value);
+                next_value += 1;
+            }
+            for &inst in &body.blocks[block].insts {
+                value_num[inst] = Some(next_value);
+                next_value += 1;
+            }
+        }
+        let use_token =
+            |value: Value| Token::Use(value_num[body.resolve_alias(value)].unwrap_or(usize::MAX));
+
+        let mut tokens = vec![];
+        let mut holes = vec![];
+        tokens.push(Token::Params(body.n_params));
+        tokens.extend(
+            body.locals
+                .values()
+                .take(body.n_params)
+                .map(|&ty| Token::Type(ty)),
+        );
+        tokens.push(Token::Rets(body.rets.len()));
+        tokens.extend(body.rets.iter().map(|&ty| Token::Type(ty)));
+
+        for &block in &rpo {
+            let block_def = &body.blocks[block];
+            tokens.push(Token::Block(block_def.params.len()));
+            tokens.extend(block_def.params.iter().map(|&(ty, _)| Token::Type(ty)));
+            for &inst in &block_def.insts {
+                match body.values[inst] {
+                    ValueDef::Operator(op, args, tys) => {
+                        let op_token = match op {
+                            Operator::Call { function_index }
+                                if Some(function_index) == options.self_func =>
+                            {
+                                Token::SelfCall
+                            }
+                            Operator::Call { .. } if options.holes => {
+                                holes.push(inst);
+                                Token::CallHole
+                            }
+                            Operator::I32Const { .. } if options.holes => {
+                                holes.push(inst);
+                                Token::ConstHole(Type::I32)
+                            }
+                            Operator::I64Const { .. } if options.holes => {
+                                holes.push(inst);
+                                Token::ConstHole(Type::I64)
+                            }
+                            Operator::F32Const { .. } if options.holes => {
+                                holes.push(inst);
+                                Token::ConstHole(Type::F32)
+                            }
+                            Operator::F64Const { .. } if options.holes => {
+                                holes.push(inst);
+                                Token::ConstHole(Type::F64)
+                            }
+                            op => Token::Op(op),
+                        };
+                        tokens.push(op_token);
+                        tokens.push(Token::Args(args.len()));
+                        tokens.extend(body.arg_pool[args].iter().map(|&arg| use_token(arg)));
+                        tokens.extend(body.type_pool[tys].iter().map(|&ty| Token::Type(ty)));
+                    }
+                    ValueDef::PickOutput(from, index, ty) => {
+                        tokens.push(Token::Pick(index));
+                        tokens.push(use_token(from));
+                        tokens.push(Token::Type(ty));
+                    }
+                    ValueDef::Trace(id, args) => {
+                        tokens.push(Token::Trace(id));
+                        tokens.push(Token::Args(args.len()));
+                        tokens.extend(body.arg_pool[args].iter().map(|&arg| use_token(arg)));
+                    }
+                    _ => {}
+                }
+            }
+
+            let push_target = |tokens: &mut Vec<Token>, block: Block, args: &[Value]| {
+                tokens.push(Token::Target(block_num[block].unwrap_or(usize::MAX)));
+                tokens.push(Token::Args(args.len()));
+                tokens.extend(args.iter().map(|&arg| use_token(arg)));
+            };
+            match &block_def.terminator {
+                Terminator::Br { target } => {
+                    tokens.push(Token::Br);
+                    push_target(&mut tokens, target.block, &target.args[..]);
+                }
+                Terminator::CondBr {
+                    cond,
+                    if_true,
+                    if_false,
+                } => {
+                    tokens.push(Token::CondBr);
+                    tokens.push(use_token(*cond));
+                    push_target(&mut tokens, if_true.block, &if_true.args[..]);
+                    push_target(&mut tokens, if_false.block, &if_false.args[..]);
+                }
+                Terminator::Select {
+                    value,
+                    targets,
+                    default,
+                } => {
+                    tokens.push(Token::Select(targets.len()));
+                    tokens.push(use_token(*value));
+                    for target in targets {
+                        push_target(&mut tokens, target.block, &target.args[..]);
+                    }
+                    push_target(&mut tokens, default.block, &default.args[..]);
+                }
+                Terminator::Return { values } => {
+                    tokens.push(Token::Return(values.len()));
+                    tokens.extend(values.iter().map(|&value| use_token(value)));
+                }
+                Terminator::Unreachable => tokens.push(Token::Unreachable),
+                Terminator::None => tokens.push(Token::NoTerminator),
+            }
+        }
+
+        StructuralKey { tokens, holes }
+    }
+
+    pub fn hash_value(&self) -> u64 {
+        let mut hasher = fxhash::FxHasher::default();
+        self.tokens.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl FunctionBody {
+    /// A hash of this body's structure that does not depend on how
+    /// its values and blocks are numbered.
+    pub fn structural_hash(&self) -> u64 {
+        StructuralKey::new(self, KeyOptions::default()).hash_value()
+    }
+
+    /// Do these two bodies compute the same thing, with the same
+    /// signature, up to renumbering of values and blocks?
+    pub fn structurally_eq(&self, other: &FunctionBody) -> bool {
+        StructuralKey::new(self, KeyOptions::default()).tokens
+            == StructuralKey::new(other, KeyOptions::default()).tokens
+    }
+}
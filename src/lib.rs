@@ -5,6 +5,7 @@
 // Re-export wasmparser for easier use of the right version by our embedders.
 pub use wasmparser;
 
+pub mod analysis;
 mod backend;
 pub mod cfg;
 pub mod entity;
@@ -874,3 +874,37 @@ pub fn op_rematerialize(op: &Operator) -> bool {
         _ => false,
     }
 }
+
+/// Can `op` produce a NaN whose bits the Wasm spec leaves
+/// nondeterministic? These are the arithmetic float operators; the
+/// bitwise ones (`abs`, `neg`, `copysign`), conversions and
+/// reinterpretations are deterministic.
+pub fn op_produces_arithmetic_nan(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::F32Add
+            | Operator::F32Sub
+            | Operator::F32Mul
+            | Operator::F32Div
+            | Operator::F32Min
+            | Operator::F32Max
+            | Operator::F32Sqrt
+            | Operator::F32Ceil
+            | Operator::F32Floor
+            | Operator::F32Trunc
+            | Operator::F32Nearest
+            | Operator::F32DemoteF64
+            | Operator::F64Add
+            | Operator::F64Sub
+            | Operator::F64Mul
+            | Operator::F64Div
+            | Operator::F64Min
+            | Operator::F64Max
+            | Operator::F64Sqrt
+            | Operator::F64Ceil
+            | Operator::F64Floor
+            | Operator::F64Trunc
+            | Operator::F64Nearest
+            | Operator::F64PromoteF32
+    )
+}
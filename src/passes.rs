@@ -1,10 +1,25 @@
 //! Passes.
 
+pub mod asyncify;
 pub mod basic_opt;
+pub mod coverage;
+pub mod devirt;
 pub mod dom_pass;
 pub mod empty_blocks;
+pub mod fuel;
+pub mod gc;
+pub mod legalize;
+pub mod lower_features;
+pub mod manager;
 pub mod maxssa;
+pub mod memtrace;
+pub mod merge_funcs;
+pub mod nan_canon;
+pub mod profile;
 pub mod remove_phis;
 pub mod resolve_aliases;
 pub mod ssa;
+pub mod stack_limit;
 pub mod trace;
+
+pub use manager::{FunctionPass, FunctionPassFn, ModulePass, ModulePassFn, PassManager, PassStats};
@@ -0,0 +1,603 @@
+//! Asyncify: suspend and resume execution across async host calls,
+//! without engine support for stack switching.
+//!
+//! Functions that can reach an async import through the call graph
+//! are rewritten so that they can unwind their frames into a buffer
+//! in linear memory and later rewind them. Each call that can unwind
+//! is split into its own block and the body is converted to max-SSA
+//! with those blocks as cut points, so the values live across the
+//! call are exactly that block's blockparams. After the call, if the
+//! module is unwinding, the function pushes its blockparams and the
+//! index of the call site onto the buffer and returns; on entry, if
+//! the module is rewinding, it pops them and branches straight back
+//! to the call.
+//!
+//! The control API is that of Binaryen's Asyncify:
+//! `asyncify_start_unwind(data)`, `asyncify_stop_unwind()`,
+//! `asyncify_start_rewind(data)`, `asyncify_stop_rewind()` and
+//! `asyncify_get_state()`, where `data` points to two `i32`s, the
+//! current and end addresses of the buffer. The layout of the buffer
+//! itself is private to the module.
+//!
+//! Indirect calls can unwind if a known element of the called table
+//! is an async function of the right signature; as in the call graph,
+//! targets installed at runtime are not seen. Values of types that
+//! cannot be stored in memory (`v128` and `funcref`) cannot be live
+//! across a call that can unwind.
+
+use crate::analysis::CallGraph;
+use crate::entity::EntityRef;
+use crate::ir::{
+    Block, BlockTarget, Export, ExportKind, FuncDecl, FunctionBody, Global, GlobalData, ImportKind,
+    Memory, Module, SignatureData, Terminator, Type, Value, ValueDef,
+};
+use crate::ops::MemoryArg;
+use crate::pool::ListRef;
+use crate::Operator;
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+use std::collections::HashSet;
+
+const STATE_NORMAL: u32 = 0;
+const STATE_UNWINDING: u32 = 1;
+const STATE_REWINDING: u32 = 2;
+
+#[derive(Clone, Debug)]
+pub struct AsyncifyOptions {
+    /// Imports that can unwind, as `(module, name)`, or `None` for
+    /// every imported function.
+    pub imports: Option<Vec<(String, String)>>,
+    /// The memory holding unwind buffers.
+    pub memory: Memory,
+}
+
+impl Default for AsyncifyOptions {
+    fn default() -> Self {
+        AsyncifyOptions {
+            imports: None,
+            memory: Memory::new(0),
+        }
+    }
+}
+
+const EXPORTS: &[&str] = &[
+    "asyncify_start_unwind",
+    "asyncify_stop_unwind",
+    "asyncify_start_rewind",
+    "asyncify_stop_rewind",
+    "asyncify_get_state",
+];
+
+/// The globals holding the asyncify state and the address of the
+/// current buffer's `data` structure.
+#[derive(Clone, Copy)]
+struct State {
+    state: Global,
+    data: Global,
+    memory: Memory,
+}
+
+pub fn run(module: &mut Module, options: &AsyncifyOptions) -> Result<()> {
+    for export in &module.exports {
+        if EXPORTS.contains(&&export.name[..]) {
+            bail!("Module already exports '{}'", export.name);
+        }
+    }
+    if options.memory.index() >= module.memories.len() {
+        bail!("No memory {} for asyncify buffers", options.memory);
+    }
+    module.expand_all_funcs()?;
+
+    // Everything that can reach an async import can unwind.
+    let mut is_async = vec![false; module.funcs.len()];
+    let mut worklist = module
+        .imports
+        .iter()
+        .filter_map(|import| match import.kind {
+            ImportKind::Func(func) => Some((import, func)),
+            _ => None,
+        })
+        .filter(|(import, _)| {
+            options.imports.as_ref().is_none_or(|imports| {
+                imports
+                    .iter()
+                    .any(|(module, name)| *module == import.module && *name == import.name)
+            })
+        })
+        .map(|(_, func)| func)
+        .collect::<Vec<_>>();
+    let callgraph = CallGraph::new(module)?;
+    while let Some(func) = worklist.pop() {
+        if !is_async[func.index()] {
+            is_async[func.index()] = true;
+            worklist.extend(callgraph.callers(func).iter().copied());
+        }
+    }
+    for (func, decl) in module.funcs.entries() {
+        if is_async[func.index()] && decl.body().is_some() {
+            let sig = &module.signatures[decl.sig()];
+            if let Some(&ty) = sig.returns.iter().find(|&&ty| !storable(ty)) {
+                bail!("Cannot asyncify {}: it returns a {}", decl.name(), ty);
+            }
+        }
+    }
+
+    let state = State {
+        state: add_global(module, "__asyncify_state"),
+        data: add_global(module, "__asyncify_data"),
+        memory: options.memory,
+    };
+
+    let unwinds = |body: &FunctionBody, value: Value| match body.values[value] {
+        ValueDef::Operator(Operator::Call { function_index }, ..) => {
+            is_async[function_index.index()]
+        }
+        ValueDef::Operator(
+            Operator::CallIndirect {
+                sig_index,
+                table_index,
+            },
+            ..,
+        ) => module.tables[table_index]
+            .func_elements
+            .iter()
+            .flatten()
+            .any(|&func| {
+                // Match signatures structurally, as the call graph does.
+                func.is_valid()
+                    && is_async[func.index()]
+                    && module.signatures[module.funcs[func].sig()] == module.signatures[sig_index]
+            }),
+        _ => false,
+    };
+    let mut bodies = module
+        .funcs
+        .entries()
+        .filter(|(func, decl)| is_async[func.index()] && decl.body().is_some())
+        .map(|(func, decl)| {
+            let body = decl.body().unwrap();
+            let calls = body
+                .blocks
+                .values()
+                .flat_map(|block| block.insts.iter().copied())
+                .filter(|&inst| unwinds(body, inst))
+                .collect::<HashSet<_>>();
+            (func, calls)
+        })
+        .collect::<Vec<_>>();
+    bodies.retain(|(_, calls)| !calls.is_empty());
+
+    let instrumented = bodies
+        .into_par_iter()
+        .map(|(func, calls)| {
+            let decl = &module.funcs[func];
+            let mut body = decl.body().unwrap().clone();
+            instrument(state, &calls, &mut body)
+                .map_err(|e| anyhow::anyhow!("Cannot asyncify {}: {}", decl.name(), e))?;
+            Ok((func, body))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for (func, body) in instrumented {
+        *module.funcs[func].body_mut().unwrap() = body;
+    }
+
+    add_api(module, state);
+    Ok(())
+}
+
+fn add_global(module: &mut Module, name: &str) -> Global {
+    let global = module.globals.push(GlobalData {
+        ty: Type::I32,
+        value: Some(0),
+        mutable: true,
+    });
+    module.global_names[global] = Some(name.to_owned());
+    global
+}
+
+fn storable(ty: Type) -> bool {
+    matches!(ty, Type::I32 | Type::I64 | Type::F32 | Type::F64)
+}
+
+fn type_size(ty: Type) -> u32 {
+    match ty {
+        Type::I32 | Type::F32 => 4,
+        Type::I64 | Type::F64 => 8,
+        _ => unreachable!(),
+    }
+}
+
+fn add_op(
+    body: &mut FunctionBody,
+    block: Block,
+    op: Operator,
+    args: &[Value],
+    ty: Option<Type>,
+) -> Value {
+    let args = body.arg_pool.from_iter(args.iter().copied());
+    let tys = match ty {
+        Some(ty) => body.single_type_list(ty),
+        None => ListRef::default(),
+    };
+    let value = body.add_value(ValueDef::Operator(op, args, tys));
+    body.append_to_block(block, value);
+    value
+}
+
+fn i32_const(body: &mut FunctionBody, block: Block, value: u32) -> Value {
+    add_op(
+        body,
+        block,
+        Operator::I32Const { value },
+        &[],
+        Some(Type::I32),
+    )
+}
+
+fn zero(body: &mut FunctionBody, block: Block, ty: Type) -> Value {
+    let op = match ty {
+        Type::I32 => Operator::I32Const { value: 0 },
+        Type::I64 => Operator::I64Const { value: 0 },
+        Type::F32 => Operator::F32Const { value: 0 },
+        Type::F64 => Operator::F64Const { value: 0 },
+        _ => unreachable!(),
+    };
+    add_op(body, block, op, &[], Some(ty))
+}
+
+fn global_get(body: &mut FunctionBody, block: Block, global_index: Global) -> Value {
+    let op = Operator::GlobalGet { global_index };
+    add_op(body, block, op, &[], Some(Type::I32))
+}
+
+fn global_set(body: &mut FunctionBody, block: Block, global_index: Global, value: Value) {
+    add_op(
+        body,
+        block,
+        Operator::GlobalSet { global_index },
+        &[value],
+        None,
+    );
+}
+
+/// `global == value`, as an `i32`.
+fn global_is(body: &mut FunctionBody, block: Block, global: Global, value: u32) -> Value {
+    let current = global_get(body, block, global);
+    let value = i32_const(body, block, value);
+    add_op(
+        body,
+        block,
+        Operator::I32Eq,
+        &[current, value],
+        Some(Type::I32),
+    )
+}
+
+fn load(
+    body: &mut FunctionBody,
+    block: Block,
+    memory: Memory,
+    addr: Value,
+    offset: u32,
+    ty: Type,
+) -> Value {
+    let memory = MemoryArg {
+        align: 0,
+        offset,
+        memory,
+    };
+    let op = match ty {
+        Type::I32 => Operator::I32Load { memory },
+        Type::I64 => Operator::I64Load { memory },
+        Type::F32 => Operator::F32Load { memory },
+        Type::F64 => Operator::F64Load { memory },
+        _ => unreachable!(),
+    };
+    add_op(body, block, op, &[addr], Some(ty))
+}
+
+fn store(
+    body: &mut FunctionBody,
+    block: Block,
+    memory: Memory,
+    addr: Value,
+    offset: u32,
+    value: Value,
+    ty: Type,
+) {
+    let memory = MemoryArg {
+        align: 0,
+        offset,
+        memory,
+    };
+    let op = match ty {
+        Type::I32 => Operator::I32Store { memory },
+        Type::I64 => Operator::I64Store { memory },
+        Type::F32 => Operator::F32Store { memory },
+        Type::F64 => Operator::F64Store { memory },
+        _ => unreachable!(),
+    };
+    add_op(body, block, op, &[addr, value], None);
+}
+
+fn br(block: Block, args: Vec<Value>) -> BlockTarget {
+    BlockTarget { block, args }
+}
+
+/// Move each call in `calls` into a block of its own (with any
+/// `PickOutput`s of its results), followed by the rest of its
+/// original block. Returns the new blocks.
+fn split_calls(calls: &HashSet<Value>, body: &mut FunctionBody) -> Vec<Block> {
+    let mut call_blocks = vec![];
+    for mut block in body.blocks.iter().collect::<Vec<_>>() {
+        let mut i = 0;
+        while i < body.blocks[block].insts.len() {
+            let inst = body.blocks[block].insts[i];
+            if !calls.contains(&inst) {
+                i += 1;
+                continue;
+            }
+            let call_block = if i == 0 {
+                block
+            } else {
+                body.split_block(block, i)
+            };
+            let rest = body.split_block(call_block, 1);
+            let picks = body.blocks[rest]
+                .insts
+                .iter()
+                .copied()
+                .filter(|&value| matches!(body.values[value], ValueDef::PickOutput(call, ..) if call == inst))
+                .collect::<Vec<_>>();
+            body.blocks[rest]
+                .insts
+                .retain(|value| !picks.contains(value));
+            for pick in picks {
+                body.append_to_block(call_block, pick);
+            }
+            call_blocks.push(call_block);
+            block = rest;
+            i = 0;
+        }
+    }
+    call_blocks
+}
+
+fn instrument(state: State, calls: &HashSet<Value>, body: &mut FunctionBody) -> Result<()> {
+    let call_blocks = split_calls(calls, body);
+    body.recompute_edges();
+    body.convert_to_max_ssa(Some(call_blocks.iter().copied().collect()));
+
+    // The values live across each call, with their offsets in its
+    // frame; the index of the call follows them.
+    let mut frames = vec![];
+    for &call_block in &call_blocks {
+        let mut offset = 0;
+        let mut frame = vec![];
+        for &(ty, param) in &body.blocks[call_block].params {
+            if !storable(ty) {
+                bail!("a {} is live across a call that can unwind", ty);
+            }
+            frame.push((ty, param, offset));
+            offset += type_size(ty);
+        }
+        frames.push((frame, offset));
+    }
+    let memory = state.memory;
+
+    let trap = body.add_block();
+    body.set_terminator(trap, Terminator::Unreachable);
+
+    // Unwind after each call if the callee started to.
+    let rets = body.rets.clone();
+    for (index, (&call_block, (frame, size))) in call_blocks.iter().zip(frames.iter()).enumerate() {
+        let rest = std::mem::take(&mut body.blocks[call_block].terminator);
+        let unwinding = global_is(body, call_block, state.state, STATE_UNWINDING);
+        let unwind = body.add_block();
+        let rest = match rest {
+            Terminator::Br { target } => target,
+            _ => unreachable!(),
+        };
+        body.blocks[call_block].terminator = Terminator::CondBr {
+            cond: unwinding,
+            if_true: br(unwind, vec![]),
+            if_false: rest,
+        };
+
+        let data = global_get(body, unwind, state.data);
+        let pos = load(body, unwind, memory, data, 0, Type::I32);
+        let end = load(body, unwind, memory, data, 4, Type::I32);
+        let frame_size = i32_const(body, unwind, size + 4);
+        let new_pos = add_op(
+            body,
+            unwind,
+            Operator::I32Add,
+            &[pos, frame_size],
+            Some(Type::I32),
+        );
+        let overflow = add_op(
+            body,
+            unwind,
+            Operator::I32GtU,
+            &[new_pos, end],
+            Some(Type::I32),
+        );
+        let save = body.add_block();
+        body.set_terminator(
+            unwind,
+            Terminator::CondBr {
+                cond: overflow,
+                if_true: br(trap, vec![]),
+                if_false: br(save, vec![]),
+            },
+        );
+        for &(ty, value, offset) in frame {
+            store(body, save, memory, pos, offset, value, ty);
+        }
+        let index = i32_const(body, save, index as u32);
+        store(body, save, memory, pos, *size, index, Type::I32);
+        store(body, save, memory, data, 0, new_pos, Type::I32);
+        let values = rets.iter().map(|&ty| zero(body, save, ty)).collect();
+        body.set_terminator(save, Terminator::Return { values });
+    }
+
+    // Rewind on entry, from a new entry block in case the old one is
+    // also a loop header.
+    let old_entry = body.entry;
+    let entry = body.add_block();
+    let params = body.blocks[old_entry].params.clone();
+    let args = params
+        .into_iter()
+        .map(|(ty, param)| {
+            let arg = body.add_blockparam(entry, ty);
+            body.value_locals[arg] = body.value_locals[param];
+            arg
+        })
+        .collect::<Vec<_>>();
+    let rewinding = global_is(body, entry, state.state, STATE_REWINDING);
+    let rewind = body.add_block();
+    body.set_terminator(
+        entry,
+        Terminator::CondBr {
+            cond: rewinding,
+            if_true: br(rewind, vec![]),
+            if_false: br(old_entry, args),
+        },
+    );
+    body.entry = entry;
+
+    let data = global_get(body, rewind, state.data);
+    let pos = load(body, rewind, memory, data, 0, Type::I32);
+    let four = i32_const(body, rewind, 4);
+    let index_addr = add_op(
+        body,
+        rewind,
+        Operator::I32Sub,
+        &[pos, four],
+        Some(Type::I32),
+    );
+    let index = load(body, rewind, memory, index_addr, 0, Type::I32);
+    let mut targets = vec![];
+    for (&call_block, (frame, size)) in call_blocks.iter().zip(frames.iter()) {
+        let restore = body.add_block();
+        let size = i32_const(body, restore, *size);
+        let frame_addr = add_op(
+            body,
+            restore,
+            Operator::I32Sub,
+            &[index_addr, size],
+            Some(Type::I32),
+        );
+        let values = frame
+            .iter()
+            .map(|&(ty, _, offset)| load(body, restore, memory, frame_addr, offset, ty))
+            .collect();
+        store(body, restore, memory, data, 0, frame_addr, Type::I32);
+        body.set_terminator(
+            restore,
+            Terminator::Br {
+                target: br(call_block, values),
+            },
+        );
+        targets.push(br(restore, vec![]));
+    }
+    body.set_terminator(
+        rewind,
+        Terminator::Select {
+            value: index,
+            targets,
+            default: br(trap, vec![]),
+        },
+    );
+    body.recompute_edges();
+    Ok(())
+}
+
+/// Add and export the control functions.
+fn add_api(module: &mut Module, state: State) {
+    let data_param = SignatureData {
+        params: vec![Type::I32],
+        returns: vec![],
+    };
+    let no_params = SignatureData {
+        params: vec![],
+        returns: vec![],
+    };
+    let get_state = SignatureData {
+        params: vec![],
+        returns: vec![Type::I32],
+    };
+    let api: [(&str, SignatureData, Option<u32>); 5] = [
+        (
+            "asyncify_start_unwind",
+            data_param.clone(),
+            Some(STATE_UNWINDING),
+        ),
+        (
+            "asyncify_stop_unwind",
+            no_params.clone(),
+            Some(STATE_NORMAL),
+        ),
+        ("asyncify_start_rewind", data_param, Some(STATE_REWINDING)),
+        ("asyncify_stop_rewind", no_params, Some(STATE_NORMAL)),
+        ("asyncify_get_state", get_state, None),
+    ];
+    for (name, sig, new_state) in api {
+        let sig = module.find_or_add_signature(sig);
+        let mut body = FunctionBody::new(module, sig);
+        let entry = body.entry;
+        let terminator = match new_state {
+            None => {
+                let state = global_get(&mut body, entry, state.state);
+                Terminator::Return {
+                    values: vec![state],
+                }
+            }
+            Some(new_state) => {
+                let new_state = i32_const(&mut body, entry, new_state);
+                global_set(&mut body, entry, state.state, new_state);
+                if let Some(&(_, data)) = body.blocks[entry].params.first() {
+                    global_set(&mut body, entry, state.data, data);
+                }
+                Terminator::Return { values: vec![] }
+            }
+        };
+
+        // Check that the buffer has not overflowed, except when
+        // rewinding has finished and the buffer is no longer in use.
+        if name != "asyncify_stop_rewind" && new_state.is_some() {
+            let data = global_get(&mut body, entry, state.data);
+            let pos = load(&mut body, entry, state.memory, data, 0, Type::I32);
+            let end = load(&mut body, entry, state.memory, data, 4, Type::I32);
+            let overflow = add_op(
+                &mut body,
+                entry,
+                Operator::I32GtU,
+                &[pos, end],
+                Some(Type::I32),
+            );
+            let ok = body.add_block();
+            let trap = body.add_block();
+            body.set_terminator(trap, Terminator::Unreachable);
+            body.set_terminator(ok, terminator);
+            body.set_terminator(
+                entry,
+                Terminator::CondBr {
+                    cond: overflow,
+                    if_true: br(trap, vec![]),
+                    if_false: br(ok, vec![]),
+                },
+            );
+        } else {
+            body.set_terminator(entry, terminator);
+        }
+
+        let func = module
+            .funcs
+            .push(FuncDecl::Body(sig, name.to_owned(), Box::new(body)));
+        module.exports.push(Export {
+            name: name.to_owned(),
+            kind: ExportKind::Func(func),
+        });
+    }
+}
@@ -0,0 +1,378 @@
+//! Coverage instrumentation for coverage-guided fuzzing without
+//! engine support.
+//!
+//! Every coverage site (each reachable block, or each CFG edge) gets
+//! a counter in a region of linear memory, and the site increments
+//! its counter whenever it runs. By default the region is in a new
+//! memory, so the program sees its own memories unchanged. Edges are
+//! counted at the start of their target if it has no other
+//! predecessors, at the end of their source if it has no other
+//! successors, and otherwise in a new block split into the edge. Counters are SanitizerCoverage-style 8-bit
+//! counters, which wrap on overflow, or single bits of a bitmap.
+//!
+//! Right after the counters, the region holds a table that maps each
+//! site to its function and IR block: one entry of three
+//! little-endian `u32`s per site, `(func, block, pred)`, where `pred`
+//! is the source block of an edge or `u32::MAX` for a block site. The
+//! memory and the layout of the region are exported, as
+//! `__coverage_memory` and the immutable `i32` globals
+//! `__coverage_counters` (address), `__coverage_counters_len` (in
+//! bytes), `__coverage_table` (address), and `__coverage_table_len`
+//! (in entries). Function and block indices are those after
+//! instrumentation; passes that renumber functions should run first.
+
+use crate::cfg::CFGInfo;
+use crate::entity::EntityRef;
+use crate::ir::{
+    Block, Export, ExportKind, Func, FunctionBody, GlobalData, ImportKind, Memory, MemoryData,
+    MemorySegment, Module, Type, Value, ValueDef,
+};
+use crate::ops::MemoryArg;
+use crate::pool::ListRef;
+use crate::Operator;
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+
+const PAGE_SIZE: usize = 65536;
+/// Bytes per entry of the site table.
+const TABLE_ENTRY_SIZE: usize = 12;
+
+/// What to count.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CoverageSites {
+    /// Executions of each reachable block.
+    #[default]
+    Blocks,
+    /// Traversals of each CFG edge, plus function entries.
+    Edges,
+}
+
+/// How to count.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CounterKind {
+    /// One wrapping 8-bit counter per site.
+    #[default]
+    Counters8,
+    /// One bit per site, set when the site first runs.
+    Bitmap,
+}
+
+/// Where to put the counters and site table.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CounterRegion {
+    /// A new memory, leaving the program's own memories untouched. If
+    /// the module already has a memory, the result requires
+    /// multi-memory support.
+    #[default]
+    NewMemory,
+    /// New pages added to the initial (and maximum) size of a memory
+    /// the module defines. The program sees a larger memory, through
+    /// `memory.size` and accesses past its old end, and may overwrite
+    /// the counters if it grows the memory itself.
+    Append(Memory),
+    /// A given address in a memory, which the caller guarantees is
+    /// otherwise unused and zero at instantiation.
+    At(Memory, u32),
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CoverageOptions {
+    pub sites: CoverageSites,
+    pub counters: CounterKind,
+    pub region: CounterRegion,
+}
+
+/// One coverage site: a block, or an edge from `pred` to `block`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CoverageSite {
+    pub func: Func,
+    pub block: Block,
+    pub pred: Option<Block>,
+}
+
+/// The layout of the instrumented module's counter region. The
+/// counter (or bit) for `sites[i]` is at index `i`.
+#[derive(Clone, Debug)]
+pub struct CoverageMap {
+    pub memory: Memory,
+    pub counters: u32,
+    pub counters_len: u32,
+    pub table: u32,
+    pub sites: Vec<CoverageSite>,
+}
+
+/// Where in the instrumented body a site's counter is updated.
+struct Placement {
+    site: CoverageSite,
+    block: Block,
+    at_end: bool,
+}
+
+pub fn run(module: &mut Module, options: &CoverageOptions) -> Result<CoverageMap> {
+    for export in &module.exports {
+        if export.name.starts_with("__coverage_") {
+            bail!("Module already exports '{}'", export.name);
+        }
+    }
+    module.expand_all_funcs()?;
+
+    let placements = module
+        .funcs
+        .entries_mut()
+        .filter_map(|(func, decl)| decl.body_mut().map(|body| (func, body)))
+        .collect::<Vec<_>>()
+        .par_iter_mut()
+        .map(|(func, body)| {
+            let placements = match options.sites {
+                CoverageSites::Blocks => block_sites(*func, body),
+                CoverageSites::Edges => edge_sites(*func, body),
+            };
+            (*func, placements)
+        })
+        .collect::<Vec<_>>();
+
+    let n_sites = placements.iter().map(|(_, p)| p.len()).sum::<usize>();
+    let counters_len = match options.counters {
+        CounterKind::Counters8 => n_sites,
+        CounterKind::Bitmap => n_sites.div_ceil(8),
+    };
+    let table_offset = (counters_len + 3) & !3;
+    let region_len = table_offset + n_sites * TABLE_ENTRY_SIZE;
+    let (memory, base) = allocate_region(module, options.region, region_len)?;
+    let table = base + table_offset;
+
+    let mut index = 0;
+    for (func, func_placements) in &placements {
+        let body = module.funcs[*func].body_mut().unwrap();
+        for placement in func_placements {
+            let (address, bit) = match options.counters {
+                CounterKind::Counters8 => (base + index, None),
+                CounterKind::Bitmap => (base + index / 8, Some(1 << (index % 8))),
+            };
+            increment(body, placement, memory, address as u32, bit);
+            index += 1;
+        }
+    }
+
+    let sites = placements
+        .into_iter()
+        .flat_map(|(_, func_placements)| func_placements.into_iter().map(|p| p.site))
+        .collect::<Vec<_>>();
+    let mut table_data = Vec::with_capacity(sites.len() * TABLE_ENTRY_SIZE);
+    for site in &sites {
+        let pred = site
+            .pred
+            .map(|pred| pred.index() as u32)
+            .unwrap_or(u32::MAX);
+        for word in [site.func.index() as u32, site.block.index() as u32, pred] {
+            table_data.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+    if !table_data.is_empty() {
+        let segments = &mut module.memories[memory].segments;
+        module
+            .segment_names
+            .insert((memory, segments.len()), "__coverage_table".to_owned());
+        segments.push(MemorySegment {
+            offset: table,
+            data: table_data,
+        });
+    }
+
+    module.exports.push(Export {
+        name: "__coverage_memory".to_owned(),
+        kind: ExportKind::Memory(memory),
+    });
+    for (name, value) in [
+        ("__coverage_counters", base),
+        ("__coverage_counters_len", counters_len),
+        ("__coverage_table", table),
+        ("__coverage_table_len", sites.len()),
+    ] {
+        let global = module.globals.push(GlobalData {
+            ty: Type::I32,
+            value: Some(value as u64),
+            mutable: false,
+        });
+        module.global_names[global] = Some(name.to_owned());
+        module.exports.push(Export {
+            name: name.to_owned(),
+            kind: ExportKind::Global(global),
+        });
+    }
+
+    Ok(CoverageMap {
+        memory,
+        counters: base as u32,
+        counters_len: counters_len as u32,
+        table: table as u32,
+        sites,
+    })
+}
+
+/// Find or make room for `len` bytes of counters and table. Returns
+/// the memory and the region's address.
+fn allocate_region(
+    module: &mut Module,
+    region: CounterRegion,
+    len: usize,
+) -> Result<(Memory, usize)> {
+    let imported = |module: &Module, memory: Memory| {
+        module
+            .imports
+            .iter()
+            .any(|import| import.kind == ImportKind::Memory(memory))
+    };
+    let pages = len.div_ceil(PAGE_SIZE);
+    match region {
+        CounterRegion::NewMemory => {
+            let memory = module.memories.push(MemoryData {
+                initial_pages: pages,
+                maximum_pages: Some(pages),
+                segments: vec![],
+            });
+            module.memory_names[memory] = Some("__coverage_memory".to_owned());
+            Ok((memory, 0))
+        }
+        CounterRegion::Append(memory) => {
+            if memory.index() >= module.memories.len() || imported(module, memory) {
+                bail!("Cannot append coverage counters to {}", memory);
+            }
+            let data = &mut module.memories[memory];
+            let base = data.initial_pages * PAGE_SIZE;
+            data.initial_pages += pages;
+            if let Some(max) = &mut data.maximum_pages {
+                *max += pages;
+            }
+            if base + len > u32::MAX as usize {
+                bail!("Coverage counters do not fit in {}", memory);
+            }
+            Ok((memory, base))
+        }
+        CounterRegion::At(memory, address) => {
+            if memory.index() >= module.memories.len() {
+                bail!("No memory {} for coverage counters", memory);
+            }
+            let base = address as usize;
+            if base + len > u32::MAX as usize {
+                bail!("Coverage counters do not fit in {}", memory);
+            }
+            Ok((memory, base))
+        }
+    }
+}
+
+/// One site at the start of each reachable block.
+fn block_sites(func: Func, body: &FunctionBody) -> Vec<Placement> {
+    let cfg = CFGInfo::new(body);
+    cfg.rpo
+        .values()
+        .map(|&block| Placement {
+            site: CoverageSite {
+                func,
+                block,
+                pred: None,
+            },
+            block,
+            at_end: false,
+        })
+        .collect()
+}
+
+/// One site for the entry and for each edge between reachable
+/// blocks, splitting critical edges.
+fn edge_sites(func: Func, body: &mut FunctionBody) -> Vec<Placement> {
+    body.recompute_edges();
+    let cfg = CFGInfo::new(body);
+    let mut placements = vec![Placement {
+        site: CoverageSite {
+            func,
+            block: body.entry,
+            pred: None,
+        },
+        block: body.entry,
+        at_end: false,
+    }];
+    for &from in cfg.rpo.values() {
+        for succ_idx in 0..body.blocks[from].succs.len() {
+            let to = body.blocks[from].succs[succ_idx];
+            let site = CoverageSite {
+                func,
+                block: to,
+                pred: Some(from),
+            };
+            let placement = if body.blocks[to].preds.len() == 1 && to != body.entry {
+                Placement {
+                    site,
+                    block: to,
+                    at_end: false,
+                }
+            } else if body.blocks[from].succs.len() == 1 {
+                Placement {
+                    site,
+                    block: from,
+                    at_end: true,
+                }
+            } else {
+                let edge_block = body.split_edge(from, to, succ_idx);
+                Placement {
+                    site,
+                    block: edge_block,
+                    at_end: false,
+                }
+            };
+            placements.push(placement);
+        }
+    }
+    placements
+}
+
+fn add_op(body: &mut FunctionBody, block: Block, op: Operator, args: &[Value]) -> Value {
+    let args = body.arg_pool.from_iter(args.iter().copied());
+    let tys = match op {
+        Operator::I32Store8 { .. } => ListRef::default(),
+        _ => body.single_type_list(Type::I32),
+    };
+    let value = body.add_value(ValueDef::Operator(op, args, tys));
+    body.value_blocks[value] = block;
+    value
+}
+
+/// Insert the update of one counter (or, with `bit`, one bit of the
+/// bitmap) at `address`.
+fn increment(
+    body: &mut FunctionBody,
+    placement: &Placement,
+    memory: Memory,
+    address: u32,
+    bit: Option<u32>,
+) {
+    let block = placement.block;
+    let mem = MemoryArg {
+        align: 0,
+        offset: address,
+        memory,
+    };
+    let zero = add_op(body, block, Operator::I32Const { value: 0 }, &[]);
+    let old = add_op(body, block, Operator::I32Load8U { memory: mem }, &[zero]);
+    let (value, op) = match bit {
+        None => (1, Operator::I32Add),
+        Some(bit) => (bit, Operator::I32Or),
+    };
+    let value = add_op(body, block, Operator::I32Const { value }, &[]);
+    let new = add_op(body, block, op, &[old, value]);
+    let store = add_op(
+        body,
+        block,
+        Operator::I32Store8 { memory: mem },
+        &[zero, new],
+    );
+
+    let insts = [zero, old, value, new, store];
+    let block_insts = &mut body.blocks[block].insts;
+    if placement.at_end {
+        block_insts.extend(insts);
+    } else {
+        block_insts.splice(0..0, insts);
+    }
+}
@@ -0,0 +1,412 @@
+//! Devirtualization of `call_indirect` through immutable tables.
+//!
+//! A table is immutable if it is neither imported nor exported and
+//! no function writes to it (`table.set`, `table.grow`, etc.), so its
+//! contents are exactly its element segments. At each indirect call
+//! through such a table, we compute the set of indices the callee
+//! operand may take (a constant, a small masked or modulo range, a
+//! select or blockparam over such values, or every index if the
+//! table is small). If every index in a set computed from the operand
+//! reaches the same function, the call becomes a direct call; the set
+//! of every index does not rule out an out-of-bounds one, so it always
+//! gets a switch. If there are a few possible callees,
+//! the call becomes a switch over the index with one direct call per
+//! callee; indices that would trap (out of bounds, null entry, or
+//! signature mismatch) go to the original `call_indirect`, which
+//! traps exactly as before.
+
+use crate::entity::{EntityRef, EntityVec};
+use crate::ir::{
+    Block, BlockTarget, EntityUse, ExportKind, Func, FuncDecl, FunctionBody, ImportKind, Module,
+    Signature, SignatureData, Table, Terminator, Value, ValueDef,
+};
+use crate::Operator;
+use anyhow::Result;
+use rayon::prelude::*;
+
+/// Largest index set we track at a call site, and the largest index
+/// we switch on.
+const MAX_INDICES: usize = 16;
+/// Largest number of distinct callees we switch between.
+const MAX_TARGETS: usize = 4;
+/// How far to look through selects and blockparams.
+const MAX_DEPTH: usize = 4;
+
+struct Context {
+    /// Elements of each immutable table; `None` if the table may
+    /// change at runtime.
+    tables: EntityVec<Table, Option<Vec<Func>>>,
+    func_sigs: EntityVec<Func, Signature>,
+    signatures: EntityVec<Signature, SignatureData>,
+}
+
+impl Context {
+    /// The function called by `call_indirect` with signature `sig`
+    /// at table index `index`, or `None` if the call traps.
+    fn resolve(&self, elts: &[Func], sig: Signature, index: u32) -> Option<Func> {
+        let func = *elts.get(index as usize)?;
+        if func.is_invalid() || self.func_sigs[func].is_invalid() {
+            return None;
+        }
+        if self.signatures[self.func_sigs[func]] != self.signatures[sig] {
+            return None;
+        }
+        Some(func)
+    }
+}
+
+pub(crate) struct Site {
+    pub block: Block,
+    pub pos: usize,
+    pub inst: Value,
+    /// Each possible index and the function it calls, if any.
+    pub targets: Vec<(u32, Option<Func>)>,
+}
+
+pub fn run(module: &mut Module) -> Result<()> {
+    let mut mutable = vec![false; module.tables.len()];
+    for import in &module.imports {
+        if let ImportKind::Table(table) = import.kind {
+            mutable[table.index()] = true;
+        }
+    }
+    for export in &module.exports {
+        if let ExportKind::Table(table) = export.kind {
+            mutable[table.index()] = true;
+        }
+    }
+    for decl in module.funcs.values() {
+        decl.visit_uses(|u| {
+            if let EntityUse::TableWrite(table) = u {
+                mutable[table.index()] = true;
+            }
+        })?;
+    }
+
+    let ctx = Context {
+        tables: EntityVec::from(
+            module
+                .tables
+                .entries()
+                .map(|(table, data)| {
+                    if mutable[table.index()] {
+                        None
+                    } else {
+                        data.func_elements.clone()
+                    }
+                })
+                .collect::<Vec<_>>(),
+        ),
+        func_sigs: EntityVec::from(
+            module
+                .funcs
+                .values()
+                .map(|decl| match decl {
+                    FuncDecl::None => Signature::invalid(),
+                    decl => decl.sig(),
+                })
+                .collect::<Vec<_>>(),
+        ),
+        signatures: module.signatures.clone(),
+    };
+    if ctx.tables.values().all(|elts| elts.is_none()) {
+        return Ok(());
+    }
+
+    module
+        .funcs
+        .values_mut()
+        .filter_map(|decl| decl.body_mut())
+        .collect::<Vec<_>>()
+        .par_iter_mut()
+        .for_each(|body| devirtualize(&ctx, body));
+    Ok(())
+}
+
+fn union(mut a: Vec<u32>, b: Vec<u32>) -> Option<Vec<u32>> {
+    a.extend(b);
+    a.sort_unstable();
+    a.dedup();
+    if a.len() > MAX_INDICES {
+        None
+    } else {
+        Some(a)
+    }
+}
+
+fn const_i32(body: &FunctionBody, value: Value) -> Option<u32> {
+    match &body.values[body.resolve_alias(value)] {
+        &ValueDef::Operator(Operator::I32Const { value }, _, _) => Some(value),
+        _ => None,
+    }
+}
+
+/// A superset of the values that `value` may take, if small.
+fn index_candidates(body: &FunctionBody, value: Value, depth: usize) -> Option<Vec<u32>> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let value = body.resolve_alias(value);
+    match &body.values[value] {
+        &ValueDef::Operator(Operator::I32Const { value }, _, _) => Some(vec![value]),
+        &ValueDef::Operator(Operator::I32And, args, _) => {
+            let args = &body.arg_pool[args];
+            let mask = const_i32(body, args[0]).or_else(|| const_i32(body, args[1]))?;
+            if (mask as usize) < MAX_INDICES {
+                Some((0..=mask).collect())
+            } else {
+                None
+            }
+        }
+        &ValueDef::Operator(Operator::I32RemU, args, _) => {
+            let modulus = const_i32(body, body.arg_pool[args][1])?;
+            if modulus > 0 && (modulus as usize) <= MAX_INDICES {
+                Some((0..modulus).collect())
+            } else {
+                None
+            }
+        }
+        &ValueDef::Operator(Operator::Select, args, _)
+        | &ValueDef::Operator(Operator::TypedSelect { .. }, args, _) => {
+            let args = &body.arg_pool[args];
+            union(
+                index_candidates(body, args[0], depth + 1)?,
+                index_candidates(body, args[1], depth + 1)?,
+            )
+        }
+        &ValueDef::BlockParam(block, index, _) => {
+            if block == body.entry || body.blocks[block].preds.is_empty() {
+                return None;
+            }
+            let mut incoming = vec![];
+            for &pred in &body.blocks[block].preds {
+                body.blocks[pred].terminator.visit_targets(|target| {
+                    if target.block == block {
+                        incoming.push(target.args[index as usize]);
+                    }
+                });
+            }
+            let mut result = vec![];
+            for arg in incoming {
+                result = union(result, index_candidates(body, arg, depth + 1)?)?;
+            }
+            Some(result)
+        }
+        _ => None,
+    }
+}
+
+fn devirtualize(ctx: &Context, body: &mut FunctionBody) {
+    // Collect all sites before rewriting anything, since the index
+    // facts depend on the CFG as it stands.
+    let mut sites = vec![];
+    for (block, block_def) in body.blocks.entries() {
+        for (pos, &inst) in block_def.insts.iter().enumerate() {
+            let (sig, table, args) = match &body.values[inst] {
+                &ValueDef::Operator(
+                    Operator::CallIndirect {
+                        sig_index,
+                        table_index,
+                    },
+                    args,
+                    _,
+                ) => (sig_index, table_index, args),
+                _ => continue,
+            };
+            let elts = match &ctx.tables[table] {
+                Some(elts) => elts,
+                None => continue,
+            };
+            let index = *body.arg_pool[args].last().unwrap();
+            // Only a set computed from the operand proves that the
+            // index is in bounds.
+            let (candidates, proven) = match index_candidates(body, index, 0) {
+                Some(candidates) => (candidates, true),
+                None if elts.len() <= MAX_INDICES => ((0..elts.len() as u32).collect(), false),
+                None => continue,
+            };
+            let targets = candidates
+                .into_iter()
+                .map(|index| (index, ctx.resolve(elts, sig, index)))
+                .collect::<Vec<_>>();
+            let site = Site {
+                block,
+                pos,
+                inst,
+                targets,
+            };
+            sites.push((site, proven));
+        }
+    }
+
+    // Rewrite from the end of each block backward so that earlier
+    // sites' positions remain valid.
+    let mut changed = false;
+    for (site, proven) in sites.into_iter().rev() {
+        let mut callees = vec![];
+        for &(_, func) in &site.targets {
+            if let Some(func) = func {
+                if !callees.contains(&func) {
+                    callees.push(func);
+                }
+            }
+        }
+        let traps = site.targets.iter().any(|(_, func)| func.is_none());
+        if callees.is_empty() {
+            continue;
+        }
+        if callees.len() == 1 && !traps && proven {
+            log::trace!("devirt: {} calls {}", site.inst, callees[0]);
+            make_direct_call(body, site.inst, callees[0]);
+        } else if callees.len() <= MAX_TARGETS
+            && site
+                .targets
+                .iter()
+                .all(|&(index, _)| (index as usize) < MAX_INDICES)
+        {
+            log::trace!("devirt: {} switches over {:?}", site.inst, callees);
+            let args = match &body.values[site.inst] {
+                &ValueDef::Operator(_, args, _) => body.arg_pool[args].to_vec(),
+                _ => unreachable!(),
+            };
+            let (index, call_args) = args.split_last().unwrap();
+            make_switch(body, &site, *index, call_args, &callees[..]);
+            changed = true;
+        }
+    }
+    if changed {
+        body.recompute_edges();
+    }
+}
+
+fn make_direct_call(body: &mut FunctionBody, inst: Value, func: Func) {
+    let (args, tys) = match &body.values[inst] {
+        &ValueDef::Operator(_, args, tys) => (args, tys),
+        _ => unreachable!(),
+    };
+    let args = body.arg_pool[args].to_vec();
+    let args = body
+        .arg_pool
+        .from_iter(args[..args.len() - 1].iter().copied());
+    body.values[inst] = ValueDef::Operator(
+        Operator::Call {
+            function_index: func,
+        },
+        args,
+        tys,
+    );
+}
+
+/// Replace the call at `site` with a switch on `index` over direct
+/// calls to `callees` with `call_args`. Indices that are not targets
+/// of the site go to a copy of the original call.
+pub(crate) fn make_switch(
+    body: &mut FunctionBody,
+    site: &Site,
+    index: Value,
+    call_args: &[Value],
+    callees: &[Func],
+) {
+    let (op, args, tys) = match &body.values[site.inst] {
+        &ValueDef::Operator(op, args, tys) => (op, args, tys),
+        _ => unreachable!(),
+    };
+    let args = body.arg_pool[args].to_vec();
+    let ret_tys = body.type_pool[tys].to_vec();
+    let loc = body.source_locs[site.inst];
+    let orig_offset = body.orig_offsets[site.inst];
+
+    // Move everything after the call into a continuation block that
+    // receives the call's results as blockparams.
+    let cont = body.split_block(site.block, site.pos);
+    let call = body.blocks[cont].insts.remove(0);
+    debug_assert_eq!(call, site.inst);
+    let results = ret_tys
+        .iter()
+        .map(|&ty| body.add_blockparam(cont, ty))
+        .collect::<Vec<_>>();
+    if results.len() == 1 {
+        body.set_alias(site.inst, results[0]);
+    } else if results.len() > 1 {
+        for value in 0..body.values.len() {
+            let value = Value::new(value);
+            if let ValueDef::PickOutput(from, i, _) = body.values[value] {
+                if from == site.inst {
+                    let block = body.value_blocks[value];
+                    if block.is_valid() {
+                        body.blocks[block].insts.retain(|&inst| inst != value);
+                    }
+                    body.values[value] = ValueDef::Alias(results[i as usize]);
+                }
+            }
+        }
+    }
+
+    let add_call_block = |body: &mut FunctionBody, op: Operator, args: &[Value]| -> Block {
+        let block = body.add_block();
+        let args = body.arg_pool.from_iter(args.iter().copied());
+        let call = body.add_value(ValueDef::Operator(op, args, tys));
+        body.append_to_block(block, call);
+        body.source_locs[call] = loc;
+        body.orig_offsets[call] = orig_offset;
+        let outs = if ret_tys.len() == 1 {
+            vec![call]
+        } else {
+            ret_tys
+                .iter()
+                .enumerate()
+                .map(|(i, &ty)| {
+                    let out = body.add_value(ValueDef::PickOutput(call, i as u32, ty));
+                    body.append_to_block(block, out);
+                    out
+                })
+                .collect::<Vec<_>>()
+        };
+        body.blocks[block].terminator = Terminator::Br {
+            target: BlockTarget {
+                block: cont,
+                args: outs,
+            },
+        };
+        block
+    };
+
+    let callee_blocks = callees
+        .iter()
+        .map(|&func| {
+            add_call_block(
+                body,
+                Operator::Call {
+                    function_index: func,
+                },
+                call_args,
+            )
+        })
+        .collect::<Vec<_>>();
+    let fallback = add_call_block(body, op, &args[..]);
+
+    let max_index = site.targets.iter().map(|&(index, _)| index).max().unwrap();
+    let targets = (0..=max_index)
+        .map(|index| {
+            let block = site
+                .targets
+                .iter()
+                .find(|&&(i, _)| i == index)
+                .and_then(|&(_, func)| func)
+                .map(|func| callee_blocks[callees.iter().position(|&f| f == func).unwrap()])
+                .unwrap_or(fallback);
+            BlockTarget {
+                block,
+                args: vec![],
+            }
+        })
+        .collect::<Vec<_>>();
+    body.blocks[site.block].terminator = Terminator::Select {
+        value: index,
+        targets,
+        default: BlockTarget {
+            block: fallback,
+            args: vec![],
+        },
+    };
+}
@@ -0,0 +1,291 @@
+//! Fuel metering: deterministic execution limits without engine
+//! support.
+//!
+//! A mutable `i64` fuel global is charged for the work each block
+//! does, as the sum of its operators' costs from a `CostTable` plus
+//! the cost of its terminator. Charges happen at block entry, but a
+//! block reached only by falling through from a block with no other
+//! successor is charged together with its predecessor, so straight
+//! lines of blocks are charged once. Every cycle in the CFG passes
+//! through a charged block, so every loop iteration costs fuel.
+//!
+//! When a charge takes the fuel below zero, execution either traps or
+//! calls the imported `waffle_fuel.out_of_fuel()` handler. A handler
+//! that returns (e.g. after refilling the fuel) resumes execution
+//! with the charged block.
+
+use crate::cfg::CFGInfo;
+use crate::entity::EntityRef;
+use crate::ir::{
+    Block, BlockTarget, Export, ExportKind, Func, FunctionBody, Global, GlobalData, Module,
+    SignatureData, Terminator, Type, Value, ValueDef,
+};
+use crate::pool::ListRef;
+use crate::Operator;
+use anyhow::{bail, Result};
+use fxhash::FxHashMap;
+use rayon::prelude::*;
+use std::mem::Discriminant;
+
+/// Fuel cost of each kind of operator, ignoring immediates.
+#[derive(Clone, Debug)]
+pub struct CostTable {
+    costs: FxHashMap<Discriminant<Operator>, u64>,
+    /// Cost of an operator with no entry in the table.
+    pub default: u64,
+    /// Cost of a block's terminator. Nonzero, so that empty loops
+    /// also consume fuel.
+    pub terminator: u64,
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        CostTable {
+            costs: FxHashMap::default(),
+            default: 1,
+            terminator: 1,
+        }
+    }
+}
+
+impl CostTable {
+    /// Set the cost of every operator of the same kind as `op`.
+    pub fn set(&mut self, op: &Operator, cost: u64) {
+        self.costs.insert(std::mem::discriminant(op), cost);
+    }
+
+    pub fn cost(&self, op: &Operator) -> u64 {
+        self.costs
+            .get(&std::mem::discriminant(op))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Where the fuel lives.
+#[derive(Clone, Debug)]
+pub enum FuelGlobal {
+    /// A new global, exported as `fuel`, with the given initial fuel.
+    New { initial: i64 },
+    /// A global imported from the given module and name.
+    Import { module: String, name: String },
+}
+
+/// What happens when the fuel runs out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutOfFuel {
+    #[default]
+    Trap,
+    /// Call the imported `waffle_fuel.out_of_fuel()` handler.
+    Handler,
+}
+
+#[derive(Clone, Debug)]
+pub struct FuelOptions {
+    pub costs: CostTable,
+    pub global: FuelGlobal,
+    pub out_of_fuel: OutOfFuel,
+}
+
+impl Default for FuelOptions {
+    fn default() -> Self {
+        FuelOptions {
+            costs: CostTable::default(),
+            global: FuelGlobal::New { initial: i64::MAX },
+            out_of_fuel: OutOfFuel::Trap,
+        }
+    }
+}
+
+/// Instrument every function body. Returns the fuel global.
+pub fn run(module: &mut Module, options: &FuelOptions) -> Result<Global> {
+    module.expand_all_funcs()?;
+
+    let fuel = match &options.global {
+        FuelGlobal::New { initial } => {
+            if module.exports.iter().any(|export| export.name == "fuel") {
+                bail!("Module already exports 'fuel'");
+            }
+            let global = module.globals.push(GlobalData {
+                ty: Type::I64,
+                value: Some(*initial as u64),
+                mutable: true,
+            });
+            module.global_names[global] = Some("fuel".to_owned());
+            module.exports.push(Export {
+                name: "fuel".to_owned(),
+                kind: ExportKind::Global(global),
+            });
+            global
+        }
+        FuelGlobal::Import { module: from, name } => {
+            let global = module.add_global_import(
+                from,
+                name,
+                GlobalData {
+                    ty: Type::I64,
+                    value: None,
+                    mutable: true,
+                },
+            )?;
+            module.global_names[global] = Some(name.clone());
+            global
+        }
+    };
+
+    let handler = match options.out_of_fuel {
+        OutOfFuel::Trap => None,
+        OutOfFuel::Handler => {
+            let sig = module.find_or_add_signature(SignatureData {
+                params: vec![],
+                returns: vec![],
+            });
+            Some(module.add_func_imports(&[("waffle_fuel", "out_of_fuel", sig)])?[0])
+        }
+    };
+
+    module
+        .funcs
+        .values_mut()
+        .filter_map(|decl| decl.body_mut())
+        .collect::<Vec<_>>()
+        .par_iter_mut()
+        .for_each(|body| instrument(&options.costs, fuel, handler, body));
+    Ok(fuel)
+}
+
+/// The fuel each reachable block charges on entry, for the blocks
+/// that charge at all.
+fn charges(costs: &CostTable, body: &FunctionBody) -> Vec<(Block, u64)> {
+    let cfg = CFGInfo::new(body);
+    let mut charged_by = vec![Block::invalid(); body.blocks.len()];
+    let mut charges: Vec<(Block, u64)> = vec![];
+    let mut charge_index = vec![0; body.blocks.len()];
+    for &block in cfg.rpo.values() {
+        let data = &body.blocks[block];
+        let cost = data
+            .insts
+            .iter()
+            .map(|&inst| match &body.values[inst] {
+                ValueDef::Operator(op, ..) => costs.cost(op),
+                _ => 0,
+            })
+            .sum::<u64>()
+            + costs.terminator;
+
+        let straight_line = block != body.entry
+            && data.preds.len() == 1
+            && body.blocks[data.preds[0]].succs.len() == 1
+            && charged_by[data.preds[0].index()].is_valid();
+        let charger = if straight_line {
+            charged_by[data.preds[0].index()]
+        } else {
+            charge_index[block.index()] = charges.len();
+            charges.push((block, 0));
+            block
+        };
+        charged_by[block.index()] = charger;
+        let (_, total) = &mut charges[charge_index[charger.index()]];
+        *total = total.saturating_add(cost);
+    }
+    charges
+}
+
+fn add_op(
+    body: &mut FunctionBody,
+    block: Block,
+    op: Operator,
+    args: &[Value],
+    ty: Option<Type>,
+) -> Value {
+    let args = body.arg_pool.from_iter(args.iter().copied());
+    let tys = match ty {
+        Some(ty) => body.single_type_list(ty),
+        None => ListRef::default(),
+    };
+    let value = body.add_value(ValueDef::Operator(op, args, tys));
+    body.append_to_block(block, value);
+    value
+}
+
+fn instrument(costs: &CostTable, fuel: Global, handler: Option<Func>, body: &mut FunctionBody) {
+    body.recompute_edges();
+    let charges = charges(costs, body);
+
+    let mut trap_block = None;
+    for (block, cost) in charges {
+        // The charge and check run in `block`; its contents move to
+        // `rest`.
+        let rest = body.split_block(block, 0);
+        let global_index = fuel;
+        let old = add_op(
+            body,
+            block,
+            Operator::GlobalGet { global_index },
+            &[],
+            Some(Type::I64),
+        );
+        let cost = add_op(
+            body,
+            block,
+            Operator::I64Const { value: cost },
+            &[],
+            Some(Type::I64),
+        );
+        let new = add_op(body, block, Operator::I64Sub, &[old, cost], Some(Type::I64));
+        add_op(
+            body,
+            block,
+            Operator::GlobalSet { global_index },
+            &[new],
+            None,
+        );
+        let zero = add_op(
+            body,
+            block,
+            Operator::I64Const { value: 0 },
+            &[],
+            Some(Type::I64),
+        );
+        let exhausted = add_op(body, block, Operator::I64LtS, &[new, zero], Some(Type::I32));
+
+        let out_of_fuel = match handler {
+            Some(handler) => {
+                let out_of_fuel = body.add_block();
+                add_op(
+                    body,
+                    out_of_fuel,
+                    Operator::Call {
+                        function_index: handler,
+                    },
+                    &[],
+                    None,
+                );
+                body.blocks[out_of_fuel].terminator = Terminator::Br {
+                    target: BlockTarget {
+                        block: rest,
+                        args: vec![],
+                    },
+                };
+                out_of_fuel
+            }
+            None => *trap_block.get_or_insert_with(|| {
+                let trap = body.add_block();
+                body.blocks[trap].terminator = Terminator::Unreachable;
+                trap
+            }),
+        };
+        body.blocks[block].terminator = Terminator::CondBr {
+            cond: exhausted,
+            if_true: BlockTarget {
+                block: out_of_fuel,
+                args: vec![],
+            },
+            if_false: BlockTarget {
+                block: rest,
+                args: vec![],
+            },
+        };
+    }
+    body.recompute_edges();
+}
@@ -0,0 +1,141 @@
+//! Dead function, global, and table-entry elimination.
+//!
+//! Computes reachability from the module's exports, its start
+//! function, and the elements of every table that is live, then
+//! removes unreachable functions, globals, imports, and signatures
+//! and renumbers the remaining entities. Lazy bodies are scanned
+//! without being expanded, and are only expanded if the indices they
+//! refer to change. Expanded bodies are scanned as IR, which omits
+//! unreachable code; one that is still clean is recompiled if its
+//! original bytes refer to anything removed or renumbered.
+
+use crate::entity::{EntityRef, EntityVec};
+use crate::ir::{EntityRemap, EntityUse, ExportKind, Func, ImportKind, Module};
+use anyhow::Result;
+
+fn compact<Idx: EntityRef + std::fmt::Debug>(live: &[bool]) -> EntityVec<Idx, Idx> {
+    let mut next = 0;
+    EntityVec::from(
+        live.iter()
+            .map(|&live| {
+                if live {
+                    next += 1;
+                    Idx::new(next - 1)
+                } else {
+                    Idx::invalid()
+                }
+            })
+            .collect::<Vec<_>>(),
+    )
+}
+
+pub fn run(module: &mut Module) -> Result<()> {
+    let mut live_funcs = vec![false; module.funcs.len()];
+    let mut live_sigs = vec![false; module.signatures.len()];
+    let mut live_globals = vec![false; module.globals.len()];
+    let mut live_tables = vec![false; module.tables.len()];
+
+    let mut func_worklist: Vec<Func> = vec![];
+    let mut mark_func = |func: Func, worklist: &mut Vec<Func>| {
+        if func.is_valid() && !live_funcs[func.index()] {
+            live_funcs[func.index()] = true;
+            worklist.push(func);
+        }
+    };
+    let mut table_worklist = vec![];
+
+    // Tables visible outside the module may be called through or
+    // modified by the host, so all of their elements are roots.
+    for import in &module.imports {
+        if let ImportKind::Table(table) = import.kind {
+            if !live_tables[table.index()] {
+                live_tables[table.index()] = true;
+                table_worklist.push(table);
+            }
+        }
+    }
+    for export in &module.exports {
+        match export.kind {
+            ExportKind::Func(func) => mark_func(func, &mut func_worklist),
+            ExportKind::Global(global) => live_globals[global.index()] = true,
+            ExportKind::Table(table) => {
+                if !live_tables[table.index()] {
+                    live_tables[table.index()] = true;
+                    table_worklist.push(table);
+                }
+            }
+            ExportKind::Memory(_) => {}
+        }
+    }
+    if let Some(start) = module.start_func {
+        mark_func(start, &mut func_worklist);
+    }
+
+    while !func_worklist.is_empty() || !table_worklist.is_empty() {
+        while let Some(table) = table_worklist.pop() {
+            if let Some(elts) = &module.tables[table].func_elements {
+                for &elt in elts {
+                    mark_func(elt, &mut func_worklist);
+                }
+            }
+        }
+        while let Some(func) = func_worklist.pop() {
+            let decl = &module.funcs[func];
+            live_sigs[decl.sig().index()] = true;
+            let mut uses = vec![];
+            decl.visit_uses(|u| uses.push(u))?;
+            for u in uses {
+                match u {
+                    EntityUse::Call(callee) | EntityUse::FuncRef(callee) => {
+                        mark_func(callee, &mut func_worklist)
+                    }
+                    EntityUse::CallIndirect(sig, table) => {
+                        live_sigs[sig.index()] = true;
+                        if !live_tables[table.index()] {
+                            live_tables[table.index()] = true;
+                            table_worklist.push(table);
+                        }
+                    }
+                    EntityUse::GlobalGet(global) | EntityUse::GlobalSet(global) => {
+                        live_globals[global.index()] = true
+                    }
+                    EntityUse::Table(table) | EntityUse::TableWrite(table) => {
+                        if !live_tables[table.index()] {
+                            live_tables[table.index()] = true;
+                            table_worklist.push(table);
+                        }
+                    }
+                    EntityUse::Signature(sig) => live_sigs[sig.index()] = true,
+                }
+            }
+        }
+    }
+
+    // Nothing can observe the contents of a dead table, so its
+    // elements are cleared rather than kept alive.
+    for (table, table_data) in module.tables.entries_mut() {
+        if !live_tables[table.index()] {
+            if let Some(elts) = &mut table_data.func_elements {
+                elts.iter_mut().for_each(|elt| *elt = Func::invalid());
+            }
+        }
+    }
+
+    log::debug!(
+        "gc: keeping {}/{} funcs, {}/{} signatures, {}/{} globals",
+        live_funcs.iter().filter(|&&l| l).count(),
+        live_funcs.len(),
+        live_sigs.iter().filter(|&&l| l).count(),
+        live_sigs.len(),
+        live_globals.iter().filter(|&&l| l).count(),
+        live_globals.len()
+    );
+
+    let remap = EntityRemap {
+        funcs: compact(&live_funcs),
+        signatures: compact(&live_sigs),
+        globals: compact(&live_globals),
+        tables: EntityRemap::identity(module).tables,
+    };
+    module.remap_entities(&remap)
+}
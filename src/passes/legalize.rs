@@ -0,0 +1,381 @@
+//! Legalization of the host interface for embedders that cannot pass
+//! `i64` values across it, such as JS without BigInt integration.
+//!
+//! Every exported function whose signature uses `i64` is exported
+//! through a wrapper instead, and every such imported function is
+//! imported with a legal signature and called through a thunk with
+//! the original one. In a legal signature, each `i64` parameter is a
+//! pair of `i32`s, low half first, and an `i64` result is its low
+//! half, with the high half passed in a global: a legalized export
+//! sets it before returning, and the host reads it by calling the
+//! exported `getTempRet0()`; a legalized import's host function sets
+//! it by calling the exported `setTempRet0(hi)` before returning.
+//!
+//! A function with more than one result, any of them an `i64`,
+//! cannot be legalized. Values inside bodies are left alone.
+
+use crate::ir::{
+    Block, Export, ExportKind, Func, FuncDecl, FunctionBody, Global, GlobalData, ImportKind,
+    Module, Signature, SignatureData, Terminator, Type, Value, ValueDef,
+};
+use crate::pool::ListRef;
+use crate::Operator;
+use anyhow::{bail, Result};
+use fxhash::FxHashMap;
+
+const GET_TEMP_RET: &str = "getTempRet0";
+const SET_TEMP_RET: &str = "setTempRet0";
+
+/// The legal form of `sig`, if it uses `i64`.
+fn legalize_sig(sig: &SignatureData) -> Result<Option<SignatureData>> {
+    let uses_i64 = |tys: &[Type]| tys.contains(&Type::I64);
+    if !uses_i64(&sig.params) && !uses_i64(&sig.returns) {
+        return Ok(None);
+    }
+    if sig.returns.len() > 1 && uses_i64(&sig.returns) {
+        bail!("Cannot legalize a signature with multiple results including i64");
+    }
+    let params = sig
+        .params
+        .iter()
+        .flat_map(|&ty| match ty {
+            Type::I64 => vec![Type::I32, Type::I32],
+            ty => vec![ty],
+        })
+        .collect();
+    let returns = sig
+        .returns
+        .iter()
+        .map(|&ty| if ty == Type::I64 { Type::I32 } else { ty })
+        .collect();
+    Ok(Some(SignatureData { params, returns }))
+}
+
+pub fn run(module: &mut Module) -> Result<()> {
+    for export in &module.exports {
+        if export.name == GET_TEMP_RET || export.name == SET_TEMP_RET {
+            bail!("Module already exports '{}'", export.name);
+        }
+    }
+    module.expand_all_funcs()?;
+
+    // Find what needs legalizing before changing anything.
+    let mut legal_sigs = FxHashMap::default();
+    let mut imports = vec![];
+    for import in &module.imports {
+        if let ImportKind::Func(func) = import.kind {
+            let sig = module.funcs[func].sig();
+            if let Some(legal) = legalize_sig(&module.signatures[sig])? {
+                legal_sigs.insert(sig, legal);
+                imports.push(func);
+            }
+        }
+    }
+    let mut exports = vec![];
+    for (i, export) in module.exports.iter().enumerate() {
+        if let ExportKind::Func(func) = export.kind {
+            let sig = module.funcs[func].sig();
+            if let Some(legal) = legalize_sig(&module.signatures[sig])? {
+                legal_sigs.insert(sig, legal);
+                exports.push(i);
+            }
+        }
+    }
+    if imports.is_empty() && exports.is_empty() {
+        return Ok(());
+    }
+
+    let temp_ret = module.globals.push(GlobalData {
+        ty: Type::I32,
+        value: Some(0),
+        mutable: true,
+    });
+    module.global_names[temp_ret] = Some("tempRet0".to_owned());
+    let legal_sigs = legal_sigs
+        .into_iter()
+        .map(|(sig, legal)| (sig, module.find_or_add_signature(legal)))
+        .collect::<FxHashMap<_, _>>();
+
+    // Import with the legal signature, and call through a thunk.
+    let n_funcs = module.funcs.len();
+    let mut thunks = FxHashMap::default();
+    for import in imports {
+        let sig = module.funcs[import].sig();
+        let name = module.funcs[import].name().to_owned();
+        let legal_sig = legal_sigs[&sig];
+        module.funcs[import] = FuncDecl::Import(legal_sig, name.clone());
+        let body = thunk(module, sig, import, temp_ret);
+        let thunk = module.funcs.push(FuncDecl::Body(
+            sig,
+            format!("{}$legalized", name),
+            Box::new(body),
+        ));
+        thunks.insert(import, thunk);
+    }
+    let redirect = |func: &mut Func| {
+        if let Some(&thunk) = thunks.get(func) {
+            *func = thunk;
+        }
+    };
+    for decl in module.funcs.values_mut().take(n_funcs) {
+        if let Some(body) = decl.body_mut() {
+            for value in body.values.values_mut() {
+                if let ValueDef::Operator(Operator::Call { function_index }, ..) = value {
+                    redirect(function_index);
+                }
+            }
+        }
+    }
+    for table in module.tables.values_mut() {
+        table.func_elements.iter_mut().flatten().for_each(redirect);
+    }
+    for export in &mut module.exports {
+        if let ExportKind::Func(func) = &mut export.kind {
+            redirect(func);
+        }
+    }
+
+    // Export through a wrapper.
+    let mut wrappers = FxHashMap::default();
+    for i in exports {
+        let func = match module.exports[i].kind {
+            ExportKind::Func(func) => func,
+            _ => unreachable!(),
+        };
+        let wrapper = match wrappers.get(&func) {
+            Some(&wrapper) => wrapper,
+            None => {
+                let sig = module.funcs[func].sig();
+                let legal_sig = legal_sigs[&sig];
+                let body = wrapper(module, legal_sig, sig, func, temp_ret);
+                let name = format!("{}$legalized", module.funcs[func].name());
+                let wrapper = module
+                    .funcs
+                    .push(FuncDecl::Body(legal_sig, name, Box::new(body)));
+                wrappers.insert(func, wrapper);
+                wrapper
+            }
+        };
+        module.exports[i].kind = ExportKind::Func(wrapper);
+    }
+
+    add_temp_ret_api(module, temp_ret);
+    Ok(())
+}
+
+fn add_op(
+    body: &mut FunctionBody,
+    block: Block,
+    op: Operator,
+    args: &[Value],
+    tys: &[Type],
+) -> Value {
+    let args = body.arg_pool.from_iter(args.iter().copied());
+    let tys = match tys {
+        [] => ListRef::default(),
+        &[ty] => body.single_type_list(ty),
+        tys => body.type_pool.from_iter(tys.iter().copied()),
+    };
+    let value = body.add_value(ValueDef::Operator(op, args, tys));
+    body.append_to_block(block, value);
+    value
+}
+
+/// Join `lo` and `hi` into an `i64`.
+fn join(body: &mut FunctionBody, block: Block, lo: Value, hi: Value) -> Value {
+    let lo = add_op(body, block, Operator::I64ExtendI32U, &[lo], &[Type::I64]);
+    let hi = add_op(body, block, Operator::I64ExtendI32U, &[hi], &[Type::I64]);
+    let shift = add_op(
+        body,
+        block,
+        Operator::I64Const { value: 32 },
+        &[],
+        &[Type::I64],
+    );
+    let hi = add_op(body, block, Operator::I64Shl, &[hi, shift], &[Type::I64]);
+    add_op(body, block, Operator::I64Or, &[lo, hi], &[Type::I64])
+}
+
+/// Split `value` into its low and high halves.
+fn split(body: &mut FunctionBody, block: Block, value: Value) -> (Value, Value) {
+    let lo = add_op(body, block, Operator::I32WrapI64, &[value], &[Type::I32]);
+    let shift = add_op(
+        body,
+        block,
+        Operator::I64Const { value: 32 },
+        &[],
+        &[Type::I64],
+    );
+    let hi = add_op(
+        body,
+        block,
+        Operator::I64ShrU,
+        &[value, shift],
+        &[Type::I64],
+    );
+    let hi = add_op(body, block, Operator::I32WrapI64, &[hi], &[Type::I32]);
+    (lo, hi)
+}
+
+/// A body with signature `sig` that calls `import`, which has the
+/// legal form of `sig`.
+fn thunk(module: &Module, sig: Signature, import: Func, temp_ret: Global) -> FunctionBody {
+    let mut body = FunctionBody::new(module, sig);
+    let entry = body.entry;
+    let mut args = vec![];
+    for (ty, param) in body.blocks[entry].params.clone() {
+        if ty == Type::I64 {
+            let (lo, hi) = split(&mut body, entry, param);
+            args.extend([lo, hi]);
+        } else {
+            args.push(param);
+        }
+    }
+    let returns = module.signatures[sig].returns.clone();
+    let legal_returns = returns
+        .iter()
+        .map(|&ty| if ty == Type::I64 { Type::I32 } else { ty })
+        .collect::<Vec<_>>();
+    let call = Operator::Call {
+        function_index: import,
+    };
+    let result = add_op(&mut body, entry, call, &args, &legal_returns);
+    let values = match &returns[..] {
+        [] => vec![],
+        [Type::I64] => {
+            let global_index = temp_ret;
+            let hi = add_op(
+                &mut body,
+                entry,
+                Operator::GlobalGet { global_index },
+                &[],
+                &[Type::I32],
+            );
+            vec![join(&mut body, entry, result, hi)]
+        }
+        [_] => vec![result],
+        _ => pick_outputs(&mut body, entry, result, &returns),
+    };
+    body.set_terminator(entry, Terminator::Return { values });
+    body
+}
+
+/// A body with the legal signature `legal_sig` that calls `func`,
+/// which has signature `sig`.
+fn wrapper(
+    module: &Module,
+    legal_sig: Signature,
+    sig: Signature,
+    func: Func,
+    temp_ret: Global,
+) -> FunctionBody {
+    let mut body = FunctionBody::new(module, legal_sig);
+    let entry = body.entry;
+    let params = body.blocks[entry].params.clone();
+    let mut params = params.into_iter().map(|(_, param)| param);
+    let mut args = vec![];
+    for &ty in &module.signatures[sig].params {
+        let param = params.next().unwrap();
+        if ty == Type::I64 {
+            let hi = params.next().unwrap();
+            args.push(join(&mut body, entry, param, hi));
+        } else {
+            args.push(param);
+        }
+    }
+    let returns = module.signatures[sig].returns.clone();
+    let call = Operator::Call {
+        function_index: func,
+    };
+    let result = add_op(&mut body, entry, call, &args, &returns);
+    let values = match &returns[..] {
+        [] => vec![],
+        [Type::I64] => {
+            let (lo, hi) = split(&mut body, entry, result);
+            let global_index = temp_ret;
+            add_op(
+                &mut body,
+                entry,
+                Operator::GlobalSet { global_index },
+                &[hi],
+                &[],
+            );
+            vec![lo]
+        }
+        [_] => vec![result],
+        _ => pick_outputs(&mut body, entry, result, &returns),
+    };
+    body.set_terminator(entry, Terminator::Return { values });
+    body
+}
+
+fn pick_outputs(body: &mut FunctionBody, block: Block, value: Value, tys: &[Type]) -> Vec<Value> {
+    tys.iter()
+        .enumerate()
+        .map(|(i, &ty)| {
+            let pick = body.add_value(ValueDef::PickOutput(value, i as u32, ty));
+            body.append_to_block(block, pick);
+            pick
+        })
+        .collect()
+}
+
+/// Export `getTempRet0` and `setTempRet0`.
+fn add_temp_ret_api(module: &mut Module, temp_ret: Global) {
+    let get_sig = module.find_or_add_signature(SignatureData {
+        params: vec![],
+        returns: vec![Type::I32],
+    });
+    let mut body = FunctionBody::new(module, get_sig);
+    let entry = body.entry;
+    let value = add_op(
+        &mut body,
+        entry,
+        Operator::GlobalGet {
+            global_index: temp_ret,
+        },
+        &[],
+        &[Type::I32],
+    );
+    body.set_terminator(
+        entry,
+        Terminator::Return {
+            values: vec![value],
+        },
+    );
+    let get = module.funcs.push(FuncDecl::Body(
+        get_sig,
+        GET_TEMP_RET.to_owned(),
+        Box::new(body),
+    ));
+
+    let set_sig = module.find_or_add_signature(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![],
+    });
+    let mut body = FunctionBody::new(module, set_sig);
+    let entry = body.entry;
+    let value = body.blocks[entry].params[0].1;
+    add_op(
+        &mut body,
+        entry,
+        Operator::GlobalSet {
+            global_index: temp_ret,
+        },
+        &[value],
+        &[],
+    );
+    body.set_terminator(entry, Terminator::Return { values: vec![] });
+    let set = module.funcs.push(FuncDecl::Body(
+        set_sig,
+        SET_TEMP_RET.to_owned(),
+        Box::new(body),
+    ));
+
+    for (name, func) in [(GET_TEMP_RET, get), (SET_TEMP_RET, set)] {
+        module.exports.push(Export {
+            name: name.to_owned(),
+            kind: ExportKind::Func(func),
+        });
+    }
+}
@@ -0,0 +1,660 @@
+//! Lowering of post-MVP features, for engines that support only some
+//! of them.
+//!
+//! Given the features a target supports, lowers what it can of the
+//! rest to MVP equivalents, then checks that nothing the target lacks
+//! remains:
+//!
+//! - `sign-ext`: each sign-extension operator becomes a pair of
+//!   shifts.
+//! - `nontrapping-fptoint`: each saturating truncation becomes a
+//!   branch on whether its input is in range, to either the trapping
+//!   truncation or the saturated result.
+//! - `multivalue`: a function with more than one result returns only
+//!   the first, and stores the others to a return area at a fixed
+//!   address in linear memory, from which each caller loads them
+//!   right after the call. The caller of the pass chooses the
+//!   address, which the program must not otherwise use. This also changes the signatures of
+//!   imports and exports: a host function must store its other
+//!   results to the return area too, and a host calling an export
+//!   must load them from it.
+//! - `reference-types`: a typed `select` of a non-reference type
+//!   becomes an untyped one. Nothing else is lowered.
+//! - `simd128`: nothing is lowered.
+//!
+//! `bulk-memory` is accepted in feature lists, but the IR has no
+//! bulk-memory operators to lower.
+
+use crate::entity::EntityRef;
+use crate::ir::{
+    Block, BlockTarget, FunctionBody, Memory, Module, Terminator, Type, Value, ValueDef,
+};
+use crate::ops::MemoryArg;
+use crate::passes::resolve_aliases;
+use crate::pool::ListRef;
+use crate::Operator;
+use anyhow::{bail, Result};
+use fxhash::FxHashMap;
+use rayon::prelude::*;
+use std::str::FromStr;
+
+const PAGE_SIZE: usize = 65536;
+
+/// The post-MVP features a target supports.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Features {
+    pub sign_ext: bool,
+    pub nontrapping_fptoint: bool,
+    pub multivalue: bool,
+    pub reference_types: bool,
+    pub simd128: bool,
+}
+
+impl Features {
+    /// Wasm MVP, with none of the features.
+    pub const MVP: Features = Features {
+        sign_ext: false,
+        nontrapping_fptoint: false,
+        multivalue: false,
+        reference_types: false,
+        simd128: false,
+    };
+
+    fn has(&self, feature: &str) -> bool {
+        match feature {
+            "sign-ext" => self.sign_ext,
+            "nontrapping-fptoint" => self.nontrapping_fptoint,
+            "multivalue" => self.multivalue,
+            "reference-types" => self.reference_types,
+            "simd128" => self.simd128,
+            _ => unreachable!("Unknown feature '{}'", feature),
+        }
+    }
+}
+
+impl FromStr for Features {
+    type Err = anyhow::Error;
+
+    /// Parse a comma-separated list of feature names (e.g.,
+    /// `"mvp,sign-ext,multivalue"`), with the names LLVM uses.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut features = Features::MVP;
+        for name in s.split(',') {
+            match name.trim() {
+                "" | "mvp" | "bulk-memory" => {}
+                "sign-ext" => features.sign_ext = true,
+                "nontrapping-fptoint" => features.nontrapping_fptoint = true,
+                "multivalue" => features.multivalue = true,
+                "reference-types" => features.reference_types = true,
+                "simd128" => features.simd128 = true,
+                name => bail!(
+                    "Unknown feature '{}'; known features are: mvp, sign-ext, \
+                     nontrapping-fptoint, multivalue, reference-types, simd128, bulk-memory",
+                    name
+                ),
+            }
+        }
+        Ok(features)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LowerOptions {
+    pub features: Features,
+    /// The memory holding the return area.
+    pub memory: Memory,
+    /// Address of the return area, which the caller guarantees the
+    /// program does not otherwise use. It must have room for the
+    /// results after the first of any function, within the memory's
+    /// initial size. Required to lower multi-value returns: any
+    /// default address could hold program data.
+    pub return_area: Option<u32>,
+}
+
+impl Default for LowerOptions {
+    fn default() -> Self {
+        LowerOptions {
+            features: Features::MVP,
+            memory: Memory::new(0),
+            return_area: None,
+        }
+    }
+}
+
+/// Lower every function body, then check that the module only uses
+/// the target's features.
+pub fn run(module: &mut Module, options: &LowerOptions) -> Result<()> {
+    module.expand_all_funcs()?;
+    let features = options.features;
+    if !features.multivalue {
+        lower_multivalue(module, options)?;
+    }
+    module
+        .funcs
+        .values_mut()
+        .filter_map(|decl| decl.body_mut())
+        .collect::<Vec<_>>()
+        .par_iter_mut()
+        .for_each(|body| {
+            if !features.sign_ext || !features.reference_types {
+                lower_ops(body, features);
+            }
+            if !features.nontrapping_fptoint {
+                lower_sat_truncs(body);
+            }
+        });
+    check(module, features)
+}
+
+/// Check that the module only uses the given features.
+pub fn check(module: &Module, features: Features) -> Result<()> {
+    let require = |feature: Option<&str>, what: &dyn std::fmt::Display| -> Result<()> {
+        match feature {
+            Some(feature) if !features.has(feature) => {
+                bail!("{} requires the '{}' feature", what, feature)
+            }
+            _ => Ok(()),
+        }
+    };
+
+    for (sig, data) in module.signatures.entries() {
+        if data.returns.len() > 1 {
+            require(Some("multivalue"), &sig)?;
+        }
+        for &ty in data.params.iter().chain(data.returns.iter()) {
+            require(type_feature(ty), &sig)?;
+        }
+    }
+    for (global, data) in module.globals.entries() {
+        require(type_feature(data.ty), &global)?;
+    }
+    if module.tables.len() > 1 {
+        require(Some("reference-types"), &"More than one table")?;
+    }
+    for (func, decl) in module.funcs.entries() {
+        let body = match decl.body() {
+            Some(body) => body,
+            None => continue,
+        };
+        for &ty in body.locals.values() {
+            require(type_feature(ty), &func)?;
+        }
+        for (value, def) in body.values.entries() {
+            if let ValueDef::Operator(op, ..) = def {
+                require(op_feature(op), &format_args!("{}: {}", func, op))?;
+            }
+            for &ty in def.tys(&body.type_pool) {
+                require(type_feature(ty), &format_args!("{}: {}", func, value))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn op_feature(op: &Operator) -> Option<&'static str> {
+    match op {
+        Operator::I32Extend8S
+        | Operator::I32Extend16S
+        | Operator::I64Extend8S
+        | Operator::I64Extend16S
+        | Operator::I64Extend32S => Some("sign-ext"),
+        Operator::I32TruncSatF32S
+        | Operator::I32TruncSatF32U
+        | Operator::I32TruncSatF64S
+        | Operator::I32TruncSatF64U
+        | Operator::I64TruncSatF32S
+        | Operator::I64TruncSatF32U
+        | Operator::I64TruncSatF64S
+        | Operator::I64TruncSatF64U => Some("nontrapping-fptoint"),
+        Operator::TypedSelect { .. }
+        | Operator::TableGet { .. }
+        | Operator::TableSet { .. }
+        | Operator::TableGrow { .. }
+        | Operator::TableSize { .. } => Some("reference-types"),
+        Operator::CallIndirect { table_index, .. } if table_index.index() != 0 => {
+            Some("reference-types")
+        }
+        _ => None,
+    }
+}
+
+fn type_feature(ty: Type) -> Option<&'static str> {
+    match ty {
+        Type::V128 => Some("simd128"),
+        Type::FuncRef => Some("reference-types"),
+        _ => None,
+    }
+}
+
+fn add_op(
+    body: &mut FunctionBody,
+    block: Block,
+    op: Operator,
+    args: &[Value],
+    ty: Option<Type>,
+) -> Value {
+    let args = body.arg_pool.from_iter(args.iter().copied());
+    let tys = match ty {
+        Some(ty) => body.single_type_list(ty),
+        None => ListRef::default(),
+    };
+    let value = body.add_value(ValueDef::Operator(op, args, tys));
+    body.append_to_block(block, value);
+    value
+}
+
+fn type_size(ty: Type) -> u32 {
+    match ty {
+        Type::I32 | Type::F32 => 4,
+        Type::I64 | Type::F64 => 8,
+        _ => unreachable!(),
+    }
+}
+
+/// Offsets in the return area of each result after the first.
+fn return_area_layout(returns: &[Type]) -> Vec<u32> {
+    returns[1..]
+        .iter()
+        .scan(0, |offset, &ty| {
+            let this = *offset;
+            *offset += type_size(ty);
+            Some(this)
+        })
+        .collect()
+}
+
+fn lower_multivalue(module: &mut Module, options: &LowerOptions) -> Result<()> {
+    let sigs = module
+        .signatures
+        .entries()
+        .filter(|(_, data)| data.returns.len() > 1)
+        .map(|(sig, _)| sig)
+        .collect::<Vec<_>>();
+    if sigs.is_empty() {
+        return Ok(());
+    }
+    let return_area = match options.return_area {
+        Some(address) => address,
+        None => bail!("Lowering multi-value returns needs a return area address"),
+    };
+    if options.memory.index() >= module.memories.len() {
+        bail!(
+            "No memory {} for the multi-value return area",
+            options.memory
+        );
+    }
+    let mut area_len = 0;
+    for &sig in &sigs {
+        for &ty in &module.signatures[sig].returns[1..] {
+            if matches!(ty, Type::V128 | Type::FuncRef) {
+                bail!("Cannot return a {} through the return area", ty);
+            }
+        }
+        let len = module.signatures[sig].returns[1..]
+            .iter()
+            .map(|&ty| type_size(ty) as usize)
+            .sum::<usize>();
+        area_len = area_len.max(len);
+    }
+    let memory_len = module.memories[options.memory].initial_pages * PAGE_SIZE;
+    if return_area as usize + area_len > memory_len {
+        bail!(
+            "Return area of {} bytes at {} does not fit in the initial size of {}",
+            area_len,
+            return_area,
+            options.memory
+        );
+    }
+
+    module
+        .funcs
+        .values_mut()
+        .filter_map(|decl| decl.body_mut())
+        .collect::<Vec<_>>()
+        .par_iter_mut()
+        .for_each(|body| lower_multivalue_body(body, options.memory, return_area));
+    for sig in sigs {
+        module.signatures[sig].returns.truncate(1);
+    }
+    Ok(())
+}
+
+fn lower_multivalue_body(body: &mut FunctionBody, memory: Memory, return_area: u32) {
+    let rets = body.rets.clone();
+    let mut results = FxHashMap::default();
+    for block in body.blocks.iter() {
+        let mut insts = Vec::with_capacity(body.blocks[block].insts.len());
+        for inst in std::mem::take(&mut body.blocks[block].insts) {
+            insts.push(inst);
+            let (op, args, tys) = match &body.values[inst] {
+                ValueDef::Operator(
+                    op @ (Operator::Call { .. } | Operator::CallIndirect { .. }),
+                    args,
+                    tys,
+                ) if tys.len() > 1 => (*op, *args, body.type_pool[*tys].to_vec()),
+                _ => continue,
+            };
+            let ty = body.single_type_list(tys[0]);
+            body.values[inst] = ValueDef::Operator(op, args, ty);
+
+            // Load the other results right away, before anything
+            // else can use the return area.
+            let mut values = vec![inst];
+            let i32_ty = body.single_type_list(Type::I32);
+            let addr = body.add_value(ValueDef::Operator(
+                Operator::I32Const { value: return_area },
+                ListRef::default(),
+                i32_ty,
+            ));
+            insts.push(addr);
+            for (&ty, offset) in tys[1..].iter().zip(return_area_layout(&tys)) {
+                let memory = MemoryArg {
+                    align: 0,
+                    offset,
+                    memory,
+                };
+                let op = match ty {
+                    Type::I32 => Operator::I32Load { memory },
+                    Type::I64 => Operator::I64Load { memory },
+                    Type::F32 => Operator::F32Load { memory },
+                    Type::F64 => Operator::F64Load { memory },
+                    _ => unreachable!(),
+                };
+                let args = body.arg_pool.single(addr);
+                let ty = body.single_type_list(ty);
+                let load = body.add_value(ValueDef::Operator(op, args, ty));
+                insts.push(load);
+                values.push(load);
+            }
+            for &value in &insts[insts.len() - tys.len()..] {
+                body.value_blocks[value] = block;
+                body.source_locs[value] = body.source_locs[inst];
+            }
+            results.insert(inst, values);
+        }
+        body.blocks[block].insts = insts;
+
+        if rets.len() > 1 {
+            if let Terminator::Return { values } = &mut body.blocks[block].terminator {
+                let rest = values.split_off(1);
+                let addr = add_op(
+                    body,
+                    block,
+                    Operator::I32Const { value: return_area },
+                    &[],
+                    Some(Type::I32),
+                );
+                for ((value, &ty), offset) in rest
+                    .into_iter()
+                    .zip(rets[1..].iter())
+                    .zip(return_area_layout(&rets))
+                {
+                    let memory = MemoryArg {
+                        align: 0,
+                        offset,
+                        memory,
+                    };
+                    let op = match ty {
+                        Type::I32 => Operator::I32Store { memory },
+                        Type::I64 => Operator::I64Store { memory },
+                        Type::F32 => Operator::F32Store { memory },
+                        Type::F64 => Operator::F64Store { memory },
+                        _ => unreachable!(),
+                    };
+                    add_op(body, block, op, &[addr, value], None);
+                }
+            }
+        }
+    }
+    body.rets.truncate(1);
+
+    if results.is_empty() {
+        return;
+    }
+    for value in body.values.iter() {
+        if let ValueDef::PickOutput(call, index, _) = body.values[value] {
+            if let Some(values) = results.get(&call) {
+                body.set_alias(value, values[index as usize]);
+            }
+        }
+    }
+    let values = &body.values;
+    for block in body.blocks.values_mut() {
+        block
+            .insts
+            .retain(|&inst| !matches!(values[inst], ValueDef::Alias(_)));
+    }
+    resolve_aliases::run(body);
+}
+
+/// Lower sign extensions to shift pairs, and typed selects of
+/// non-reference types to untyped ones, unless the features allow
+/// them.
+fn lower_ops(body: &mut FunctionBody, features: Features) {
+    for block in body.blocks.iter() {
+        let mut insts = Vec::with_capacity(body.blocks[block].insts.len());
+        for inst in std::mem::take(&mut body.blocks[block].insts) {
+            let (op, args) = match &body.values[inst] {
+                ValueDef::Operator(op, args, _) => (*op, *args),
+                _ => {
+                    insts.push(inst);
+                    continue;
+                }
+            };
+            let (shift, shl, shr_s, ty) = match op {
+                Operator::TypedSelect { ty }
+                    if !features.reference_types && ty != Type::FuncRef =>
+                {
+                    let tys = body.single_type_list(ty);
+                    body.values[inst] = ValueDef::Operator(Operator::Select, args, tys);
+                    insts.push(inst);
+                    continue;
+                }
+                _ if features.sign_ext => {
+                    insts.push(inst);
+                    continue;
+                }
+                Operator::I32Extend8S => (24, Operator::I32Shl, Operator::I32ShrS, Type::I32),
+                Operator::I32Extend16S => (16, Operator::I32Shl, Operator::I32ShrS, Type::I32),
+                Operator::I64Extend8S => (56, Operator::I64Shl, Operator::I64ShrS, Type::I64),
+                Operator::I64Extend16S => (48, Operator::I64Shl, Operator::I64ShrS, Type::I64),
+                Operator::I64Extend32S => (32, Operator::I64Shl, Operator::I64ShrS, Type::I64),
+                _ => {
+                    insts.push(inst);
+                    continue;
+                }
+            };
+            let shift = match ty {
+                Type::I32 => Operator::I32Const { value: shift },
+                _ => Operator::I64Const {
+                    value: shift as u64,
+                },
+            };
+            let arg = body.arg_pool[args][0];
+            let tys = body.single_type_list(ty);
+            let shift = body.add_value(ValueDef::Operator(shift, ListRef::default(), tys));
+            let shl_args = body.arg_pool.double(arg, shift);
+            let shifted = body.add_value(ValueDef::Operator(shl, shl_args, tys));
+            let shr_args = body.arg_pool.double(shifted, shift);
+            body.values[inst] = ValueDef::Operator(shr_s, shr_args, tys);
+            for value in [shift, shifted] {
+                body.value_blocks[value] = block;
+                body.source_locs[value] = body.source_locs[inst];
+                insts.push(value);
+            }
+            insts.push(inst);
+        }
+        body.blocks[block].insts = insts;
+    }
+}
+
+/// The trapping truncation for a saturating one, with its input and
+/// result types and signedness.
+fn trapping_trunc(op: &Operator) -> Option<(Operator, Type, Type, bool)> {
+    use Type::*;
+    Some(match op {
+        Operator::I32TruncSatF32S => (Operator::I32TruncF32S, F32, I32, true),
+        Operator::I32TruncSatF32U => (Operator::I32TruncF32U, F32, I32, false),
+        Operator::I32TruncSatF64S => (Operator::I32TruncF64S, F64, I32, true),
+        Operator::I32TruncSatF64U => (Operator::I32TruncF64U, F64, I32, false),
+        Operator::I64TruncSatF32S => (Operator::I64TruncF32S, F32, I64, true),
+        Operator::I64TruncSatF32U => (Operator::I64TruncF32U, F32, I64, false),
+        Operator::I64TruncSatF64S => (Operator::I64TruncF64S, F64, I64, true),
+        Operator::I64TruncSatF64U => (Operator::I64TruncF64U, F64, I64, false),
+        _ => return None,
+    })
+}
+
+fn float_const(ty: Type, value: f64) -> Operator {
+    match ty {
+        Type::F32 => Operator::F32Const {
+            value: (value as f32).to_bits(),
+        },
+        _ => Operator::F64Const {
+            value: value.to_bits(),
+        },
+    }
+}
+
+fn int_const(ty: Type, value: i64) -> Operator {
+    match ty {
+        Type::I32 => Operator::I32Const {
+            value: value as u32,
+        },
+        _ => Operator::I64Const {
+            value: value as u64,
+        },
+    }
+}
+
+/// Lower saturating truncations to guarded trapping ones. Each splits
+/// its block, so new blocks are visited as they are added.
+fn lower_sat_truncs(body: &mut FunctionBody) {
+    let mut changed = false;
+    let mut block = 0;
+    while block < body.blocks.len() {
+        let block_ref = Block::new(block);
+        let index = body.blocks[block_ref].insts.iter().position(|&inst| {
+            matches!(&body.values[inst], ValueDef::Operator(op, ..) if trapping_trunc(op).is_some())
+        });
+        if let Some(index) = index {
+            lower_sat_trunc(body, block_ref, index);
+            changed = true;
+        }
+        block += 1;
+    }
+    if changed {
+        body.recompute_edges();
+    }
+}
+
+fn lower_sat_trunc(body: &mut FunctionBody, block: Block, index: usize) {
+    let inst = body.blocks[block].insts[index];
+    let (op, x) = match &body.values[inst] {
+        ValueDef::Operator(op, args, _) => (*op, body.arg_pool[*args][0]),
+        _ => unreachable!(),
+    };
+    let (trunc, from, to, signed) = trapping_trunc(&op).unwrap();
+    let (ge, gt, lt, ne) = match from {
+        Type::F32 => (
+            Operator::F32Ge,
+            Operator::F32Gt,
+            Operator::F32Lt,
+            Operator::F32Ne,
+        ),
+        _ => (
+            Operator::F64Ge,
+            Operator::F64Gt,
+            Operator::F64Lt,
+            Operator::F64Ne,
+        ),
+    };
+    let bits = match to {
+        Type::I32 => 32,
+        _ => 64,
+    };
+
+    // `inst` becomes a blockparam of the rest of the block.
+    let join = body.split_block(block, index);
+    body.blocks[block].terminator = Terminator::None;
+    body.blocks[join].insts.remove(0);
+    body.values[inst] = ValueDef::BlockParam(join, 0, to);
+    body.blocks[join].params.push((to, inst));
+
+    // In range: inputs whose truncation is representable. Signed
+    // inputs just below the minimum truncate to it, and saturate to
+    // it too, so `>=` on the minimum is exact enough.
+    let (lo, lo_cmp, hi) = if signed {
+        let min = -(2f64.powi(bits - 1));
+        (min, ge, 2f64.powi(bits - 1))
+    } else {
+        (-1.0, gt, 2f64.powi(bits))
+    };
+    let lo = add_op(body, block, float_const(from, lo), &[], Some(from));
+    let above_lo = add_op(body, block, lo_cmp, &[x, lo], Some(Type::I32));
+    let hi = add_op(body, block, float_const(from, hi), &[], Some(from));
+    let below_hi = add_op(body, block, lt, &[x, hi], Some(Type::I32));
+    let in_range = add_op(
+        body,
+        block,
+        Operator::I32And,
+        &[above_lo, below_hi],
+        Some(Type::I32),
+    );
+
+    let in_range_at = body.add_block();
+    let value = add_op(body, in_range_at, trunc, &[x], Some(to));
+    body.blocks[in_range_at].terminator = Terminator::Br {
+        target: BlockTarget {
+            block: join,
+            args: vec![value],
+        },
+    };
+
+    // Out of range: zero for NaN, else the minimum or maximum.
+    let saturate_at = body.add_block();
+    let (min, max) = match (signed, bits) {
+        (true, 32) => (i32::MIN as i64, i32::MAX as i64),
+        (true, _) => (i64::MIN, i64::MAX),
+        (false, 32) => (0, u32::MAX as i64),
+        (false, _) => (0, -1),
+    };
+    let zero = add_op(body, saturate_at, float_const(from, 0.0), &[], Some(from));
+    let negative = add_op(body, saturate_at, lt, &[x, zero], Some(Type::I32));
+    let min = add_op(body, saturate_at, int_const(to, min), &[], Some(to));
+    let max = add_op(body, saturate_at, int_const(to, max), &[], Some(to));
+    let value = add_op(
+        body,
+        saturate_at,
+        Operator::Select,
+        &[min, max, negative],
+        Some(to),
+    );
+    let is_nan = add_op(body, saturate_at, ne, &[x, x], Some(Type::I32));
+    let zero = add_op(body, saturate_at, int_const(to, 0), &[], Some(to));
+    let value = add_op(
+        body,
+        saturate_at,
+        Operator::Select,
+        &[zero, value, is_nan],
+        Some(to),
+    );
+    body.blocks[saturate_at].terminator = Terminator::Br {
+        target: BlockTarget {
+            block: join,
+            args: vec![value],
+        },
+    };
+
+    body.blocks[block].terminator = Terminator::CondBr {
+        cond: in_range,
+        if_true: BlockTarget {
+            block: in_range_at,
+            args: vec![],
+        },
+        if_false: BlockTarget {
+            block: saturate_at,
+            args: vec![],
+        },
+    };
+}
@@ -0,0 +1,385 @@
+//! Pass manager: a registry of named passes, pipelines built from
+//! pass names, optional validation after every pass, and per-pass
+//! statistics.
+
+use crate::cfg::CFGInfo;
+use crate::ir::{Func, FunctionBody, Module};
+use crate::passes::asyncify::AsyncifyOptions;
+use crate::passes::coverage::{CoverageOptions, CoverageSites};
+use crate::passes::fuel::FuelOptions;
+use crate::passes::lower_features::LowerOptions;
+use crate::passes::memtrace::MemTraceOptions;
+use crate::passes::merge_funcs::MergeOptions;
+use crate::passes::profile::ProfileOptions;
+use crate::passes::stack_limit::StackLimitOptions;
+use anyhow::Result;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// A pass that transforms one function body at a time. Function
+/// passes are run in parallel over all function bodies in a module,
+/// so they must not rely on any shared mutable state.
+pub trait FunctionPass: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn run(&self, body: &mut FunctionBody) -> Result<()>;
+}
+
+/// A pass that transforms a whole module.
+pub trait ModulePass: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn run(&self, module: &mut Module<'_>) -> Result<()>;
+}
+
+/// A function pass defined by a plain function.
+pub struct FunctionPassFn {
+    pub name: &'static str,
+    pub func: fn(&mut FunctionBody),
+}
+
+impl FunctionPass for FunctionPassFn {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+    fn run(&self, body: &mut FunctionBody) -> Result<()> {
+        (self.func)(body);
+        Ok(())
+    }
+}
+
+/// A module pass defined by a plain function.
+pub struct ModulePassFn {
+    pub name: &'static str,
+    pub func: fn(&mut Module<'_>) -> Result<()>,
+}
+
+impl ModulePass for ModulePassFn {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+    fn run(&self, module: &mut Module<'_>) -> Result<()> {
+        (self.func)(module)
+    }
+}
+
+enum Pass {
+    Function(Box<dyn FunctionPass>),
+    Module(Box<dyn ModulePass>),
+}
+
+impl Pass {
+    fn name(&self) -> &'static str {
+        match self {
+            Pass::Function(pass) => pass.name(),
+            Pass::Module(pass) => pass.name(),
+        }
+    }
+}
+
+/// Statistics for one run of one pass in a pipeline.
+#[derive(Clone, Debug)]
+pub struct PassStats {
+    pub name: &'static str,
+    pub time: Duration,
+    pub blocks_before: usize,
+    pub blocks_after: usize,
+    pub insts_before: usize,
+    pub insts_after: usize,
+}
+
+impl std::fmt::Display for PassStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<20} {:>10.3}ms  blocks {:>8} -> {:<8} insts {:>8} -> {:<8}",
+            self.name,
+            self.time.as_secs_f64() * 1000.0,
+            self.blocks_before,
+            self.blocks_after,
+            self.insts_before,
+            self.insts_after
+        )
+    }
+}
+
+/// Size of the IR in a module: (blocks, instructions), summed over
+/// all expanded function bodies.
+fn ir_size(module: &Module<'_>) -> (usize, usize) {
+    module
+        .funcs
+        .values()
+        .filter_map(|decl| decl.body())
+        .fold((0, 0), |(blocks, insts), body| {
+            (
+                blocks + body.blocks.len(),
+                insts + body.blocks.values().map(|b| b.insts.len()).sum::<usize>(),
+            )
+        })
+}
+
+/// Runs a pipeline of registered passes over a module.
+#[derive(Default)]
+pub struct PassManager {
+    registry: BTreeMap<&'static str, Pass>,
+    pipeline: Vec<&'static str>,
+    /// Run `FunctionBody::validate()` on every body after each pass.
+    pub validate: bool,
+    stats: Vec<PassStats>,
+}
+
+impl PassManager {
+    /// Create a pass manager with an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a pass manager with all of the built-in passes
+    /// registered.
+    pub fn with_builtin_passes() -> Self {
+        let mut pm = Self::new();
+        pm.register_function_pass(FunctionPassFn {
+            name: "gvn",
+            func: |body| {
+                let cfg = CFGInfo::new(body);
+                crate::passes::basic_opt::gvn(body, &cfg);
+            },
+        });
+        pm.register_function_pass(FunctionPassFn {
+            name: "remove-phis",
+            func: |body| {
+                let cfg = CFGInfo::new(body);
+                crate::passes::remove_phis::run(body, &cfg);
+            },
+        });
+        pm.register_function_pass(FunctionPassFn {
+            name: "empty-blocks",
+            func: crate::passes::empty_blocks::run,
+        });
+        pm.register_function_pass(FunctionPassFn {
+            name: "resolve-aliases",
+            func: crate::passes::resolve_aliases::run,
+        });
+        pm.register_function_pass(FunctionPassFn {
+            name: "max-ssa",
+            func: |body| body.convert_to_max_ssa(None),
+        });
+        pm.register_function_pass(FunctionPassFn {
+            name: "trace",
+            func: crate::passes::trace::run,
+        });
+        pm.register_function_pass(FunctionPassFn {
+            name: "canonicalize-nans",
+            func: crate::passes::nan_canon::run,
+        });
+        pm.register_function_pass(FunctionPassFn {
+            name: "basic-opts",
+            func: |body| body.optimize(),
+        });
+        pm.register_module_pass(ModulePassFn {
+            name: "legalize-js-interface",
+            func: crate::passes::legalize::run,
+        });
+        pm.register_module_pass(ModulePassFn {
+            name: "lower-to-mvp",
+            func: |module| crate::passes::lower_features::run(module, &LowerOptions::default()),
+        });
+        pm.register_module_pass(ModulePassFn {
+            name: "lower-trace",
+            func: crate::passes::trace::lower_to_imports,
+        });
+        pm.register_module_pass(ModulePassFn {
+            name: "coverage",
+            func: |module| {
+                crate::passes::coverage::run(module, &CoverageOptions::default())?;
+                Ok(())
+            },
+        });
+        pm.register_module_pass(ModulePassFn {
+            name: "edge-coverage",
+            func: |module| {
+                let options = CoverageOptions {
+                    sites: CoverageSites::Edges,
+                    ..CoverageOptions::default()
+                };
+                crate::passes::coverage::run(module, &options)?;
+                Ok(())
+            },
+        });
+        pm.register_module_pass(ModulePassFn {
+            name: "profile",
+            func: |module| {
+                crate::passes::profile::run(module, &ProfileOptions::default())?;
+                Ok(())
+            },
+        });
+        pm.register_module_pass(ModulePassFn {
+            name: "profile-calls",
+            func: |module| {
+                let options = ProfileOptions {
+                    call_sites: true,
+                    ..ProfileOptions::default()
+                };
+                crate::passes::profile::run(module, &options)?;
+                Ok(())
+            },
+        });
+        pm.register_module_pass(ModulePassFn {
+            name: "trace-memory",
+            func: |module| crate::passes::memtrace::run(module, &MemTraceOptions::default()),
+        });
+        pm.register_module_pass(ModulePassFn {
+            name: "fuel",
+            func: |module| {
+                crate::passes::fuel::run(module, &FuelOptions::default())?;
+                Ok(())
+            },
+        });
+        pm.register_module_pass(ModulePassFn {
+            name: "asyncify",
+            func: |module| crate::passes::asyncify::run(module, &AsyncifyOptions::default()),
+        });
+        pm.register_module_pass(ModulePassFn {
+            name: "stack-limit",
+            func: |module| {
+                crate::passes::stack_limit::run(module, &StackLimitOptions::default())?;
+                Ok(())
+            },
+        });
+        pm.register_module_pass(ModulePassFn {
+            name: "devirt",
+            func: crate::passes::devirt::run,
+        });
+        pm.register_module_pass(ModulePassFn {
+            name: "gc",
+            func: crate::passes::gc::run,
+        });
+        pm.register_module_pass(ModulePassFn {
+            name: "merge-funcs",
+            func: |module| crate::passes::merge_funcs::run(module, &MergeOptions::default()),
+        });
+        pm.register_module_pass(ModulePassFn {
+            name: "merge-similar-funcs",
+            func: |module| {
+                crate::passes::merge_funcs::run(
+                    module,
+                    &MergeOptions {
+                        merge_similar: true,
+                    },
+                )
+            },
+        });
+        pm
+    }
+
+    pub fn register_function_pass<P: FunctionPass + 'static>(&mut self, pass: P) {
+        self.registry
+            .insert(pass.name(), Pass::Function(Box::new(pass)));
+    }
+
+    pub fn register_module_pass<P: ModulePass + 'static>(&mut self, pass: P) {
+        self.registry
+            .insert(pass.name(), Pass::Module(Box::new(pass)));
+    }
+
+    /// Names of all registered passes, in sorted order.
+    pub fn registered_passes(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.registry.keys().copied()
+    }
+
+    /// Append one registered pass to the pipeline.
+    pub fn add_pass(&mut self, name: &str) -> Result<()> {
+        match self.registry.get(name) {
+            Some(pass) => {
+                self.pipeline.push(pass.name());
+                Ok(())
+            }
+            None => anyhow::bail!(
+                "Unknown pass '{}'; registered passes are: {}",
+                name,
+                self.registered_passes().collect::<Vec<_>>().join(", ")
+            ),
+        }
+    }
+
+    /// Append a comma-separated list of pass names (e.g.,
+    /// `"gvn,remove-phis,empty-blocks"`) to the pipeline.
+    pub fn add_pipeline(&mut self, pipeline: &str) -> Result<()> {
+        for name in pipeline.split(',') {
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            self.add_pass(name)?;
+        }
+        Ok(())
+    }
+
+    /// Statistics for every pass run so far, in execution order.
+    pub fn stats(&self) -> &[PassStats] {
+        &self.stats[..]
+    }
+
+    /// Run the pipeline over the module. Lazy function bodies are
+    /// left untouched by function passes; call
+    /// `Module::expand_all_funcs()` first to transform every body.
+    pub fn run(&mut self, module: &mut Module<'_>) -> Result<()> {
+        for i in 0..self.pipeline.len() {
+            let pass = &self.registry[self.pipeline[i]];
+            log::debug!("Running pass '{}'", pass.name());
+            let (blocks_before, insts_before) = ir_size(module);
+            let start = Instant::now();
+            match pass {
+                Pass::Function(pass) => {
+                    let mut bodies = module
+                        .funcs
+                        .entries_mut()
+                        .filter_map(|(func, decl)| decl.body_mut().map(|body| (func, body)))
+                        .collect::<Vec<(Func, &mut FunctionBody)>>();
+                    bodies
+                        .par_iter_mut()
+                        .map(|(func, body)| {
+                            pass.run(body).map_err(|e| {
+                                anyhow::anyhow!("Pass '{}' failed on {}: {}", pass.name(), func, e)
+                            })
+                        })
+                        .collect::<Result<Vec<()>>>()?;
+                }
+                Pass::Module(pass) => {
+                    pass.run(module)
+                        .map_err(|e| anyhow::anyhow!("Pass '{}' failed: {}", pass.name(), e))?;
+                }
+            }
+            let time = start.elapsed();
+            let (blocks_after, insts_after) = ir_size(module);
+            self.stats.push(PassStats {
+                name: pass.name(),
+                time,
+                blocks_before,
+                blocks_after,
+                insts_before,
+                insts_after,
+            });
+
+            if self.validate {
+                module
+                    .funcs
+                    .entries()
+                    .collect::<Vec<_>>()
+                    .par_iter()
+                    .filter_map(|(func, decl)| decl.body().map(|body| (func, body)))
+                    .map(|(func, body)| {
+                        body.validate().map_err(|e| {
+                            anyhow::anyhow!(
+                                "Validation failed on {} after pass '{}': {}",
+                                func,
+                                pass.name(),
+                                e
+                            )
+                        })
+                    })
+                    .collect::<Result<Vec<()>>>()?;
+            }
+        }
+        Ok(())
+    }
+}
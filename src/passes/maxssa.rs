@@ -7,7 +7,8 @@
 use crate::cfg::CFGInfo;
 use crate::entity::PerEntity;
 use crate::ir::{Block, FunctionBody, Value, ValueDef};
-use std::collections::{BTreeSet, HashMap, HashSet};
+use fxhash::FxHashMap as HashMap;
+use std::collections::{BTreeSet, HashSet};
 
 pub fn run(body: &mut FunctionBody, cut_blocks: Option<HashSet<Block>>, cfg: &CFGInfo) {
     MaxSSAPass::new(cut_blocks).run(body, cfg);
@@ -21,7 +22,7 @@ struct MaxSSAPass {
     /// order. Value numbers are *original* values.
     new_args: PerEntity<Block, Vec<Value>>,
     /// For each block, a value map: from original value to local copy
-    /// of value.
+    /// of value. Only looked up, so its hash order does not matter.
     value_map: HashMap<(Block, Value), Value>,
 }
 
@@ -30,7 +31,7 @@ impl MaxSSAPass {
         Self {
             cut_blocks,
             new_args: PerEntity::default(),
-            value_map: HashMap::new(),
+            value_map: HashMap::default(),
         }
     }
 
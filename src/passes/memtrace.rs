@@ -0,0 +1,362 @@
+//! Memory-access tracing instrumentation.
+//!
+//! Every load and store calls a hook imported from the `waffle_mem`
+//! module, one per value type: `access_<ty>(addr: i64, size: i32,
+//! is_store: i32, value: <ty>)`, where `addr` is the effective
+//! address (base plus offset), `size` is the access width in bytes,
+//! and `value` is the value loaded (after extension) or stored. Every
+//! `memory.grow` calls `grow(memory: i32, delta: i32, result: i32)`.
+//!
+//! Hooks are called after the access, so an access that traps does
+//! so before its hook runs, exactly as in the original module, and a
+//! host never sees an access that did not happen.
+//!
+//! To keep the overhead down, accesses can be filtered statically by
+//! function name or memory, and dynamically by address range: with a
+//! range, the hook is only called (behind a branch) for accesses
+//! whose effective address lies in it.
+
+use crate::cfg::CFGInfo;
+use crate::entity::EntityRef;
+use crate::ir::{
+    Block, BlockTarget, Func, FunctionBody, Memory, Module, SignatureData, Terminator, Type, Value,
+    ValueDef,
+};
+use crate::ops::MemoryArg;
+use crate::pool::ListRef;
+use crate::Operator;
+use anyhow::Result;
+use rayon::prelude::*;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+#[derive(Clone, Debug, Default)]
+pub struct MemTraceOptions {
+    /// Only instrument functions whose names match.
+    pub funcs: Option<Regex>,
+    /// Only instrument accesses to this memory.
+    pub memory: Option<Memory>,
+    /// Only report accesses whose effective address is in this range.
+    pub addresses: Option<Range<u64>>,
+}
+
+/// A load or store: its memory argument, width in bytes, direction,
+/// and value type.
+struct Access {
+    memory: MemoryArg,
+    size: u32,
+    is_store: bool,
+    ty: Type,
+}
+
+fn access(op: &Operator) -> Option<Access> {
+    let (memory, size, is_store, ty) = match *op {
+        Operator::I32Load { memory } => (memory, 4, false, Type::I32),
+        Operator::I64Load { memory } => (memory, 8, false, Type::I64),
+        Operator::F32Load { memory } => (memory, 4, false, Type::F32),
+        Operator::F64Load { memory } => (memory, 8, false, Type::F64),
+        Operator::I32Load8S { memory } | Operator::I32Load8U { memory } => {
+            (memory, 1, false, Type::I32)
+        }
+        Operator::I32Load16S { memory } | Operator::I32Load16U { memory } => {
+            (memory, 2, false, Type::I32)
+        }
+        Operator::I64Load8S { memory } | Operator::I64Load8U { memory } => {
+            (memory, 1, false, Type::I64)
+        }
+        Operator::I64Load16S { memory } | Operator::I64Load16U { memory } => {
+            (memory, 2, false, Type::I64)
+        }
+        Operator::I64Load32S { memory } | Operator::I64Load32U { memory } => {
+            (memory, 4, false, Type::I64)
+        }
+        Operator::I32Store { memory } => (memory, 4, true, Type::I32),
+        Operator::I64Store { memory } => (memory, 8, true, Type::I64),
+        Operator::F32Store { memory } => (memory, 4, true, Type::F32),
+        Operator::F64Store { memory } => (memory, 8, true, Type::F64),
+        Operator::I32Store8 { memory } => (memory, 1, true, Type::I32),
+        Operator::I32Store16 { memory } => (memory, 2, true, Type::I32),
+        Operator::I64Store8 { memory } => (memory, 1, true, Type::I64),
+        Operator::I64Store16 { memory } => (memory, 2, true, Type::I64),
+        Operator::I64Store32 { memory } => (memory, 4, true, Type::I64),
+        _ => return None,
+    };
+    Some(Access {
+        memory,
+        size,
+        is_store,
+        ty,
+    })
+}
+
+/// A traced operator: a load or store, or a `memory.grow`.
+enum Traced {
+    Access(Access),
+    Grow(Memory),
+}
+
+fn traced(options: &MemTraceOptions, body: &FunctionBody, value: Value) -> Option<Traced> {
+    let op = match &body.values[value] {
+        ValueDef::Operator(op, ..) => op,
+        _ => return None,
+    };
+    let traced = match op {
+        &Operator::MemoryGrow { mem } => Traced::Grow(mem),
+        op => Traced::Access(access(op)?),
+    };
+    let memory = match &traced {
+        Traced::Access(access) => access.memory.memory,
+        Traced::Grow(memory) => *memory,
+    };
+    if options.memory.is_none_or(|m| m == memory) {
+        Some(traced)
+    } else {
+        None
+    }
+}
+
+/// The imported hooks, by index in the instrumented module.
+struct Hooks {
+    access: BTreeMap<Type, Func>,
+    grow: Option<Func>,
+}
+
+pub fn run(module: &mut Module, options: &MemTraceOptions) -> Result<()> {
+    module.expand_all_funcs()?;
+
+    let selected = module
+        .funcs
+        .values()
+        .map(|decl| {
+            decl.body().is_some()
+                && options
+                    .funcs
+                    .as_ref()
+                    .is_none_or(|re| re.is_match(decl.name()))
+        })
+        .collect::<Vec<_>>();
+
+    // Import only the hooks that are used.
+    let mut access_types = vec![];
+    let mut grows = false;
+    for (decl, _) in module
+        .funcs
+        .values()
+        .zip(selected.iter())
+        .filter(|(_, &selected)| selected)
+    {
+        let body = decl.body().unwrap();
+        for block in body.blocks.values() {
+            for &inst in &block.insts {
+                match traced(options, body, inst) {
+                    Some(Traced::Access(access)) => access_types.push(access.ty),
+                    Some(Traced::Grow(_)) => grows = true,
+                    None => {}
+                }
+            }
+        }
+    }
+    access_types.sort();
+    access_types.dedup();
+    if access_types.is_empty() && !grows {
+        return Ok(());
+    }
+
+    let names = access_types
+        .iter()
+        .map(|ty| format!("access_{}", ty))
+        .collect::<Vec<_>>();
+    let mut imports = vec![];
+    for (&ty, name) in access_types.iter().zip(names.iter()) {
+        let sig = module.find_or_add_signature(SignatureData {
+            params: vec![Type::I64, Type::I32, Type::I32, ty],
+            returns: vec![],
+        });
+        imports.push(("waffle_mem", &name[..], sig));
+    }
+    if grows {
+        let sig = module.find_or_add_signature(SignatureData {
+            params: vec![Type::I32, Type::I32, Type::I32],
+            returns: vec![],
+        });
+        imports.push(("waffle_mem", "grow", sig));
+    }
+    let mut hook_funcs = module.add_func_imports(&imports[..])?.into_iter();
+    let hooks = Hooks {
+        access: access_types
+            .iter()
+            .map(|&ty| (ty, hook_funcs.next().unwrap()))
+            .collect(),
+        grow: hook_funcs.next(),
+    };
+
+    // The module's own functions moved up past the new imports.
+    let mut selected_bodies = vec![false; imports.len()];
+    selected_bodies.extend(selected);
+    module
+        .funcs
+        .values_mut()
+        .zip(selected_bodies)
+        .filter(|(_, selected)| *selected)
+        .filter_map(|(decl, _)| decl.body_mut())
+        .collect::<Vec<_>>()
+        .par_iter_mut()
+        .for_each(|body| instrument(options, &hooks, body));
+    Ok(())
+}
+
+fn add_op(
+    body: &mut FunctionBody,
+    block: Block,
+    insts: &mut Vec<Value>,
+    op: Operator,
+    args: &[Value],
+    ty: Option<Type>,
+) -> Value {
+    let args = body.arg_pool.from_iter(args.iter().copied());
+    let tys = match ty {
+        Some(ty) => body.single_type_list(ty),
+        None => ListRef::default(),
+    };
+    let value = body.add_value(ValueDef::Operator(op, args, tys));
+    body.value_blocks[value] = block;
+    insts.push(value);
+    value
+}
+
+fn i32_const(body: &mut FunctionBody, block: Block, insts: &mut Vec<Value>, value: u32) -> Value {
+    let op = Operator::I32Const { value };
+    add_op(body, block, insts, op, &[], Some(Type::I32))
+}
+
+fn instrument(options: &MemTraceOptions, hooks: &Hooks, body: &mut FunctionBody) {
+    let cfg = CFGInfo::new(body);
+    for &start in cfg.rpo.values() {
+        let mut block = start;
+        let mut i = 0;
+        while i < body.blocks[block].insts.len() {
+            let inst = body.blocks[block].insts[i];
+            i += 1;
+            let traced = match traced(options, body, inst) {
+                Some(traced) => traced,
+                None => continue,
+            };
+            let args = match body.values[inst] {
+                ValueDef::Operator(_, args, _) => body.arg_pool[args].to_vec(),
+                _ => unreachable!(),
+            };
+
+            let access = match traced {
+                Traced::Grow(memory) => {
+                    let mut insts = vec![];
+                    let memory = i32_const(body, block, &mut insts, memory.index() as u32);
+                    let call = Operator::Call {
+                        function_index: hooks.grow.unwrap(),
+                    };
+                    add_op(
+                        body,
+                        block,
+                        &mut insts,
+                        call,
+                        &[memory, args[0], inst],
+                        None,
+                    );
+                    let n = insts.len();
+                    body.blocks[block].insts.splice(i..i, insts);
+                    i += n;
+                    continue;
+                }
+                Traced::Access(access) => access,
+            };
+
+            // Effective address, as an i64 so that it cannot wrap.
+            let mut insts = vec![];
+            let op = Operator::I64ExtendI32U;
+            let mut addr = add_op(body, block, &mut insts, op, &[args[0]], Some(Type::I64));
+            if access.memory.offset != 0 {
+                let op = Operator::I64Const {
+                    value: access.memory.offset as u64,
+                };
+                let offset = add_op(body, block, &mut insts, op, &[], Some(Type::I64));
+                addr = add_op(
+                    body,
+                    block,
+                    &mut insts,
+                    Operator::I64Add,
+                    &[addr, offset],
+                    Some(Type::I64),
+                );
+            }
+
+            // Call the hook in this block, or in a new block reached
+            // only for addresses in range.
+            let (hook_block, rest) = match &options.addresses {
+                None => (block, None),
+                Some(range) => {
+                    let rest = body.split_block(block, i);
+                    let mut bound = |body: &mut FunctionBody, value: u64, op: Operator| {
+                        let value = Operator::I64Const { value };
+                        let bound = add_op(body, block, &mut insts, value, &[], Some(Type::I64));
+                        add_op(body, block, &mut insts, op, &[addr, bound], Some(Type::I32))
+                    };
+                    let above = bound(body, range.start, Operator::I64GeU);
+                    let below = bound(body, range.end, Operator::I64LtU);
+                    let op = Operator::I32And;
+                    let cond = add_op(
+                        body,
+                        block,
+                        &mut insts,
+                        op,
+                        &[above, below],
+                        Some(Type::I32),
+                    );
+                    body.blocks[block].insts.append(&mut insts);
+
+                    let hook_block = body.add_block();
+                    body.blocks[block].terminator = Terminator::CondBr {
+                        cond,
+                        if_true: BlockTarget {
+                            block: hook_block,
+                            args: vec![],
+                        },
+                        if_false: BlockTarget {
+                            block: rest,
+                            args: vec![],
+                        },
+                    };
+                    body.blocks[hook_block].terminator = Terminator::Br {
+                        target: BlockTarget {
+                            block: rest,
+                            args: vec![],
+                        },
+                    };
+                    (hook_block, Some(rest))
+                }
+            };
+
+            let value = if access.is_store { args[1] } else { inst };
+            let size = i32_const(body, hook_block, &mut insts, access.size);
+            let is_store = i32_const(body, hook_block, &mut insts, access.is_store as u32);
+            let call = Operator::Call {
+                function_index: hooks.access[&access.ty],
+            };
+            let args = [addr, size, is_store, value];
+            add_op(body, hook_block, &mut insts, call, &args, None);
+
+            match rest {
+                None => {
+                    let n = insts.len();
+                    body.blocks[block].insts.splice(i..i, insts);
+                    i += n;
+                }
+                Some(rest) => {
+                    body.blocks[hook_block].insts = insts;
+                    block = rest;
+                    i = 0;
+                }
+            }
+        }
+    }
+    body.recompute_edges();
+}
@@ -0,0 +1,441 @@
+//! Identical function merging.
+//!
+//! Functions whose bodies are identical up to value and block
+//! numbering (or, for unexpanded bodies, byte-identical) are merged:
+//! every call, table element, export, and start-function reference to
+//! a duplicate is redirected to the first copy, and the duplicates
+//! are removed.
+//!
+//! Optionally, functions that differ only in some constants or
+//! callees are merged too: one shared body takes the differing
+//! constants (and, for callees, a selector for a switch over direct
+//! calls) as extra parameters, and each original function becomes a
+//! thunk that calls it.
+
+use crate::entity::{EntityRef, EntityVec};
+use crate::ir::{
+    ExportKind, Func, FuncDecl, FunctionBody, KeyOptions, Module, Signature, SignatureData,
+    StructuralKey, Terminator, Type, Value, ValueDef,
+};
+use crate::passes::devirt::{make_switch, Site};
+use crate::Operator;
+use anyhow::Result;
+use fxhash::FxHashMap;
+use rayon::prelude::*;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MergeOptions {
+    /// Also merge functions that differ only in constants or callees,
+    /// through a shared body with extra parameters.
+    pub merge_similar: bool,
+}
+
+/// Smallest body (in instructions) worth merging with thunks.
+const MIN_SIMILAR_INSTS: usize = 8;
+/// Most extra parameters a merged body may take.
+const MAX_HOLE_PARAMS: usize = 4;
+
+#[derive(PartialEq, Eq, Hash)]
+enum FuncKey<'a> {
+    Body(SignatureData, StructuralKey),
+    Lazy(SignatureData, &'a [u8]),
+}
+
+pub fn run(module: &mut Module, options: &MergeOptions) -> Result<()> {
+    let n_funcs = module.funcs.len();
+    let canonical = find_identical(module);
+    let n_dups = canonical
+        .entries()
+        .filter(|&(func, &canon)| func != canon)
+        .count();
+    log::debug!("merge_funcs: {} identical duplicates", n_dups);
+    if n_dups > 0 {
+        redirect(module, &canonical)?;
+    }
+
+    if options.merge_similar {
+        merge_similar(module, &canonical);
+    }
+
+    if n_dups > 0 {
+        let mut next = 0;
+        let funcs = (0..module.funcs.len())
+            .map(|func| {
+                let func = Func::new(func);
+                if func.index() < n_funcs && canonical[func] != func {
+                    Func::invalid()
+                } else {
+                    next += 1;
+                    Func::new(next - 1)
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut remap = crate::ir::EntityRemap::identity(module);
+        remap.funcs = EntityVec::from(funcs);
+        module.remap_entities(&remap)?;
+    }
+    Ok(())
+}
+
+/// Map each function to the first function identical to it.
+fn find_identical(module: &Module) -> EntityVec<Func, Func> {
+    let keys = module
+        .funcs
+        .entries()
+        .collect::<Vec<_>>()
+        .par_iter()
+        .map(|&(func, decl)| match decl {
+            FuncDecl::Body(sig, _, body) => Some(FuncKey::Body(
+                module.signatures[*sig].clone(),
+                StructuralKey::new(
+                    body,
+                    KeyOptions {
+                        self_func: Some(func),
+                        holes: false,
+                    },
+                ),
+            )),
+            FuncDecl::Lazy(sig, _, reader) => Some(FuncKey::Lazy(
+                module.signatures[*sig].clone(),
+                &module.orig_bytes[reader.range()],
+            )),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let mut first: FxHashMap<&FuncKey, Func> = FxHashMap::default();
+    let mut canonical = vec![];
+    for (func, key) in keys.iter().enumerate() {
+        let func = Func::new(func);
+        canonical.push(match key {
+            Some(key) => *first.entry(key).or_insert(func),
+            None => func,
+        });
+    }
+    EntityVec::from(canonical)
+}
+
+/// Redirect every reference to a function to its canonical copy.
+fn redirect(module: &mut Module, canonical: &EntityVec<Func, Func>) -> Result<()> {
+    let redirected = |func: Func| func.is_valid() && canonical[func] != func;
+
+    // Lazy bodies that call a duplicate must be expanded to rewrite
+    // the call.
+    let mut to_expand = vec![];
+    for (func, decl) in module.funcs.entries() {
+        if let FuncDecl::Lazy(..) = decl {
+            let mut calls_dup = false;
+            decl.visit_uses(|u| {
+                if let crate::ir::EntityUse::Call(callee) | crate::ir::EntityUse::FuncRef(callee) =
+                    u
+                {
+                    calls_dup |= redirected(callee);
+                }
+            })?;
+            if calls_dup {
+                to_expand.push(func);
+            }
+        }
+    }
+    for func in to_expand {
+        module.expand_func(func)?;
+    }
+
+    for decl in module.funcs.values_mut() {
+        if let Some(body) = decl.body_mut() {
+            for value_def in body.values.values_mut() {
+                if let ValueDef::Operator(Operator::Call { function_index }, ..) = value_def {
+                    if redirected(*function_index) {
+                        *function_index = canonical[*function_index];
+                    }
+                }
+            }
+        }
+    }
+    for table_data in module.tables.values_mut() {
+        if let Some(elts) = &mut table_data.func_elements {
+            for elt in elts.iter_mut() {
+                if redirected(*elt) {
+                    *elt = canonical[*elt];
+                }
+            }
+        }
+    }
+    for export in &mut module.exports {
+        if let ExportKind::Func(func) = &mut export.kind {
+            if redirected(*func) {
+                *func = canonical[*func];
+            }
+        }
+    }
+    if let Some(start) = module.start_func {
+        if redirected(start) {
+            module.start_func = Some(canonical[start]);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HoleValue {
+    Const(Type, u64),
+    Callee(Func),
+}
+
+fn hole_value(body: &FunctionBody, inst: Value) -> HoleValue {
+    match body.values[inst] {
+        ValueDef::Operator(Operator::I32Const { value }, ..) => {
+            HoleValue::Const(Type::I32, value as u64)
+        }
+        ValueDef::Operator(Operator::I64Const { value }, ..) => HoleValue::Const(Type::I64, value),
+        ValueDef::Operator(Operator::F32Const { value }, ..) => {
+            HoleValue::Const(Type::F32, value as u64)
+        }
+        ValueDef::Operator(Operator::F64Const { value }, ..) => HoleValue::Const(Type::F64, value),
+        ValueDef::Operator(Operator::Call { function_index }, ..) => {
+            HoleValue::Callee(function_index)
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn const_op(ty: Type, bits: u64) -> Operator {
+    match ty {
+        Type::I32 => Operator::I32Const { value: bits as u32 },
+        Type::I64 => Operator::I64Const { value: bits },
+        Type::F32 => Operator::F32Const { value: bits as u32 },
+        Type::F64 => Operator::F64Const { value: bits },
+        _ => unreachable!(),
+    }
+}
+
+fn find_or_add_signature(module: &mut Module, sig_data: SignatureData) -> Signature {
+    let existing = module
+        .signatures
+        .entries()
+        .find(|(_, data)| **data == sig_data)
+        .map(|(sig, _)| sig);
+    existing.unwrap_or_else(|| module.signatures.push(sig_data))
+}
+
+fn merge_similar(module: &mut Module, canonical: &EntityVec<Func, Func>) {
+    let keys = module
+        .funcs
+        .entries()
+        .collect::<Vec<_>>()
+        .par_iter()
+        .map(|&(func, decl)| {
+            let body = decl.body()?;
+            let n_insts = body.blocks.values().map(|b| b.insts.len()).sum::<usize>();
+            if canonical[func] != func
+                || n_insts < MIN_SIMILAR_INSTS
+                || !body.blocks[body.entry].preds.is_empty()
+            {
+                return None;
+            }
+            Some((
+                module.signatures[decl.sig()].clone(),
+                StructuralKey::new(
+                    body,
+                    KeyOptions {
+                        self_func: None,
+                        holes: true,
+                    },
+                ),
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    let mut group_of: FxHashMap<(&SignatureData, &[crate::ir::Token]), usize> =
+        FxHashMap::default();
+    let mut groups: Vec<Vec<Func>> = vec![];
+    for (func, key) in keys.iter().enumerate() {
+        if let Some((sig_data, key)) = key {
+            let group = *group_of
+                .entry((sig_data, &key.tokens[..]))
+                .or_insert_with(|| {
+                    groups.push(vec![]);
+                    groups.len() - 1
+                });
+            groups[group].push(Func::new(func));
+        }
+    }
+
+    for members in groups.into_iter().filter(|members| members.len() > 1) {
+        let holes = &keys[members[0].index()].as_ref().unwrap().1.holes;
+        let values = members
+            .iter()
+            .map(|&func| {
+                let body = module.funcs[func].body().unwrap();
+                keys[func.index()]
+                    .as_ref()
+                    .unwrap()
+                    .1
+                    .holes
+                    .iter()
+                    .map(|&inst| hole_value(body, inst))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let differing = (0..holes.len())
+            .filter(|&h| values.iter().any(|v| v[h] != values[0][h]))
+            .collect::<Vec<_>>();
+        if differing.is_empty() || differing.len() > MAX_HOLE_PARAMS {
+            continue;
+        }
+        merge_group(module, &members[..], holes, &values[..], &differing[..]);
+    }
+}
+
+fn merge_group(
+    module: &mut Module,
+    members: &[Func],
+    holes: &[Value],
+    values: &[Vec<HoleValue>],
+    differing: &[usize],
+) {
+    log::debug!(
+        "merge_funcs: merging {:?} with {} parameters",
+        members,
+        differing.len()
+    );
+    let first = members[0];
+    let member_sig = module.funcs[first].sig();
+    let mut body = module.funcs[first].body().unwrap().clone();
+
+    // Distinct callees of each callee hole, in member order; a
+    // member passes the index of its callee as the selector.
+    let callees = differing
+        .iter()
+        .map(|&h| {
+            let mut callees = vec![];
+            for v in values {
+                if let HoleValue::Callee(func) = v[h] {
+                    if !callees.contains(&func) {
+                        callees.push(func);
+                    }
+                }
+            }
+            callees
+        })
+        .collect::<Vec<_>>();
+
+    let params = differing
+        .iter()
+        .map(|&h| match values[0][h] {
+            HoleValue::Const(ty, _) => body.add_param(ty),
+            HoleValue::Callee(_) => body.add_param(Type::I32),
+        })
+        .collect::<Vec<_>>();
+
+    // Callee holes become switches. Split blocks from the end
+    // backward so that earlier positions stay valid.
+    let mut sites = differing
+        .iter()
+        .enumerate()
+        .filter(|&(_, &h)| matches!(values[0][h], HoleValue::Callee(_)))
+        .map(|(i, &h)| {
+            let inst = holes[h];
+            let block = body.value_blocks[inst];
+            let pos = body.blocks[block]
+                .insts
+                .iter()
+                .position(|&v| v == inst)
+                .unwrap();
+            (i, block, pos, inst)
+        })
+        .collect::<Vec<_>>();
+    sites.sort_by_key(|&(_, block, pos, _)| std::cmp::Reverse((block, pos)));
+    for (i, block, pos, inst) in sites {
+        let call_args = match &body.values[inst] {
+            &ValueDef::Operator(_, args, _) => body.arg_pool[args].to_vec(),
+            _ => unreachable!(),
+        };
+        let site = Site {
+            block,
+            pos,
+            inst,
+            targets: callees[i]
+                .iter()
+                .enumerate()
+                .map(|(j, &func)| (j as u32, Some(func)))
+                .collect(),
+        };
+        make_switch(&mut body, &site, params[i], &call_args[..], &callees[i][..]);
+    }
+    // Constant holes become uses of the new parameters.
+    for (i, &h) in differing.iter().enumerate() {
+        if let HoleValue::Const(..) = values[0][h] {
+            let inst = holes[h];
+            let block = body.value_blocks[inst];
+            body.blocks[block].insts.retain(|&v| v != inst);
+            body.values[inst] = ValueDef::Alias(params[i]);
+        }
+    }
+    body.recompute_edges();
+
+    let mut merged_sig_data = module.signatures[member_sig].clone();
+    merged_sig_data.params.extend(
+        params
+            .iter()
+            .map(|&param| body.values[param].ty(&body.type_pool).unwrap()),
+    );
+    let merged_sig = find_or_add_signature(module, merged_sig_data);
+    let merged_name = format!("{}$merged", module.funcs[first].name());
+    let merged = module
+        .funcs
+        .push(FuncDecl::Body(merged_sig, merged_name, Box::new(body)));
+
+    for (member_index, &member) in members.iter().enumerate() {
+        let mut thunk = FunctionBody::new(module, member_sig);
+        let entry = thunk.entry;
+        let mut args = thunk.blocks[entry]
+            .params
+            .iter()
+            .map(|&(_, param)| param)
+            .collect::<Vec<_>>();
+        for (i, &h) in differing.iter().enumerate() {
+            let op = match values[member_index][h] {
+                HoleValue::Const(ty, bits) => const_op(ty, bits),
+                HoleValue::Callee(func) => Operator::I32Const {
+                    value: callees[i].iter().position(|&f| f == func).unwrap() as u32,
+                },
+            };
+            let ty = match op {
+                Operator::I32Const { .. } => Type::I32,
+                Operator::I64Const { .. } => Type::I64,
+                Operator::F32Const { .. } => Type::F32,
+                _ => Type::F64,
+            };
+            let tys = thunk.single_type_list(ty);
+            let value = thunk.add_value(ValueDef::Operator(op, Default::default(), tys));
+            thunk.append_to_block(entry, value);
+            args.push(value);
+        }
+        let rets = thunk.rets.clone();
+        let args = thunk.arg_pool.from_iter(args.into_iter());
+        let tys = thunk.type_pool.from_iter(rets.iter().copied());
+        let call = thunk.add_value(ValueDef::Operator(
+            Operator::Call {
+                function_index: merged,
+            },
+            args,
+            tys,
+        ));
+        thunk.append_to_block(entry, call);
+        let results = if rets.len() == 1 {
+            vec![call]
+        } else {
+            rets.iter()
+                .enumerate()
+                .map(|(i, &ty)| {
+                    let value = thunk.add_value(ValueDef::PickOutput(call, i as u32, ty));
+                    thunk.append_to_block(entry, value);
+                    value
+                })
+                .collect()
+        };
+        thunk.set_terminator(entry, Terminator::Return { values: results });
+        module.replace_body(member, thunk);
+    }
+}
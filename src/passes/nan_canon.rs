@@ -0,0 +1,121 @@
+//! NaN canonicalization, for deterministic floats across engines.
+//!
+//! The bits of a NaN produced by an arithmetic float operator are
+//! nondeterministic in Wasm; everything else that produces a float
+//! (constants, loads, conversions, `abs`, `neg`, `copysign`, ...) is
+//! deterministic given deterministic inputs. So replacing the result
+//! `x` of each arithmetic operator with `select(canonical_nan, x, x !=
+//! x)` makes all float values deterministic.
+//!
+//! Results that provably cannot be NaN are left alone: those of
+//! operators whose inputs are not NaN and that only produce a NaN from
+//! a NaN input (rounding, `min`, `max`, promotion, demotion, and the
+//! bitwise operators), starting from non-NaN constants and
+//! conversions from integers.
+
+use crate::cfg::CFGInfo;
+use crate::entity::PerEntity;
+use crate::interp::{CANONICAL_NAN_F32, CANONICAL_NAN_F64};
+use crate::ir::{FunctionBody, Type, Value, ValueDef};
+use crate::op_traits::op_produces_arithmetic_nan;
+use crate::pool::ListRef;
+use crate::Operator;
+
+/// Can `value` provably not be NaN, given which of the values
+/// defined before it cannot?
+fn not_nan(body: &FunctionBody, not_nan: &PerEntity<Value, bool>, value: Value) -> bool {
+    let (op, args) = match &body.values[value] {
+        ValueDef::Operator(op, args, _) => (op, &body.arg_pool[*args]),
+        _ => return false,
+    };
+    match op {
+        Operator::F32Const { value } => !f32::from_bits(*value).is_nan(),
+        Operator::F64Const { value } => !f64::from_bits(*value).is_nan(),
+        Operator::F32ConvertI32S
+        | Operator::F32ConvertI32U
+        | Operator::F32ConvertI64S
+        | Operator::F32ConvertI64U
+        | Operator::F64ConvertI32S
+        | Operator::F64ConvertI32U
+        | Operator::F64ConvertI64S
+        | Operator::F64ConvertI64U => true,
+        Operator::F32Abs
+        | Operator::F32Neg
+        | Operator::F32Ceil
+        | Operator::F32Floor
+        | Operator::F32Trunc
+        | Operator::F32Nearest
+        | Operator::F32DemoteF64
+        | Operator::F64Abs
+        | Operator::F64Neg
+        | Operator::F64Ceil
+        | Operator::F64Floor
+        | Operator::F64Trunc
+        | Operator::F64Nearest
+        | Operator::F64PromoteF32
+        | Operator::F32Copysign
+        | Operator::F64Copysign => not_nan[args[0]],
+        Operator::F32Min | Operator::F32Max | Operator::F64Min | Operator::F64Max => {
+            not_nan[args[0]] && not_nan[args[1]]
+        }
+        _ => false,
+    }
+}
+
+pub fn run(body: &mut FunctionBody) {
+    let cfg = CFGInfo::new(body);
+    let mut known_not_nan = PerEntity::default();
+    for &block in cfg.rpo.values() {
+        let mut insts = Vec::with_capacity(body.blocks[block].insts.len());
+        for inst in std::mem::take(&mut body.blocks[block].insts) {
+            if not_nan(body, &known_not_nan, inst) {
+                known_not_nan[inst] = true;
+                insts.push(inst);
+                continue;
+            }
+            let (op, ty) = match &body.values[inst] {
+                ValueDef::Operator(op, _, tys) if op_produces_arithmetic_nan(op) => {
+                    (*op, body.type_pool[*tys][0])
+                }
+                _ => {
+                    insts.push(inst);
+                    continue;
+                }
+            };
+            let (ne, nan) = match ty {
+                Type::F32 => (
+                    Operator::F32Ne,
+                    Operator::F32Const {
+                        value: CANONICAL_NAN_F32,
+                    },
+                ),
+                Type::F64 => (
+                    Operator::F64Ne,
+                    Operator::F64Const {
+                        value: CANONICAL_NAN_F64,
+                    },
+                ),
+                _ => unreachable!("{} produces a {}", op, ty),
+            };
+
+            // Move the operator to a new value, and make `inst` the
+            // canonicalized result, so that its uses see that.
+            let def = std::mem::take(&mut body.values[inst]);
+            let result = body.add_value(def);
+            let i32_ty = body.single_type_list(Type::I32);
+            let args = body.arg_pool.double(result, result);
+            let is_nan = body.add_value(ValueDef::Operator(ne, args, i32_ty));
+            let nan_ty = body.single_type_list(ty);
+            let nan = body.add_value(ValueDef::Operator(nan, ListRef::default(), nan_ty));
+            let args = body.arg_pool.triple(nan, result, is_nan);
+            body.values[inst] = ValueDef::Operator(Operator::Select, args, nan_ty);
+            for value in [result, is_nan, nan] {
+                body.value_blocks[value] = block;
+                body.source_locs[value] = body.source_locs[inst];
+                insts.push(value);
+            }
+            insts.push(inst);
+        }
+        body.blocks[block].insts = insts;
+    }
+}
@@ -0,0 +1,285 @@
+//! Function-level profiling instrumentation.
+//!
+//! Every function body calls hooks imported from the `waffle_prof`
+//! module: `enter(func_id)` on entry, and `exit(func_id)` before
+//! every return and every `unreachable` trap. Traps inside
+//! instructions (e.g. out-of-bounds accesses or division by zero)
+//! and traps in callees are not seen, so a host that unwinds on a
+//! trap should pop its shadow stack itself. Optionally, every call
+//! site also calls `call(site_id)` just before the call, for counting
+//! calls per site.
+//!
+//! A function's id is its index before instrumentation, which adds
+//! the hooks as imports and so renumbers the module's own functions.
+//! The returned `ProfileMap` maps ids back to functions and names,
+//! and its `Display` output is a sidecar file for profile tools.
+
+use crate::cfg::CFGInfo;
+use crate::entity::EntityRef;
+use crate::ir::{
+    Block, BlockTarget, Func, FunctionBody, Module, SignatureData, Terminator, Type, Value,
+    ValueDef,
+};
+use crate::pool::ListRef;
+use crate::Operator;
+use anyhow::Result;
+use rayon::prelude::*;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ProfileOptions {
+    /// Call the `enter` and `exit` hooks.
+    pub entry_exit: bool,
+    /// Call the `call` hook at every call site.
+    pub call_sites: bool,
+}
+
+impl Default for ProfileOptions {
+    fn default() -> Self {
+        ProfileOptions {
+            entry_exit: true,
+            call_sites: false,
+        }
+    }
+}
+
+/// A profiled function: its id, its index in the instrumented
+/// module, and its name.
+#[derive(Clone, Debug)]
+pub struct ProfiledFunc {
+    pub id: u32,
+    pub func: Func,
+    pub name: String,
+}
+
+/// A counted call site: the call `value` in the function with id
+/// `caller`, and the id of the callee if the call is direct.
+#[derive(Clone, Debug)]
+pub struct ProfiledCallSite {
+    pub id: u32,
+    pub caller: u32,
+    pub value: Value,
+    pub callee: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ProfileMap {
+    pub funcs: Vec<ProfiledFunc>,
+    pub call_sites: Vec<ProfiledCallSite>,
+}
+
+impl std::fmt::Display for ProfileMap {
+    /// One tab-separated line per function (`func`, id, name) and per
+    /// call site (`call`, id, caller id, call value, callee id or
+    /// `-`).
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for func in &self.funcs {
+            writeln!(f, "func\t{}\t{}", func.id, func.name)?;
+        }
+        for site in &self.call_sites {
+            let callee = site
+                .callee
+                .map(|callee| callee.to_string())
+                .unwrap_or_else(|| "-".to_owned());
+            writeln!(
+                f,
+                "call\t{}\t{}\t{}\t{}",
+                site.id, site.caller, site.value, callee
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The imported hooks, by index in the instrumented module.
+#[derive(Clone, Copy)]
+struct Hooks {
+    enter: Option<Func>,
+    exit: Option<Func>,
+    call: Option<Func>,
+}
+
+pub fn run(module: &mut Module, options: &ProfileOptions) -> Result<ProfileMap> {
+    module.expand_all_funcs()?;
+
+    let mut map = ProfileMap::default();
+    for (func, decl) in module.funcs.entries() {
+        if decl.body().is_some() {
+            map.funcs.push(ProfiledFunc {
+                id: func.index() as u32,
+                func,
+                name: decl.name().to_owned(),
+            });
+        }
+    }
+    if map.funcs.is_empty() || !(options.entry_exit || options.call_sites) {
+        return Ok(map);
+    }
+
+    // Find call sites before adding the hooks, so that callee ids are
+    // pre-instrumentation indices.
+    let mut sites_by_func = vec![];
+    for profiled in &map.funcs {
+        let body = module.funcs[profiled.func].body().unwrap();
+        let calls = if options.call_sites {
+            call_sites(body)
+        } else {
+            vec![]
+        };
+        for &(value, callee) in &calls {
+            map.call_sites.push(ProfiledCallSite {
+                id: map.call_sites.len() as u32,
+                caller: profiled.id,
+                value,
+                callee: callee.map(|callee| callee.index() as u32),
+            });
+        }
+        sites_by_func.push(calls.len());
+    }
+
+    let sig = module.find_or_add_signature(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![],
+    });
+    let mut imports = vec![];
+    if options.entry_exit {
+        imports.push(("waffle_prof", "enter", sig));
+        imports.push(("waffle_prof", "exit", sig));
+    }
+    if options.call_sites {
+        imports.push(("waffle_prof", "call", sig));
+    }
+    let hook_funcs = module.add_func_imports(&imports[..])?;
+    let mut hook_funcs = hook_funcs.into_iter();
+    let hooks = Hooks {
+        enter: options.entry_exit.then(|| hook_funcs.next().unwrap()),
+        exit: options.entry_exit.then(|| hook_funcs.next().unwrap()),
+        call: options.call_sites.then(|| hook_funcs.next().unwrap()),
+    };
+    for profiled in &mut map.funcs {
+        profiled.func = Func::new(profiled.func.index() + imports.len());
+    }
+
+    let mut first_site = 0;
+    let mut work = vec![];
+    for (profiled, &n_sites) in map.funcs.iter().zip(sites_by_func.iter()) {
+        work.push((profiled.func, profiled.id, first_site));
+        first_site += n_sites;
+    }
+    let mut bodies = module
+        .funcs
+        .entries_mut()
+        .filter_map(|(func, decl)| decl.body_mut().map(|body| (func, body)))
+        .collect::<Vec<_>>();
+    bodies.par_iter_mut().zip(work.par_iter()).for_each(
+        |((func, body), &(profiled, id, first_site))| {
+            debug_assert_eq!(*func, profiled);
+            instrument(body, hooks, id, first_site as u32);
+        },
+    );
+
+    Ok(map)
+}
+
+/// Calls in reachable blocks, in block order, with their direct
+/// callees.
+fn call_sites(body: &FunctionBody) -> Vec<(Value, Option<Func>)> {
+    let cfg = CFGInfo::new(body);
+    let mut calls = vec![];
+    for &block in cfg.rpo.values() {
+        for &inst in &body.blocks[block].insts {
+            match body.values[inst] {
+                ValueDef::Operator(Operator::Call { function_index }, ..) => {
+                    calls.push((inst, Some(function_index)))
+                }
+                ValueDef::Operator(Operator::CallIndirect { .. }, ..) => calls.push((inst, None)),
+                _ => {}
+            }
+        }
+    }
+    calls
+}
+
+/// Build a call to `hook` with a constant argument, in `block`.
+fn hook_call(body: &mut FunctionBody, block: Block, hook: Func, arg: u32) -> [Value; 2] {
+    let ty = body.single_type_list(Type::I32);
+    let arg = body.add_value(ValueDef::Operator(
+        Operator::I32Const { value: arg },
+        ListRef::default(),
+        ty,
+    ));
+    let args = body.arg_pool.single(arg);
+    let call = body.add_value(ValueDef::Operator(
+        Operator::Call {
+            function_index: hook,
+        },
+        args,
+        ListRef::default(),
+    ));
+    body.value_blocks[arg] = block;
+    body.value_blocks[call] = block;
+    [arg, call]
+}
+
+fn instrument(body: &mut FunctionBody, hooks: Hooks, id: u32, first_site: u32) {
+    let cfg = CFGInfo::new(body);
+
+    if let Some(call_hook) = hooks.call {
+        let mut site = first_site;
+        for &block in cfg.rpo.values() {
+            let insts = std::mem::take(&mut body.blocks[block].insts);
+            let mut instrumented = Vec::with_capacity(insts.len());
+            for inst in insts {
+                if matches!(
+                    body.values[inst],
+                    ValueDef::Operator(Operator::Call { .. }, ..)
+                        | ValueDef::Operator(Operator::CallIndirect { .. }, ..)
+                ) {
+                    instrumented.extend(hook_call(body, block, call_hook, site));
+                    site += 1;
+                }
+                instrumented.push(inst);
+            }
+            body.blocks[block].insts = instrumented;
+        }
+    }
+
+    if let Some(exit) = hooks.exit {
+        for &block in cfg.rpo.values() {
+            if matches!(
+                body.blocks[block].terminator,
+                Terminator::Return { .. } | Terminator::Unreachable
+            ) {
+                let call = hook_call(body, block, exit, id);
+                body.blocks[block].insts.extend(call);
+            }
+        }
+    }
+
+    if let Some(enter) = hooks.enter {
+        // Enter from a new entry block, in case the old one is also a
+        // loop header.
+        let old_entry = body.entry;
+        let entry = body.add_block();
+        let params = body.blocks[old_entry].params.clone();
+        let args = params
+            .into_iter()
+            .map(|(ty, param)| {
+                let arg = body.add_blockparam(entry, ty);
+                body.value_locals[arg] = body.value_locals[param];
+                arg
+            })
+            .collect::<Vec<_>>();
+        let call = hook_call(body, entry, enter, id);
+        body.blocks[entry].insts.extend(call);
+        body.set_terminator(
+            entry,
+            Terminator::Br {
+                target: BlockTarget {
+                    block: old_entry,
+                    args,
+                },
+            },
+        );
+        body.entry = entry;
+    }
+}
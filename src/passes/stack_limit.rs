@@ -0,0 +1,414 @@
+//! Stack-depth limiting: identical stack-overflow behavior across
+//! engines.
+//!
+//! Every function body adds its frame cost to a depth counter on
+//! entry, trapping if that would take the depth over the limit, and
+//! subtracts it again before every return. The depth is a mutable
+//! `i32` global exported as `__stack_depth`; a trap leaves it as it
+//! was in the trapping frame, so a host that catches traps and calls
+//! in again should reset it to zero first.
+//!
+//! A frame's cost is an estimate of its size in bytes: by default, a
+//! fixed base plus the sizes of the locals the backend would
+//! allocate for the body. Imported functions are charged a fixed cost
+//! through a wrapper function, which replaces the import in direct
+//! calls and in the elements of tables, so that indirect calls see
+//! the same costs as direct ones. Calls from tables the host modifies
+//! go to whatever the host put there, and are not charged.
+
+use crate::backend::localify::Localifier;
+use crate::backend::reducify::reducify;
+use crate::backend::treeify::Trees;
+use crate::backend::OptimizeFor;
+use crate::cfg::CFGInfo;
+use crate::entity::EntityRef;
+use crate::ir::{
+    Block, BlockTarget, Export, ExportKind, Func, FuncDecl, FunctionBody, Global, GlobalData,
+    Module, Terminator, Type, Value, ValueDef,
+};
+use crate::pool::ListRef;
+use crate::Operator;
+use anyhow::{bail, Result};
+use fxhash::FxHashMap;
+use rayon::prelude::*;
+
+/// How to compute the cost of a function's frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameCost {
+    /// `base` plus the size in bytes of each local the backend would
+    /// allocate, parameters included.
+    Locals { base: u32 },
+    /// The same cost for every function.
+    Fixed(u32),
+}
+
+impl Default for FrameCost {
+    fn default() -> Self {
+        FrameCost::Locals { base: 16 }
+    }
+}
+
+/// Where the depth limit comes from.
+#[derive(Clone, Debug)]
+pub enum StackLimit {
+    /// A constant limit.
+    Fixed(u32),
+    /// An immutable `i32` global imported from the given module and
+    /// name.
+    Import { module: String, name: String },
+}
+
+#[derive(Clone, Debug)]
+pub struct StackLimitOptions {
+    pub frame_cost: FrameCost,
+    /// Cost of a call to an imported function. Zero leaves calls to
+    /// imports unwrapped.
+    pub import_cost: u32,
+    pub limit: StackLimit,
+}
+
+impl Default for StackLimitOptions {
+    fn default() -> Self {
+        StackLimitOptions {
+            frame_cost: FrameCost::default(),
+            import_cost: 64,
+            limit: StackLimit::Fixed(1 << 20),
+        }
+    }
+}
+
+/// Instrument every function body. Returns the depth global.
+pub fn run(module: &mut Module, options: &StackLimitOptions) -> Result<Global> {
+    if module
+        .exports
+        .iter()
+        .any(|export| export.name == "__stack_depth")
+    {
+        bail!("Module already exports '__stack_depth'");
+    }
+    module.expand_all_funcs()?;
+
+    let limit = match &options.limit {
+        StackLimit::Fixed(limit) => Limit::Const(*limit),
+        StackLimit::Import { module: from, name } => {
+            let global = module.add_global_import(
+                from,
+                name,
+                GlobalData {
+                    ty: Type::I32,
+                    value: None,
+                    mutable: false,
+                },
+            )?;
+            module.global_names[global] = Some(name.clone());
+            Limit::Global(global)
+        }
+    };
+    let depth = module.globals.push(GlobalData {
+        ty: Type::I32,
+        value: Some(0),
+        mutable: true,
+    });
+    module.global_names[depth] = Some("__stack_depth".to_owned());
+    module.exports.push(Export {
+        name: "__stack_depth".to_owned(),
+        kind: ExportKind::Global(depth),
+    });
+
+    let mut costs = module
+        .funcs
+        .values()
+        .collect::<Vec<_>>()
+        .par_iter()
+        .map(|decl| decl.body().map(|body| frame_cost(options.frame_cost, body)))
+        .collect::<Vec<_>>();
+
+    if options.import_cost > 0 {
+        let wrappers = wrap_imports(module);
+        costs.resize(module.funcs.len(), Some(options.import_cost));
+        for table in module.tables.values_mut() {
+            // Uninitialized slots hold `Func::invalid()`.
+            for func in table
+                .func_elements
+                .iter_mut()
+                .flatten()
+                .filter(|func| func.is_valid())
+            {
+                if let Some(&wrapper) = wrappers.get(func) {
+                    *func = wrapper;
+                }
+            }
+        }
+        let n_funcs = costs.len() - wrappers.len();
+        module
+            .funcs
+            .values_mut()
+            .take(n_funcs)
+            .filter_map(|decl| decl.body_mut())
+            .collect::<Vec<_>>()
+            .par_iter_mut()
+            .for_each(|body| redirect_calls(&wrappers, body));
+    }
+
+    module
+        .funcs
+        .values_mut()
+        .zip(costs)
+        .filter_map(|(decl, cost)| decl.body_mut().map(|body| (body, cost.unwrap())))
+        .collect::<Vec<_>>()
+        .par_iter_mut()
+        .for_each(|(body, cost)| instrument(depth, limit, *cost, body));
+    Ok(depth)
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Limit {
+    Const(u32),
+    Global(Global),
+}
+
+fn type_size(ty: Type) -> u32 {
+    match ty {
+        Type::I32 | Type::F32 => 4,
+        Type::I64 | Type::F64 | Type::FuncRef => 8,
+        Type::V128 => 16,
+    }
+}
+
+fn frame_cost(frame_cost: FrameCost, body: &FunctionBody) -> u32 {
+    match frame_cost {
+        FrameCost::Fixed(cost) => cost,
+        FrameCost::Locals { base } => {
+            let body = reducify(body);
+            let cfg = CFGInfo::new(&body);
+            let trees = Trees::compute(&body, OptimizeFor::default());
+            let locals = Localifier::compute(&body, &cfg, &trees);
+            locals
+                .locals
+                .values()
+                .fold(base, |cost, &ty| cost.saturating_add(type_size(ty)))
+        }
+    }
+}
+
+/// Add a wrapper function for each imported function that is called
+/// directly or is in a table. Returns the wrappers by import.
+fn wrap_imports(module: &mut Module) -> FxHashMap<Func, Func> {
+    let mut used = module
+        .tables
+        .values()
+        .flat_map(|table| table.func_elements.iter().flatten().copied())
+        .filter(|func| func.is_valid())
+        .collect::<Vec<_>>();
+    for decl in module.funcs.values() {
+        if let Some(body) = decl.body() {
+            for value in body.values.values() {
+                if let ValueDef::Operator(Operator::Call { function_index }, ..) = value {
+                    used.push(*function_index);
+                }
+            }
+        }
+    }
+    used.sort();
+    used.dedup();
+
+    let mut wrappers = FxHashMap::default();
+    for func in used {
+        let (sig, name) = match &module.funcs[func] {
+            FuncDecl::Import(sig, name) => (*sig, name.clone()),
+            _ => continue,
+        };
+        let mut body = FunctionBody::new(module, sig);
+        let args = body.blocks[body.entry]
+            .params
+            .iter()
+            .map(|&(_, param)| param)
+            .collect::<Vec<_>>();
+        let args = body.arg_pool.from_iter(args.into_iter());
+        let rets = body.rets.clone();
+        let tys = body.type_pool.from_iter(rets.iter().copied());
+        let call = body.add_value(ValueDef::Operator(
+            Operator::Call {
+                function_index: func,
+            },
+            args,
+            tys,
+        ));
+        body.append_to_block(body.entry, call);
+        let values = if rets.len() == 1 {
+            vec![call]
+        } else {
+            rets.iter()
+                .enumerate()
+                .map(|(i, &ty)| {
+                    let pick = body.add_value(ValueDef::PickOutput(call, i as u32, ty));
+                    body.append_to_block(body.entry, pick);
+                    pick
+                })
+                .collect()
+        };
+        body.set_terminator(body.entry, Terminator::Return { values });
+        let wrapper = module.funcs.push(FuncDecl::Body(
+            sig,
+            format!("{}$stack_limit", name),
+            Box::new(body),
+        ));
+        wrappers.insert(func, wrapper);
+    }
+    wrappers
+}
+
+fn redirect_calls(wrappers: &FxHashMap<Func, Func>, body: &mut FunctionBody) {
+    for value in body.values.values_mut() {
+        if let ValueDef::Operator(Operator::Call { function_index }, ..) = value {
+            if let Some(&wrapper) = wrappers.get(function_index) {
+                *function_index = wrapper;
+            }
+        }
+    }
+}
+
+fn add_op(
+    body: &mut FunctionBody,
+    block: Block,
+    op: Operator,
+    args: &[Value],
+    ty: Option<Type>,
+) -> Value {
+    let args = body.arg_pool.from_iter(args.iter().copied());
+    let tys = match ty {
+        Some(ty) => body.single_type_list(ty),
+        None => ListRef::default(),
+    };
+    let value = body.add_value(ValueDef::Operator(op, args, tys));
+    body.append_to_block(block, value);
+    value
+}
+
+fn instrument(depth: Global, limit: Limit, cost: u32, body: &mut FunctionBody) {
+    let cfg = CFGInfo::new(body);
+    let returns = cfg
+        .rpo
+        .values()
+        .copied()
+        .filter(|&block| matches!(body.blocks[block].terminator, Terminator::Return { .. }))
+        .collect::<Vec<_>>();
+    for block in returns {
+        let old = add_op(
+            body,
+            block,
+            Operator::GlobalGet {
+                global_index: depth,
+            },
+            &[],
+            Some(Type::I32),
+        );
+        let cost = add_op(
+            body,
+            block,
+            Operator::I32Const { value: cost },
+            &[],
+            Some(Type::I32),
+        );
+        let new = add_op(body, block, Operator::I32Sub, &[old, cost], Some(Type::I32));
+        add_op(
+            body,
+            block,
+            Operator::GlobalSet {
+                global_index: depth,
+            },
+            &[new],
+            None,
+        );
+    }
+
+    // Check and charge in a new entry block, in case the old one is
+    // also a loop header.
+    let old_entry = body.entry;
+    let entry = body.add_block();
+    let params = body.blocks[old_entry].params.clone();
+    let args = params
+        .into_iter()
+        .map(|(ty, param)| {
+            let arg = body.add_blockparam(entry, ty);
+            body.value_locals[arg] = body.value_locals[param];
+            arg
+        })
+        .collect::<Vec<_>>();
+    let old = add_op(
+        body,
+        entry,
+        Operator::GlobalGet {
+            global_index: depth,
+        },
+        &[],
+        Some(Type::I32),
+    );
+    let cost = add_op(
+        body,
+        entry,
+        Operator::I32Const { value: cost },
+        &[],
+        Some(Type::I32),
+    );
+    let new = add_op(body, entry, Operator::I32Add, &[old, cost], Some(Type::I32));
+    let limit = match limit {
+        Limit::Const(value) => add_op(
+            body,
+            entry,
+            Operator::I32Const { value },
+            &[],
+            Some(Type::I32),
+        ),
+        Limit::Global(global_index) => add_op(
+            body,
+            entry,
+            Operator::GlobalGet { global_index },
+            &[],
+            Some(Type::I32),
+        ),
+    };
+    let overflow = add_op(
+        body,
+        entry,
+        Operator::I32GtU,
+        &[new, limit],
+        Some(Type::I32),
+    );
+    let overflow_at = body.add_block();
+    body.set_terminator(overflow_at, Terminator::Unreachable);
+    let charge = body.add_block();
+    add_op(
+        body,
+        charge,
+        Operator::GlobalSet {
+            global_index: depth,
+        },
+        &[new],
+        None,
+    );
+    body.set_terminator(
+        charge,
+        Terminator::Br {
+            target: BlockTarget {
+                block: old_entry,
+                args,
+            },
+        },
+    );
+    body.set_terminator(
+        entry,
+        Terminator::CondBr {
+            cond: overflow,
+            if_true: BlockTarget {
+                block: overflow_at,
+                args: vec![],
+            },
+            if_false: BlockTarget {
+                block: charge,
+                args: vec![],
+            },
+        },
+    );
+    body.entry = entry;
+}
@@ -1,7 +1,12 @@
-//! Trace-insertion pass.
+//! Trace-insertion pass, and lowering of traces to calls to imported
+//! hooks.
 
 use crate::entity::EntityRef;
 use crate::ir::*;
+use crate::ops::Operator;
+use crate::pool::ListRef;
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
 
 pub fn run(body: &mut FunctionBody) {
     for (block, data) in body.blocks.entries_mut() {
@@ -14,3 +19,110 @@ pub fn run(body: &mut FunctionBody) {
         data.insts.insert(0, value);
     }
 }
+
+/// The types of a trace's arguments.
+fn trace_types(body: &FunctionBody, args: ListRef<Value>) -> Vec<Type> {
+    body.arg_pool[args]
+        .iter()
+        .map(|&arg| {
+            let arg = body.resolve_alias(arg);
+            body.values[arg].ty(&body.type_pool).unwrap()
+        })
+        .collect()
+}
+
+/// The name of the hook for traces with arguments of types `tys`:
+/// `trace`, then each type, separated by underscores.
+fn hook_name(tys: &[Type]) -> String {
+    std::iter::once("trace".to_owned())
+        .chain(tys.iter().map(|ty| ty.to_string()))
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// Replace every trace with a call to a hook imported from the
+/// `waffle` module, so that traced modules run on any engine rather
+/// than only in the interpreter. There is one hook per list of
+/// argument types (e.g. `waffle.trace_i32_i64`), taking the trace ID
+/// followed by the trace's arguments and returning nothing.
+pub fn lower_to_imports(module: &mut Module<'_>) -> Result<()> {
+    let mut hook_types = BTreeSet::new();
+    for body in module.funcs.values().filter_map(|decl| decl.body()) {
+        for block in body.blocks.values() {
+            for &inst in &block.insts {
+                if let ValueDef::Trace(_, args) = body.values[inst] {
+                    hook_types.insert(trace_types(body, args));
+                }
+            }
+        }
+    }
+    if hook_types.is_empty() {
+        return Ok(());
+    }
+
+    let names = hook_types
+        .iter()
+        .map(|tys| hook_name(tys))
+        .collect::<Vec<_>>();
+    let imports = hook_types
+        .iter()
+        .zip(names.iter())
+        .map(|(tys, name)| {
+            let params = std::iter::once(Type::I32).chain(tys.iter().copied());
+            let sig = module.find_or_add_signature(SignatureData {
+                params: params.collect(),
+                returns: vec![],
+            });
+            ("waffle", &name[..], sig)
+        })
+        .collect::<Vec<_>>();
+    let funcs = module.add_func_imports(&imports[..])?;
+    let hooks: BTreeMap<Vec<Type>, Func> = hook_types.into_iter().zip(funcs).collect();
+
+    for decl in module.funcs.values_mut() {
+        let has_traces = decl.body().is_some_and(|body| {
+            body.values
+                .values()
+                .any(|def| matches!(def, ValueDef::Trace(..)))
+        });
+        if has_traces {
+            lower_body(decl.body_mut().unwrap(), &hooks);
+        }
+    }
+    Ok(())
+}
+
+fn lower_body(body: &mut FunctionBody, hooks: &BTreeMap<Vec<Type>, Func>) {
+    for block in body.blocks.iter() {
+        let insts = std::mem::take(&mut body.blocks[block].insts);
+        let mut lowered = Vec::with_capacity(insts.len());
+        for inst in insts {
+            if let ValueDef::Trace(id, args) = body.values[inst] {
+                let hook = hooks[&trace_types(body, args)];
+                let ty = body.single_type_list(Type::I32);
+                let id = body.add_value(ValueDef::Operator(
+                    Operator::I32Const { value: id as u32 },
+                    ListRef::default(),
+                    ty,
+                ));
+                body.value_blocks[id] = block;
+                lowered.push(id);
+
+                let args = std::iter::once(id)
+                    .chain(body.arg_pool[args].iter().copied())
+                    .collect::<Vec<_>>();
+                let args = body.arg_pool.from_iter(args.into_iter());
+                body.values[inst] = ValueDef::Operator(
+                    Operator::Call {
+                        function_index: hook,
+                    },
+                    args,
+                    ListRef::default(),
+                );
+                body.value_blocks[inst] = block;
+            }
+            lowered.push(inst);
+        }
+        body.blocks[block].insts = lowered;
+    }
+}
@@ -0,0 +1,183 @@
+//! An asyncified module must unwind and rewind across direct and
+//! indirect calls to an async import, and compute what it computes
+//! when the import is synchronous.
+
+use waffle::passes::asyncify::AsyncifyOptions;
+use waffle::{
+    ConstVal, Export, ExportKind, FrontendOptions, Func, InterpContext, InterpResult, Memory,
+    Module,
+};
+use wasm_encoder::{
+    CodeSection, ConstExpr, ElementSection, Elements, EntityType, ExportSection, Function,
+    FunctionSection, ImportSection, Instruction, MemorySection, MemoryType, TableSection,
+    TableType, TypeSection, ValType,
+};
+
+/// `work(x)` keeps an `i64` live across a direct call to the async
+/// import `sleep` and an indirect call to `helper`, which calls
+/// `sleep` too. The indirect call's type is a duplicate of
+/// `helper`'s at a different index.
+fn sleepy_module() -> Vec<u8> {
+    let mut module = wasm_encoder::Module::new();
+    let mut types = TypeSection::new();
+    types.function([ValType::I32], [ValType::I32]);
+    types.function([ValType::I32], [ValType::I32]);
+    module.section(&types);
+    let mut imports = ImportSection::new();
+    imports.import("env", "sleep", EntityType::Function(0));
+    module.section(&imports);
+    let mut funcs = FunctionSection::new();
+    funcs.function(0);
+    funcs.function(0);
+    module.section(&funcs);
+    let mut tables = TableSection::new();
+    tables.table(TableType {
+        element_type: ValType::FuncRef,
+        minimum: 1,
+        maximum: Some(1),
+    });
+    module.section(&tables);
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: 1,
+        maximum: None,
+        memory64: false,
+        shared: false,
+    });
+    module.section(&memories);
+    let mut exports = ExportSection::new();
+    exports.export("work", wasm_encoder::ExportKind::Func, 2);
+    module.section(&exports);
+    let mut elements = ElementSection::new();
+    elements.active(
+        None,
+        &ConstExpr::i32_const(0),
+        ValType::FuncRef,
+        Elements::Functions(&[1]),
+    );
+    module.section(&elements);
+
+    let mut code = CodeSection::new();
+    let mut helper = Function::new([]);
+    helper
+        .instruction(&Instruction::LocalGet(0))
+        .instruction(&Instruction::I32Const(100))
+        .instruction(&Instruction::I32Add)
+        .instruction(&Instruction::Call(0))
+        .instruction(&Instruction::End);
+    code.function(&helper);
+    let mut work = Function::new([(1, ValType::I64)]);
+    work.instruction(&Instruction::LocalGet(0))
+        .instruction(&Instruction::I64ExtendI32U)
+        .instruction(&Instruction::I64Const(3))
+        .instruction(&Instruction::I64Mul)
+        .instruction(&Instruction::LocalSet(1))
+        .instruction(&Instruction::LocalGet(0))
+        .instruction(&Instruction::Call(0))
+        .instruction(&Instruction::LocalGet(0))
+        .instruction(&Instruction::I32Const(0))
+        .instruction(&Instruction::CallIndirect { ty: 1, table: 0 })
+        .instruction(&Instruction::I32Add)
+        .instruction(&Instruction::LocalGet(1))
+        .instruction(&Instruction::I32WrapI64)
+        .instruction(&Instruction::I32Add)
+        .instruction(&Instruction::End);
+    code.function(&work);
+    module.section(&code);
+    module.finish()
+}
+
+fn export(module: &Module, name: &str) -> Func {
+    match module.exports.iter().find(|export| export.name == name) {
+        Some(Export {
+            kind: ExportKind::Func(func),
+            ..
+        }) => *func,
+        _ => panic!("No exported function '{}'", name),
+    }
+}
+
+fn call(ctx: &mut InterpContext, module: &Module, name: &str, args: &[ConstVal]) -> ConstVal {
+    let results = ctx.call(module, export(module, name), args).ok().unwrap();
+    results.first().copied().unwrap_or(ConstVal::None)
+}
+
+fn returns(value: u32) -> Option<InterpResult> {
+    Some(InterpResult::Ok(
+        std::iter::once(ConstVal::I32(value)).collect(),
+    ))
+}
+
+/// Address of the asyncify `data` structure.
+const DATA: u32 = 16;
+
+#[test]
+fn unwind_and_rewind_round_trip() {
+    let bytes = sleepy_module();
+    let options = FrontendOptions::default();
+
+    // Synchronously: `sleep(v)` returns `10 * v`.
+    let mut module = Module::from_wasm_bytes(&bytes[..], &options).unwrap();
+    module.expand_all_funcs().unwrap();
+    let mut ctx = InterpContext::new(&module).unwrap();
+    ctx.import_handler = Some(Box::new(|_, _, _, name, args| {
+        assert_eq!(name, "sleep");
+        let v = args[0].as_u32().unwrap();
+        returns(v * 10)
+    }));
+    let expected = [0, 1, 7]
+        .iter()
+        .map(|&x| call(&mut ctx, &module, "work", &[ConstVal::I32(x)]))
+        .collect::<Vec<_>>();
+    assert_eq!(expected[1], ConstVal::I32(23 + 1000));
+
+    // Asynchronously: `sleep(v)` unwinds, and returns `10 * v` once
+    // rewound.
+    let mut module = Module::from_wasm_bytes(&bytes[..], &options).unwrap();
+    waffle::passes::asyncify::run(&mut module, &AsyncifyOptions::default()).unwrap();
+    let mut ctx = InterpContext::new(&module).unwrap();
+    let mut pending = 0;
+    ctx.import_handler = Some(Box::new(move |ctx, module, _, _, args| {
+        let result = if call(ctx, module, "asyncify_get_state", &[]) == ConstVal::I32(2) {
+            call(ctx, module, "asyncify_stop_rewind", &[]);
+            pending
+        } else {
+            pending = args[0].as_u32().unwrap() * 10;
+            call(ctx, module, "asyncify_start_unwind", &[ConstVal::I32(DATA)]);
+            0
+        };
+        returns(result)
+    }));
+    for (&x, &expected) in [0, 1, 7].iter().zip(expected.iter()) {
+        let memory = &mut ctx.memories[Memory::from(0)].data;
+        let data = DATA as usize;
+        memory[data..data + 4].copy_from_slice(&1024u32.to_le_bytes());
+        memory[data + 4..data + 8].copy_from_slice(&2048u32.to_le_bytes());
+
+        let mut result = call(&mut ctx, &module, "work", &[ConstVal::I32(x)]);
+        let mut unwinds = 0;
+        while call(&mut ctx, &module, "asyncify_get_state", &[]) == ConstVal::I32(1) {
+            unwinds += 1;
+            call(&mut ctx, &module, "asyncify_stop_unwind", &[]);
+            call(
+                &mut ctx,
+                &module,
+                "asyncify_start_rewind",
+                &[ConstVal::I32(DATA)],
+            );
+            result = call(&mut ctx, &module, "work", &[ConstVal::I32(x)]);
+        }
+        assert_eq!(result, expected, "work({})", x);
+        assert_eq!(unwinds, 2, "work({})", x);
+        assert_eq!(
+            call(&mut ctx, &module, "asyncify_get_state", &[]),
+            ConstVal::I32(0)
+        );
+    }
+
+    let options = waffle::CompileOptions {
+        validate: true,
+        ..waffle::CompileOptions::default()
+    };
+    module.compile(&options).unwrap();
+}
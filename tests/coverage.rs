@@ -0,0 +1,145 @@
+//! Coverage instrumentation must not change what the program
+//! computes, what it sees of its memory, or where it traps.
+
+use waffle::passes::coverage::{CounterKind, CoverageOptions, CoverageSites};
+use waffle::{ConstVal, Export, ExportKind, FrontendOptions, InterpContext, InterpResult, Module};
+use wasm_encoder::{
+    BlockType, CodeSection, ExportSection, Function, FunctionSection, Instruction, MemArg,
+    MemorySection, MemoryType, TypeSection, ValType,
+};
+
+/// One page of memory, growable to two, with exported `load(addr)`,
+/// `size()`, `grow(pages)`, and `branchy(x)`.
+fn memory_probe() -> Vec<u8> {
+    let mut module = wasm_encoder::Module::new();
+    let mut types = TypeSection::new();
+    types.function([ValType::I32], [ValType::I32]);
+    types.function([], [ValType::I32]);
+    module.section(&types);
+    let mut funcs = FunctionSection::new();
+    for ty in [0, 1, 0, 0] {
+        funcs.function(ty);
+    }
+    module.section(&funcs);
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: 1,
+        maximum: Some(2),
+        memory64: false,
+        shared: false,
+    });
+    module.section(&memories);
+    let mut exports = ExportSection::new();
+    for (i, name) in ["load", "size", "grow", "branchy"].iter().enumerate() {
+        exports.export(name, wasm_encoder::ExportKind::Func, i as u32);
+    }
+    module.section(&exports);
+
+    let mut code = CodeSection::new();
+    let mut load = Function::new([]);
+    load.instruction(&Instruction::LocalGet(0))
+        .instruction(&Instruction::I32Load(MemArg {
+            offset: 0,
+            align: 2,
+            memory_index: 0,
+        }))
+        .instruction(&Instruction::End);
+    code.function(&load);
+    let mut size = Function::new([]);
+    size.instruction(&Instruction::MemorySize(0))
+        .instruction(&Instruction::End);
+    code.function(&size);
+    let mut grow = Function::new([]);
+    grow.instruction(&Instruction::LocalGet(0))
+        .instruction(&Instruction::MemoryGrow(0))
+        .instruction(&Instruction::End);
+    code.function(&grow);
+    let mut branchy = Function::new([]);
+    branchy
+        .instruction(&Instruction::LocalGet(0))
+        .instruction(&Instruction::I32Const(5))
+        .instruction(&Instruction::I32GtU)
+        .instruction(&Instruction::If(BlockType::Result(ValType::I32)))
+        .instruction(&Instruction::LocalGet(0))
+        .instruction(&Instruction::I32Const(2))
+        .instruction(&Instruction::I32Mul)
+        .instruction(&Instruction::Else)
+        .instruction(&Instruction::LocalGet(0))
+        .instruction(&Instruction::I32Const(1))
+        .instruction(&Instruction::I32Add)
+        .instruction(&Instruction::End)
+        .instruction(&Instruction::End);
+    code.function(&branchy);
+    module.section(&code);
+    module.finish()
+}
+
+/// The result of each call in a fixed sequence, with traps reduced
+/// to `None` since instrumentation renumbers blocks.
+fn run_calls(module: &Module) -> Vec<Option<Vec<ConstVal>>> {
+    let calls: &[(&str, &[u32])] = &[
+        ("size", &[]),
+        ("load", &[0]),
+        ("load", &[65532]),
+        ("load", &[65536]),
+        ("branchy", &[3]),
+        ("branchy", &[9]),
+        ("grow", &[1]),
+        ("size", &[]),
+        ("load", &[65536]),
+        ("load", &[131072]),
+        ("grow", &[1]),
+        ("size", &[]),
+    ];
+    let mut ctx = InterpContext::new(module).unwrap();
+    calls
+        .iter()
+        .map(|&(name, args)| {
+            let func = match module.exports.iter().find(|export| export.name == name) {
+                Some(Export {
+                    kind: ExportKind::Func(func),
+                    ..
+                }) => *func,
+                _ => panic!("No exported function '{}'", name),
+            };
+            let args = args
+                .iter()
+                .map(|&arg| ConstVal::I32(arg))
+                .collect::<Vec<_>>();
+            match ctx.call(module, func, &args[..]) {
+                InterpResult::Ok(results) => Some(results.to_vec()),
+                InterpResult::Trap(..) => None,
+                other => panic!("{}({:?}): {:?}", name, args, other),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn instrumentation_preserves_behavior() {
+    let bytes = memory_probe();
+    let options = FrontendOptions::default();
+    let mut module = Module::from_wasm_bytes(&bytes[..], &options).unwrap();
+    module.expand_all_funcs().unwrap();
+    let expected = run_calls(&module);
+    assert_eq!(expected[3], None, "load past the end traps");
+
+    for &sites in &[CoverageSites::Blocks, CoverageSites::Edges] {
+        for &counters in &[CounterKind::Counters8, CounterKind::Bitmap] {
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options).unwrap();
+            let coverage = CoverageOptions {
+                sites,
+                counters,
+                ..CoverageOptions::default()
+            };
+            waffle::passes::coverage::run(&mut module, &coverage).unwrap();
+            assert_eq!(
+                run_calls(&module),
+                expected,
+                "{:?} with {:?}",
+                sites,
+                counters
+            );
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Compilation must be reproducible: the same module compiles to the
+//! same bytes on every run, whatever the size of the thread pool.
+
+use waffle::passes::PassManager;
+use waffle::{
+    Block, BlockTarget, CompileOptions, Export, ExportKind, FrontendOptions, FuncDecl,
+    FunctionBody, Module, Operator, OptimizeFor, SignatureData, Terminator, Type, Value, ValueDef,
+};
+
+/// A small xorshift generator, so that the test needs no extra
+/// dependencies and builds the same module every time.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+fn add_op(body: &mut FunctionBody, block: Block, op: Operator, args: &[Value], ty: Type) -> Value {
+    let args = body.arg_pool.from_iter(args.iter().copied());
+    let tys = body.single_type_list(ty);
+    let value = body.add_value(ValueDef::Operator(op, args, tys));
+    body.append_to_block(block, value);
+    value
+}
+
+fn i32_const(body: &mut FunctionBody, block: Block, value: u32) -> Value {
+    add_op(body, block, Operator::I32Const { value }, &[], Type::I32)
+}
+
+/// A function `i32 -> i32` whose body is a random, often
+/// irreducible, CFG with values carried across edges in blockparams.
+fn random_body(module: &Module, sig: waffle::Signature, rng: &mut Rng) -> FunctionBody {
+    let mut body = FunctionBody::new(module, sig);
+    let entry = body.entry;
+    let entry_param = body.blocks[entry].params[0].1;
+
+    let n_blocks = 2 + rng.below(10);
+    let mut blocks = vec![];
+    let mut wide = vec![];
+    for _ in 0..n_blocks {
+        let block = body.add_block();
+        body.add_blockparam(block, Type::I32);
+        let is_wide = rng.below(2) == 0;
+        if is_wide {
+            body.add_blockparam(block, Type::I64);
+        }
+        blocks.push(block);
+        wide.push(is_wide);
+    }
+
+    let target = |body: &mut FunctionBody, rng: &mut Rng, from: Block, x: Value| {
+        let to = rng.below(n_blocks);
+        let mut args = vec![x];
+        if wide[to] {
+            args.push(add_op(body, from, Operator::I64ExtendI32U, &[x], Type::I64));
+        }
+        BlockTarget {
+            block: blocks[to],
+            args,
+        }
+    };
+
+    let start = target(&mut body, rng, entry, entry_param);
+    body.set_terminator(entry, Terminator::Br { target: start });
+
+    for &block in &blocks {
+        let params = body.blocks[block].params.clone();
+        let mut x = params[0].1;
+        if let Some(&(_, y)) = params.get(1) {
+            let y = add_op(&mut body, block, Operator::I32WrapI64, &[y], Type::I32);
+            x = add_op(&mut body, block, Operator::I32Add, &[x, y], Type::I32);
+        }
+        let c1 = i32_const(&mut body, block, rng.next() as u32);
+        let c2 = i32_const(&mut body, block, rng.below(200) as u32);
+        let x = add_op(&mut body, block, Operator::I32Mul, &[x, c1], Type::I32);
+        let x = add_op(&mut body, block, Operator::I32Add, &[x, c2], Type::I32);
+
+        let terminator = match rng.below(4) {
+            0 => Terminator::Return { values: vec![x] },
+            1 => Terminator::Br {
+                target: target(&mut body, rng, block, x),
+            },
+            2 => {
+                let shift = i32_const(&mut body, block, rng.below(32) as u32);
+                let cond = add_op(&mut body, block, Operator::I32ShrU, &[x, shift], Type::I32);
+                Terminator::CondBr {
+                    cond,
+                    if_true: target(&mut body, rng, block, x),
+                    if_false: target(&mut body, rng, block, x),
+                }
+            }
+            _ => {
+                let targets = (0..1 + rng.below(6))
+                    .map(|_| target(&mut body, rng, block, x))
+                    .collect();
+                Terminator::Select {
+                    value: x,
+                    targets,
+                    default: target(&mut body, rng, block, x),
+                }
+            }
+        };
+        body.set_terminator(block, terminator);
+    }
+    body
+}
+
+fn random_module(bytes: &[u8], seed: u64) -> Module<'_> {
+    let mut module = Module::from_wasm_bytes(bytes, &FrontendOptions::default()).unwrap();
+    let sig = module.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32],
+    });
+    let mut rng = Rng(seed);
+    for i in 0..32 {
+        let body = random_body(&module, sig, &mut rng);
+        let func = module
+            .funcs
+            .push(FuncDecl::Body(sig, format!("f{}", i), Box::new(body)));
+        module.exports.push(Export {
+            name: format!("f{}", i),
+            kind: ExportKind::Func(func),
+        });
+    }
+    module
+}
+
+/// Build, optimize, and compile the module in every mode, all within
+/// a pool of `threads` threads.
+fn compile_all(threads: usize) -> Vec<Vec<u8>> {
+    let bytes = b"\0asm\x01\0\0\0";
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .unwrap();
+    pool.install(|| {
+        let mut outputs = vec![];
+        for seed in [1, 0x5eed, 0xdead_beef] {
+            let mut module = random_module(&bytes[..], seed);
+            outputs.push(module.to_wasm_bytes().unwrap());
+
+            let mut pm = PassManager::with_builtin_passes();
+            pm.add_pipeline("basic-opts,merge-funcs,max-ssa").unwrap();
+            pm.run(&mut module).unwrap();
+            for optimize_for in [OptimizeFor::Speed, OptimizeFor::Size] {
+                let options = CompileOptions {
+                    optimize_for,
+                    offset_map: true,
+                    validate: true,
+                    ..CompileOptions::default()
+                };
+                outputs.push(module.compile(&options).unwrap().wasm);
+            }
+        }
+        outputs
+    })
+}
+
+#[test]
+fn reproducible_across_runs_and_thread_counts() {
+    let expected = compile_all(1);
+    for threads in [1, 2, 8] {
+        for _ in 0..3 {
+            assert!(
+                compile_all(threads) == expected,
+                "output differs with {} threads",
+                threads
+            );
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! Devirtualization must keep the trap of an out-of-bounds index.
+
+use waffle::{ConstVal, FrontendOptions, Func, InterpContext, InterpResult, Module, Operator};
+use wasm_encoder::{
+    CodeSection, ConstExpr, ElementSection, Elements, ExportKind, ExportSection, Function,
+    FunctionSection, Instruction, TableSection, TableType, TypeSection, ValType,
+};
+
+/// `call(x, index)` calls through a one-element table holding
+/// `inc(x) = x + 1`, with an index the pass cannot bound.
+fn one_element_table() -> Vec<u8> {
+    let mut module = wasm_encoder::Module::new();
+    let mut types = TypeSection::new();
+    types.function([ValType::I32], [ValType::I32]);
+    types.function([ValType::I32, ValType::I32], [ValType::I32]);
+    module.section(&types);
+    let mut funcs = FunctionSection::new();
+    funcs.function(0);
+    funcs.function(1);
+    module.section(&funcs);
+    let mut tables = TableSection::new();
+    tables.table(TableType {
+        element_type: ValType::FuncRef,
+        minimum: 1,
+        maximum: Some(1),
+    });
+    module.section(&tables);
+    let mut exports = ExportSection::new();
+    exports.export("call", ExportKind::Func, 1);
+    module.section(&exports);
+    let mut elements = ElementSection::new();
+    elements.active(
+        None,
+        &ConstExpr::i32_const(0),
+        ValType::FuncRef,
+        Elements::Functions(&[0]),
+    );
+    module.section(&elements);
+    let mut code = CodeSection::new();
+    let mut inc = Function::new([]);
+    inc.instruction(&Instruction::LocalGet(0))
+        .instruction(&Instruction::I32Const(1))
+        .instruction(&Instruction::I32Add)
+        .instruction(&Instruction::End);
+    code.function(&inc);
+    let mut call = Function::new([]);
+    call.instruction(&Instruction::LocalGet(0))
+        .instruction(&Instruction::LocalGet(1))
+        .instruction(&Instruction::CallIndirect { ty: 0, table: 0 })
+        .instruction(&Instruction::End);
+    code.function(&call);
+    module.section(&code);
+    module.finish()
+}
+
+#[test]
+fn out_of_bounds_index_still_traps() {
+    let bytes = one_element_table();
+    let mut module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+    module.expand_all_funcs().unwrap();
+    waffle::passes::devirt::run(&mut module).unwrap();
+
+    let call = Func::from(1);
+    let body = module.funcs[call].body().unwrap();
+    assert!(
+        body.values.values().any(|def| matches!(
+            def,
+            waffle::ValueDef::Operator(Operator::CallIndirect { .. }, ..)
+        )),
+        "the fallback call_indirect is gone"
+    );
+
+    let mut ctx = InterpContext::new(&module).unwrap();
+    let result = ctx
+        .call(&module, call, &[ConstVal::I32(5), ConstVal::I32(0)])
+        .ok()
+        .unwrap();
+    assert_eq!(&result[..], &[ConstVal::I32(6)]);
+    for index in [1, 100, u32::MAX] {
+        match ctx.call(&module, call, &[ConstVal::I32(5), ConstVal::I32(index)]) {
+            InterpResult::Trap(..) => {}
+            other => panic!("index {} did not trap: {:?}", index, other),
+        }
+    }
+
+    let options = waffle::CompileOptions {
+        validate: true,
+        ..waffle::CompileOptions::default()
+    };
+    module.compile(&options).unwrap();
+}
@@ -0,0 +1,53 @@
+//! Backend errors name the function they come from.
+
+use waffle::{BackendError, FrontendOptions, Func, Module, Type};
+use wasm_encoder::{CodeSection, Function, FunctionSection, Instruction, TypeSection, ValType};
+
+/// Two functions returning an `i32`.
+fn two_funcs() -> Vec<u8> {
+    let mut module = wasm_encoder::Module::new();
+    let mut types = TypeSection::new();
+    types.function([], [ValType::I32]);
+    module.section(&types);
+    let mut funcs = FunctionSection::new();
+    funcs.function(0);
+    funcs.function(0);
+    module.section(&funcs);
+    let mut code = CodeSection::new();
+    for value in [1, 2] {
+        let mut func = Function::new([]);
+        func.instruction(&Instruction::I32Const(value))
+            .instruction(&Instruction::End);
+        code.function(&func);
+    }
+    module.section(&code);
+    module.finish()
+}
+
+#[test]
+fn unsupported_value_names_its_function() {
+    let bytes = two_funcs();
+    let mut module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+    module.expand_all_funcs().unwrap();
+    let func = Func::from(1);
+    let body = module.funcs[func].body_mut().unwrap();
+    let placeholder = body.add_placeholder(Type::I32);
+    body.append_to_block(body.entry, placeholder);
+    let entry = body.entry;
+    body.blocks[entry]
+        .terminator
+        .update_uses(|value| *value = placeholder);
+
+    let err = module.to_wasm_bytes().unwrap_err();
+    match err.downcast_ref::<BackendError>() {
+        Some(BackendError::UnsupportedValue {
+            func: Some(f),
+            value,
+            ..
+        }) => {
+            assert_eq!(*f, func);
+            assert_eq!(*value, placeholder);
+        }
+        _ => panic!("unexpected error: {:?}", err),
+    }
+}
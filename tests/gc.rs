@@ -0,0 +1,52 @@
+//! GC must not leave an unchanged body's original bytes referring to
+//! functions it removed.
+
+use waffle::{CompileOptions, FrontendOptions, Module};
+use wasm_encoder::{
+    CodeSection, ExportKind, ExportSection, Function, FunctionSection, Instruction, TypeSection,
+    ValType,
+};
+
+/// `main` returns 7 before a call to `dead`, which nothing else
+/// calls.
+fn call_in_dead_code() -> Vec<u8> {
+    let mut module = wasm_encoder::Module::new();
+    let mut types = TypeSection::new();
+    types.function([], [ValType::I32]);
+    module.section(&types);
+    let mut funcs = FunctionSection::new();
+    funcs.function(0);
+    funcs.function(0);
+    module.section(&funcs);
+    let mut exports = ExportSection::new();
+    exports.export("main", ExportKind::Func, 0);
+    module.section(&exports);
+    let mut code = CodeSection::new();
+    let mut main = Function::new([]);
+    main.instruction(&Instruction::I32Const(7))
+        .instruction(&Instruction::Return)
+        .instruction(&Instruction::Call(1))
+        .instruction(&Instruction::End);
+    code.function(&main);
+    let mut dead = Function::new([]);
+    dead.instruction(&Instruction::I32Const(1))
+        .instruction(&Instruction::End);
+    code.function(&dead);
+    module.section(&code);
+    module.finish()
+}
+
+#[test]
+fn gc_then_validate_with_call_in_dead_code() {
+    let bytes = call_in_dead_code();
+    let mut module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+    module.expand_all_funcs().unwrap();
+    waffle::passes::gc::run(&mut module).unwrap();
+    assert_eq!(module.funcs.len(), 1);
+
+    let options = CompileOptions {
+        validate: true,
+        ..CompileOptions::default()
+    };
+    module.compile(&options).unwrap();
+}
@@ -0,0 +1,86 @@
+//! Lowering multi-value returns must use only the return area the
+//! caller chose.
+
+use waffle::passes::lower_features::{Features, LowerOptions};
+use waffle::{ConstVal, FrontendOptions, Func, InterpContext, Module};
+use wasm_encoder::{
+    CodeSection, Function, FunctionSection, Instruction, MemorySection, MemoryType, TypeSection,
+    ValType,
+};
+
+/// `pair()` returns `(7, 35)`; `sum()` returns their sum. With
+/// `pages` of memory 0, if any.
+fn pair_and_sum(pages: Option<u64>) -> Vec<u8> {
+    let mut module = wasm_encoder::Module::new();
+    let mut types = TypeSection::new();
+    types.function([], [ValType::I32, ValType::I32]);
+    types.function([], [ValType::I32]);
+    module.section(&types);
+    let mut funcs = FunctionSection::new();
+    funcs.function(0);
+    funcs.function(1);
+    module.section(&funcs);
+    if let Some(pages) = pages {
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType {
+            minimum: pages,
+            maximum: None,
+            memory64: false,
+            shared: false,
+        });
+        module.section(&memories);
+    }
+    let mut code = CodeSection::new();
+    let mut pair = Function::new([]);
+    pair.instruction(&Instruction::I32Const(7))
+        .instruction(&Instruction::I32Const(35))
+        .instruction(&Instruction::End);
+    code.function(&pair);
+    let mut sum = Function::new([]);
+    sum.instruction(&Instruction::Call(0))
+        .instruction(&Instruction::I32Add)
+        .instruction(&Instruction::End);
+    code.function(&sum);
+    module.section(&code);
+    module.finish()
+}
+
+fn lower(bytes: &[u8], return_area: Option<u32>) -> anyhow::Result<Module<'_>> {
+    let mut module = Module::from_wasm_bytes(bytes, &FrontendOptions::default())?;
+    let options = LowerOptions {
+        features: Features::MVP,
+        return_area,
+        ..LowerOptions::default()
+    };
+    waffle::passes::lower_features::run(&mut module, &options)?;
+    Ok(module)
+}
+
+#[test]
+fn return_area_must_be_chosen_and_fit() {
+    let bytes = pair_and_sum(Some(1));
+    assert!(lower(&bytes[..], None).is_err(), "no default address");
+    assert!(lower(&bytes[..], Some(65534)).is_err(), "past the end");
+    let bytes = pair_and_sum(None);
+    assert!(lower(&bytes[..], Some(0)).is_err(), "no memory");
+    let bytes = pair_and_sum(Some(0));
+    assert!(lower(&bytes[..], Some(0)).is_err(), "empty memory");
+}
+
+#[test]
+fn lowered_returns_round_trip() {
+    let bytes = pair_and_sum(Some(1));
+    let module = lower(&bytes[..], Some(1024)).unwrap();
+    let mut ctx = InterpContext::new(&module).unwrap();
+    let result = ctx.call(&module, Func::from(1), &[]).ok().unwrap();
+    assert_eq!(&result[..], &[ConstVal::I32(42)]);
+    let memory = &ctx.memories[waffle::Memory::from(0)].data;
+    assert_eq!(&memory[1024..1028], &35u32.to_le_bytes());
+    assert!(memory[..1024].iter().all(|&byte| byte == 0));
+
+    let options = waffle::CompileOptions {
+        validate: true,
+        ..waffle::CompileOptions::default()
+    };
+    module.compile(&options).unwrap();
+}
@@ -0,0 +1,94 @@
+//! Stack limiting must wrap imports in tables with uninitialized
+//! slots.
+
+use waffle::entity::EntityRef;
+use waffle::passes::stack_limit::StackLimitOptions;
+use waffle::{
+    ConstVal, FrontendOptions, Func, FuncDecl, InterpContext, InterpResult, Module, Table,
+};
+use wasm_encoder::{
+    CodeSection, ConstExpr, ElementSection, Elements, EntityType, ExportKind, ExportSection,
+    Function, FunctionSection, ImportSection, Instruction, TableSection, TableType, TypeSection,
+    ValType,
+};
+
+/// A four-slot table holding the import `env.inc` in slot 1 and
+/// `main` in slot 3, and `main(x)`, which calls `inc` directly.
+fn table_with_holes() -> Vec<u8> {
+    let mut module = wasm_encoder::Module::new();
+    let mut types = TypeSection::new();
+    types.function([ValType::I32], [ValType::I32]);
+    module.section(&types);
+    let mut imports = ImportSection::new();
+    imports.import("env", "inc", EntityType::Function(0));
+    module.section(&imports);
+    let mut funcs = FunctionSection::new();
+    funcs.function(0);
+    module.section(&funcs);
+    let mut tables = TableSection::new();
+    tables.table(TableType {
+        element_type: ValType::FuncRef,
+        minimum: 4,
+        maximum: None,
+    });
+    module.section(&tables);
+    let mut exports = ExportSection::new();
+    exports.export("main", ExportKind::Func, 1);
+    module.section(&exports);
+    let mut elements = ElementSection::new();
+    elements.active(
+        None,
+        &ConstExpr::i32_const(1),
+        ValType::FuncRef,
+        Elements::Functions(&[0]),
+    );
+    elements.active(
+        None,
+        &ConstExpr::i32_const(3),
+        ValType::FuncRef,
+        Elements::Functions(&[1]),
+    );
+    module.section(&elements);
+    let mut code = CodeSection::new();
+    let mut main = Function::new([]);
+    main.instruction(&Instruction::LocalGet(0))
+        .instruction(&Instruction::Call(0))
+        .instruction(&Instruction::End);
+    code.function(&main);
+    module.section(&code);
+    module.finish()
+}
+
+#[test]
+fn wrap_imports_in_table_with_holes() {
+    let bytes = table_with_holes();
+    let mut module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+    waffle::passes::stack_limit::run(&mut module, &StackLimitOptions::default()).unwrap();
+
+    let elements = module.tables[Table::from(0)].func_elements.clone().unwrap();
+    assert_eq!(elements.len(), 4);
+    assert_eq!(
+        (elements[0], elements[2]),
+        (Func::invalid(), Func::invalid())
+    );
+    assert!(matches!(module.funcs[elements[1]], FuncDecl::Body(..)));
+
+    let mut ctx = InterpContext::new(&module).unwrap();
+    ctx.import_handler = Some(Box::new(|_, _, _, _, args| {
+        let x = args[0].as_u32().unwrap();
+        Some(InterpResult::Ok(
+            std::iter::once(ConstVal::I32(x + 1)).collect(),
+        ))
+    }));
+    let result = ctx
+        .call(&module, Func::from(1), &[ConstVal::I32(41)])
+        .ok()
+        .unwrap();
+    assert_eq!(&result[..], &[ConstVal::I32(42)]);
+
+    let options = waffle::CompileOptions {
+        validate: true,
+        ..waffle::CompileOptions::default()
+    };
+    module.compile(&options).unwrap();
+}